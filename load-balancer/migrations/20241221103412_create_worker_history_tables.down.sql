@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS worker_traffic_stats;
+DROP TABLE IF EXISTS worker_health_events;
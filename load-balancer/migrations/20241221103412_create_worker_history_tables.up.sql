@@ -0,0 +1,23 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS worker_health_events(
+    id BIGSERIAL PRIMARY KEY,
+    worker_address VARCHAR(255) NOT NULL,
+    healthy BOOLEAN NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS worker_health_events_worker_recorded_at_idx
+    ON worker_health_events(worker_address, recorded_at);
+
+CREATE TABLE IF NOT EXISTS worker_traffic_stats(
+    worker_address VARCHAR(255) NOT NULL,
+    minute TIMESTAMPTZ NOT NULL,
+    requests BIGINT NOT NULL,
+    errors BIGINT NOT NULL,
+    bytes_in BIGINT NOT NULL,
+    bytes_out BIGINT NOT NULL,
+    latency_p50_ms DOUBLE PRECISION NOT NULL,
+    latency_p95_ms DOUBLE PRECISION NOT NULL,
+    latency_p99_ms DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (worker_address, minute)
+);
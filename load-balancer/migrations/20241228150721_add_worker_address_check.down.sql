@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE workers DROP CONSTRAINT IF EXISTS workers_worker_address_format;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- NOT VALID keeps existing rows loadable, the balancer rejects those at startup instead
+ALTER TABLE workers
+    ADD CONSTRAINT workers_worker_address_format CHECK (
+        worker_address ~ '^([0-9]{1,3}\.){3}[0-9]{1,3}:[0-9]{1,5}$'
+        OR worker_address ~ '^\[[0-9A-Fa-f:.]+\]:[0-9]{1,5}$'
+    ) NOT VALID;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE workers DROP CONSTRAINT IF EXISTS workers_pkey;
+DELETE FROM workers WHERE pool_name <> 'default';
+ALTER TABLE workers DROP COLUMN IF EXISTS pool_name;
+ALTER TABLE workers ADD PRIMARY KEY (worker_address);
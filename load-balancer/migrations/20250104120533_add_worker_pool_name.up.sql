@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE workers ADD COLUMN IF NOT EXISTS pool_name VARCHAR(255) NOT NULL DEFAULT 'default';
+
+-- a worker address may now serve more than one pool
+ALTER TABLE workers DROP CONSTRAINT IF EXISTS workers_pkey;
+ALTER TABLE workers ADD PRIMARY KEY (pool_name, worker_address);
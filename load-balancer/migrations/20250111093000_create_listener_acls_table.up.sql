@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS listener_acls (
+    listener_address VARCHAR(255) NOT NULL,
+    network CIDR NOT NULL,
+    action VARCHAR(5) NOT NULL CHECK (action IN ('allow', 'deny')),
+    PRIMARY KEY (listener_address, network, action)
+);
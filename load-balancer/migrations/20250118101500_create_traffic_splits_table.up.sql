@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS traffic_splits (
+    listener_address VARCHAR(255) PRIMARY KEY,
+    percentage DOUBLE PRECISION NOT NULL CHECK (percentage >= 0 AND percentage <= 100),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
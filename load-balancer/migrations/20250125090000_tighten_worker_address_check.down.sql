@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE workers DROP CONSTRAINT IF EXISTS workers_worker_address_format;
+ALTER TABLE workers
+    ADD CONSTRAINT workers_worker_address_format CHECK (
+        worker_address ~ '^([0-9]{1,3}\.){3}[0-9]{1,3}:[0-9]{1,5}$'
+        OR worker_address ~ '^\[[0-9A-Fa-f:.]+\]:[0-9]{1,5}$'
+    ) NOT VALID;
+DROP FUNCTION IF EXISTS is_worker_address(TEXT);
@@ -0,0 +1,32 @@
+-- Add up migration script here
+-- The pattern check let through octets above 255 and ports above 65535. This one parses
+-- the host as an address and accepts what the balancer does: an IPv4 address, or an IPv6
+-- address in brackets, with a port from 1 to 65535 and a host that is not unspecified.
+-- IPv6 scope ids such as fe80::1%2 are not accepted
+CREATE OR REPLACE FUNCTION is_worker_address(address TEXT) RETURNS BOOLEAN
+LANGUAGE plpgsql IMMUTABLE AS $$
+DECLARE
+    parts TEXT[];
+    host INET;
+BEGIN
+    parts := regexp_match(
+        address,
+        '^(?:([0-9]{1,3}(?:\.[0-9]{1,3}){3})|\[([0-9A-Fa-f:.]+)\]):([0-9]{1,5})$'
+    );
+    IF parts IS NULL THEN
+        RETURN FALSE;
+    END IF;
+    host := COALESCE(parts[1], parts[2])::INET;
+    RETURN parts[3]::INTEGER BETWEEN 1 AND 65535
+        AND family(host) = CASE WHEN parts[1] IS NULL THEN 6 ELSE 4 END
+        AND host NOT IN ('0.0.0.0'::INET, '::'::INET);
+EXCEPTION WHEN invalid_text_representation THEN
+    RETURN FALSE;
+END;
+$$;
+
+ALTER TABLE workers DROP CONSTRAINT IF EXISTS workers_worker_address_format;
+-- NOT VALID keeps existing rows loadable, the balancer rejects those at startup instead
+ALTER TABLE workers
+    ADD CONSTRAINT workers_worker_address_format
+    CHECK (is_worker_address(worker_address)) NOT VALID;
@@ -0,0 +1,205 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    net::{AddrParseError, IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use thiserror::Error;
+
+/// Which step of talking to a worker failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorkerPhase {
+    Connect,
+    TlsHandshake,
+    HealthCheck,
+    Transfer,
+}
+
+impl Display for WorkerPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect => write!(f, "connect"),
+            Self::TlsHandshake => write!(f, "tls handshake"),
+            Self::HealthCheck => write!(f, "health check"),
+            Self::Transfer => write!(f, "transfer"),
+        }
+    }
+}
+
+/// Why a worker address was rejected
+#[derive(Debug, Error)]
+pub enum InvalidAddressReason {
+    #[error("{0}")]
+    Syntax(#[from] AddrParseError),
+
+    #[error("port 0 is not routable")]
+    ZeroPort,
+
+    #[error("unspecified ip {0} is not routable")]
+    UnspecifiedIp(IpAddr),
+
+    #[error("duplicate of an earlier worker")]
+    Duplicate,
+}
+
+#[derive(Debug, Error)]
+pub enum LoadBalancerError {
+    #[error("failed to read config {path}: {source}")]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid config {path}: {source}")]
+    ConfigParse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("no listener could be started")]
+    NoListeners,
+
+    #[error("database url is not set, expected `{0}` in the environment")]
+    MissingDatabaseUrl(&'static str),
+
+    #[error("database error while {context}: {source}")]
+    Database {
+        context: &'static str,
+        #[source]
+        source: sqlx::Error,
+    },
+
+    #[error("failed to run migrations: {0}")]
+    Migration(#[from] sqlx::migrate::MigrateError),
+
+    #[error("invalid worker address `{address}`: {reason}")]
+    InvalidWorkerAddress {
+        address: String,
+        #[source]
+        reason: InvalidAddressReason,
+    },
+
+    #[error("worker {worker} failed during {phase}: {source}")]
+    Worker {
+        worker: SocketAddr,
+        phase: WorkerPhase,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("worker {worker} reported unhealthy status {status_code}")]
+    UnhealthyWorker { worker: SocketAddr, status_code: u16 },
+
+    #[error("worker {worker} is not serving gRPC: {reason}")]
+    GrpcNotServing { worker: SocketAddr, reason: String },
+
+    #[error("failed to open access log: {0}")]
+    AccessLog(#[source] io::Error),
+
+    #[error("failed to bind listener {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to accept connection: {0}")]
+    Accept(#[source] io::Error),
+
+    #[error("failed to load tls {kind} from {path}: {reason}")]
+    TlsFile {
+        kind: &'static str,
+        path: PathBuf,
+        reason: String,
+    },
+
+    #[error("invalid tls configuration: {0}")]
+    TlsConfig(#[from] rustls::Error),
+
+    #[error("invalid tls server name `{0}`")]
+    TlsServerName(String),
+
+    #[error("listener {addr} cannot combine {first} with {second}")]
+    ConflictingListenerModes {
+        addr: SocketAddr,
+        first: &'static str,
+        second: &'static str,
+    },
+
+    #[error("invalid PROXY protocol header from {client}: {reason}")]
+    ProxyProtocol {
+        client: SocketAddr,
+        reason: &'static str,
+    },
+
+    #[error("no usable ClientHello from {client}: {reason}")]
+    ClientHello {
+        client: SocketAddr,
+        reason: &'static str,
+    },
+
+    #[error("pool {pool} is misconfigured: {reason}")]
+    InvalidPool { pool: String, reason: &'static str },
+
+    #[error("listener {addr} has an invalid rate limit: {reason}")]
+    InvalidRateLimit { addr: SocketAddr, reason: &'static str },
+
+    #[error("listener {addr} has an invalid http cache: {reason}")]
+    InvalidHttpCache { addr: SocketAddr, reason: &'static str },
+
+    #[error("listener {addr} has an invalid mirror: {reason}")]
+    InvalidMirror { addr: SocketAddr, reason: String },
+
+    #[error("listener {addr} has an invalid traffic split: {reason}")]
+    InvalidSplit { addr: SocketAddr, reason: String },
+
+    #[error("listener {listener} has an invalid route: {reason}")]
+    InvalidRoute { listener: SocketAddr, reason: String },
+
+    #[error("request to worker {worker} failed: {source}")]
+    UpstreamHttp {
+        worker: SocketAddr,
+        #[source]
+        source: hyper::Error,
+    },
+
+    #[error("http connection from {client} failed: {source}")]
+    HttpConnection {
+        client: SocketAddr,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("client {client} went away during transfer: {source}")]
+    ClientTransfer {
+        client: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("tls handshake with {client} failed: {source}")]
+    TlsHandshake {
+        client: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl LoadBalancerError {
+    pub fn database(context: &'static str) -> impl FnOnce(sqlx::Error) -> Self {
+        move |source| Self::Database { context, source }
+    }
+
+    pub fn worker(worker: SocketAddr, phase: WorkerPhase) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::Worker {
+            worker,
+            phase,
+            source,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, LoadBalancerError>;
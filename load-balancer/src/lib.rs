@@ -1,3 +1,4 @@
+pub mod error;
 pub mod proxy;
 pub mod utils;
 pub mod services;
@@ -1,53 +1,146 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use load_balancer::{
+    error::{LoadBalancerError, Result},
     proxy::load_balancer::LoadBalancer,
     services::postgres_store::PostgresWorkerStore,
-    utils::{constants::DATABSE_URL, tracing::init_tracing},
+    utils::{
+        access_log::{AccessLog, AccessLogTarget},
+        config::{Config, DEFAULT_POOL},
+        constants::{
+            env_variables::DATABASE_URL_ENV_VAR, ACCESS_LOG_MAX_BYTES, ACCESS_LOG_MAX_FILES,
+            ACCESS_LOG_PATH, CONFIG_PATH, DATABSE_URL, FALLBACK_WORKERS,
+        },
+        tracing::init_tracing,
+        worker_address::{validate_worker_addresses, ValidatedWorkers},
+    },
 };
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use tokio::net::TcpListener;
 use tracing::{event, Level};
 
+/// Keep startup short when Postgres is down, the balancer runs without it
+const DATABASE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    init_tracing().expect("Failed to initialize tracing");
+async fn main() -> color_eyre::Result<()> {
+    init_tracing()?;
+
+    let db = match configure_postgres().await {
+        Ok(pool) => Some(PostgresWorkerStore::new(pool)),
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Worker store unavailable, continuing without it. Error: {e}"
+            );
+            None
+        }
+    };
 
-    let pool = configure_postgres().await;
-    let db = PostgresWorkerStore::new(pool.clone());
+    let config = Config::load(CONFIG_PATH.as_str())?;
 
-    let workers: Vec<String> = match db.get_workers().await {
-        Ok(workers) => workers,
+    let mut validated_pools = match &db {
+        Some(db) => match db.get_workers().await {
+            Ok(validated_pools) => validated_pools,
+            Err(e) => {
+                event!(Level::WARN, "Using fallback workers. Error: {e}");
+                fallback_pools()
+            }
+        },
+        None => fallback_pools(),
+    };
+    for pool in config.pool_names() {
+        validated_pools.entry(pool).or_insert_with_key(|pool| {
+            event!(Level::WARN, "Pool {pool} has no workers");
+            ValidatedWorkers::default()
+        });
+    }
+    print_worker_summary(&validated_pools);
+
+    let access_log = match AccessLog::new(access_log_target()) {
+        Ok(access_log) => access_log,
         Err(e) => {
-            event!(Level::WARN, e);
-            vec![]
+            event!(
+                Level::ERROR,
+                "Writing access log to stdout. {}",
+                LoadBalancerError::AccessLog(e)
+            );
+            AccessLog::new(AccessLogTarget::Stdout).map_err(LoadBalancerError::AccessLog)?
         }
     };
 
-    let mut lb = LoadBalancer::new(workers, db);
+    let pools: HashMap<String, Vec<SocketAddr>> = validated_pools
+        .into_iter()
+        .map(|(pool, validated)| (pool, validated.workers))
+        .collect();
 
-    let addr = SocketAddr::from_str("127.0.0.1:3000").unwrap();
+    let mut lb = LoadBalancer::new(pools, &config.pools, db, access_log, &config.limits);
 
-    let listener = TcpListener::bind(addr).await?;
+    lb.run(config.listeners, config.admin).await?;
 
-    event!(Level::INFO, "Listening at addr: {}", addr);
+    Ok(())
+}
 
-    lb.run(listener).await
+pub async fn get_postgres_pool(url: String) -> Result<PgPool> {
+    PgPoolOptions::new()
+        .max_connections(5)
+        .acquire_timeout(DATABASE_CONNECT_TIMEOUT)
+        .connect(&url)
+        .await
+        .map_err(LoadBalancerError::database("connecting"))
 }
 
-pub async fn get_postgres_pool(url: String) -> Result<PgPool, sqlx::Error> {
-    PgPoolOptions::new().max_connections(5).connect(&url).await
+fn fallback_pools() -> HashMap<String, ValidatedWorkers> {
+    HashMap::from([(
+        DEFAULT_POOL.to_string(),
+        validate_worker_addresses(FALLBACK_WORKERS.clone()),
+    )])
 }
 
-async fn configure_postgres() -> PgPool {
-    let pool = get_postgres_pool(DATABSE_URL.to_string())
-        .await
-        .expect("Failed to connect to database");
+/// Printed to stderr so it stays out of an access log written to stdout
+fn print_worker_summary(validated_pools: &HashMap<String, ValidatedWorkers>) {
+    let mut pool_names: Vec<&String> = validated_pools.keys().collect();
+    pool_names.sort();
 
-    sqlx::migrate!()
-        .run(&pool)
-        .await
-        .expect("failed to run migration");
+    for pool in pool_names {
+        let validated = &validated_pools[pool];
+        validated.log_rejected();
+
+        eprintln!("Pool {pool}: loaded {} worker(s)", validated.workers.len());
+        for worker in &validated.workers {
+            eprintln!("  - {worker}");
+        }
+
+        if !validated.rejected.is_empty() {
+            eprintln!(
+                "Pool {pool}: rejected {} worker address(es)",
+                validated.rejected.len()
+            );
+            for e in &validated.rejected {
+                eprintln!("  - {e}");
+            }
+        }
+    }
+}
+
+fn access_log_target() -> AccessLogTarget {
+    match ACCESS_LOG_PATH.as_ref() {
+        Some(path) => AccessLogTarget::File {
+            path: PathBuf::from(path),
+            max_bytes: *ACCESS_LOG_MAX_BYTES,
+            max_files: *ACCESS_LOG_MAX_FILES,
+        },
+        None => AccessLogTarget::Stdout,
+    }
+}
+
+async fn configure_postgres() -> Result<PgPool> {
+    let url = DATABSE_URL
+        .clone()
+        .ok_or(LoadBalancerError::MissingDatabaseUrl(DATABASE_URL_ENV_VAR))?;
+
+    let pool = get_postgres_pool(url).await?;
+
+    sqlx::migrate!().run(&pool).await?;
 
-    pool
+    Ok(pool)
 }
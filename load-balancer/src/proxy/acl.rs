@@ -0,0 +1,305 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use tokio::time::interval;
+use tracing::{event, Level};
+
+use crate::{
+    services::postgres_store::PostgresWorkerStore,
+    utils::{
+        config::{AclConfig, Config},
+        constants::CONFIG_PATH,
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+/// A listener's allow and deny lists, from its config and optionally from the database.
+/// Every listener has one, empty and letting everyone in until rules are added
+#[derive(Debug)]
+pub(crate) struct Acl {
+    listener: SocketAddr,
+    configured: RwLock<Rules>,
+    stored: RwLock<Rules>,
+    /// Whether the listener's current `acl` section asks for the stored rules
+    database: AtomicBool,
+    denied: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Rules {
+    fn from_config(config: &AclConfig) -> Self {
+        Self {
+            allow: config.allow.iter().map(IpNet::trunc).collect(),
+            deny: config.deny.iter().map(IpNet::trunc).collect(),
+        }
+    }
+
+    fn from_rows(rows: Vec<(IpNet, bool)>) -> Self {
+        let (deny, allow): (Vec<_>, Vec<_>) = rows.into_iter().partition(|(_, deny)| *deny);
+        Self {
+            allow: allow.into_iter().map(|(network, _)| network).collect(),
+            deny: deny.into_iter().map(|(network, _)| network).collect(),
+        }
+    }
+}
+
+impl Acl {
+    pub fn new(listener: SocketAddr, config: &AclConfig) -> Self {
+        Self {
+            listener,
+            configured: RwLock::new(Rules::from_config(config)),
+            stored: RwLock::default(),
+            database: AtomicBool::new(config.database),
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the client may connect. Deny rules win over allow rules, and IPv4 clients
+    /// accepted on a dual stack listener are matched as IPv4
+    pub fn permits(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        let (Ok(configured), Ok(stored)) = (self.configured.read(), self.stored.read()) else {
+            return true;
+        };
+        let lists = [&*configured, &*stored];
+
+        let denied = lists
+            .iter()
+            .any(|rules| rules.deny.iter().any(|network| network.contains(&client)));
+        let restricted = lists.iter().any(|rules| !rules.allow.is_empty());
+        let allowed = lists
+            .iter()
+            .any(|rules| rules.allow.iter().any(|network| network.contains(&client)));
+
+        let permitted = !denied && (!restricted || allowed);
+        if !permitted {
+            self.denied.fetch_add(1, Ordering::Relaxed);
+        }
+        permitted
+    }
+
+    /// Reads the rules stored for the listener, keeping the previous ones when that fails
+    async fn load_stored(&self, store: &PostgresWorkerStore) {
+        match store.get_listener_acl(self.listener).await {
+            Ok(rows) => {
+                if let Ok(mut stored) = self.stored.write() {
+                    *stored = Rules::from_rows(rows);
+                }
+            }
+            Err(e) => event!(
+                Level::WARN,
+                "Keeping the previous ACL of listener {}. {e}",
+                self.listener
+            ),
+        }
+    }
+
+    /// Drops the stored rules once the listener no longer asks for them
+    fn clear_stored(&self) {
+        if let Ok(mut stored) = self.stored.write() {
+            *stored = Rules::default();
+        }
+    }
+
+    /// Reads the listener's lists from the config file again. A file that no longer parses
+    /// keeps the previous lists, and a listener without an `acl` section allows everyone.
+    /// The section also decides whether stored rules still apply
+    fn load_configured(&self) {
+        let config = match Config::load(CONFIG_PATH.as_str()) {
+            Ok(config) => config,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Keeping the previous ACL of listener {}. {e}",
+                    self.listener
+                );
+                return;
+            }
+        };
+        let acl_config = config
+            .listeners
+            .into_iter()
+            .find(|listener| listener.address == self.listener)
+            .and_then(|listener| listener.acl)
+            .unwrap_or_default();
+        self.database.store(acl_config.database, Ordering::Relaxed);
+        if let Ok(mut configured) = self.configured.write() {
+            *configured = Rules::from_config(&acl_config);
+        }
+    }
+}
+
+/// Loads the listener's stored rules before it starts serving, so the first clients are
+/// already checked against them
+pub(crate) async fn load_acl(
+    listener: SocketAddr,
+    config: &AclConfig,
+    store: Option<&PostgresWorkerStore>,
+) -> Arc<Acl> {
+    let acl = Arc::new(Acl::new(listener, config));
+    match store {
+        Some(store) if config.database => acl.load_stored(store).await,
+        None if config.database => event!(
+            Level::WARN,
+            "Listener {listener} wants its ACL from the database, but none is connected"
+        ),
+        _ => {}
+    }
+    acl
+}
+
+/// Reloads the lists on an interval, so rules can change without restarting. Stored rules
+/// are read while the listener's `acl` section has `database = true`, and dropped once it
+/// no longer does
+pub(crate) async fn reload_acl(
+    acl: Arc<Acl>,
+    reload_interval: Duration,
+    store: Option<PostgresWorkerStore>,
+) {
+    let mut ticks = interval(reload_interval);
+    // the first tick completes straight away
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        acl.load_configured();
+        match (acl.database.load(Ordering::Relaxed), &store) {
+            (true, Some(store)) => acl.load_stored(store).await,
+            (true, None) => {}
+            (false, _) => acl.clear_stored(),
+        }
+    }
+}
+
+impl MetricSource for Acl {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let listener = self.listener.to_string();
+        let labels = [("listener", listener.as_str())];
+
+        metrics.counter(
+            "lb_acl_denied_total",
+            "Connections and datagrams refused by the listener's ACL",
+            &labels,
+            self.denied.load(Ordering::Relaxed),
+        );
+
+        let (mut allow, mut deny) = (0, 0);
+        for rules in [&self.configured, &self.stored] {
+            if let Ok(rules) = rules.read() {
+                allow += rules.allow.len();
+                deny += rules.deny.len();
+            }
+        }
+        for (action, count) in [("allow", allow), ("deny", deny)] {
+            metrics.gauge(
+                "lb_acl_rules",
+                "Networks in the listener's ACL",
+                &[("listener", listener.as_str()), ("action", action)],
+                count as f64,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        let config = AclConfig {
+            allow: allow
+                .iter()
+                .map(|network| network.parse().unwrap())
+                .collect(),
+            deny: deny
+                .iter()
+                .map(|network| network.parse().unwrap())
+                .collect(),
+            ..Default::default()
+        };
+        Acl::new("127.0.0.1:8080".parse().unwrap(), &config)
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn empty_lists_allow_everyone() {
+        let acl = acl(&[], &[]);
+        assert!(acl.permits(ip("10.0.0.1")));
+        assert!(acl.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn an_allow_list_restricts_clients_to_it() {
+        let acl = acl(&["10.0.0.0/8"], &[]);
+        assert!(acl.permits(ip("10.1.2.3")));
+        assert!(!acl.permits(ip("192.168.0.1")));
+        assert!(!acl.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let nested = acl(&["10.0.0.0/8"], &["10.0.0.0/24"]);
+        assert!(!nested.permits(ip("10.0.0.5")));
+        assert!(nested.permits(ip("10.0.1.5")));
+
+        let deny_only = acl(&[], &["192.168.0.0/16"]);
+        assert!(!deny_only.permits(ip("192.168.1.1")));
+        assert!(deny_only.permits(ip("172.16.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_networks() {
+        let acl = acl(&["10.0.0.0/8"], &["10.0.0.1/32"]);
+        assert!(!acl.permits(ip("::ffff:10.0.0.1")));
+        assert!(acl.permits(ip("::ffff:10.0.0.2")));
+        assert!(!acl.permits(ip("::ffff:192.168.0.1")));
+    }
+
+    #[test]
+    fn configured_networks_are_truncated() {
+        let acl = acl(&["10.0.0.7/24"], &[]);
+        assert!(acl.permits(ip("10.0.0.200")));
+    }
+
+    #[test]
+    fn stored_rules_combine_with_configured_ones() {
+        let acl = acl(&["10.0.0.0/8"], &[]);
+        *acl.stored.write().unwrap() = Rules::from_rows(vec![
+            ("192.168.0.0/16".parse().unwrap(), false),
+            ("10.9.0.0/16".parse().unwrap(), true),
+        ]);
+
+        assert!(acl.permits(ip("10.1.0.1")));
+        assert!(acl.permits(ip("192.168.3.4")));
+        assert!(!acl.permits(ip("10.9.0.1")));
+        assert!(!acl.permits(ip("172.16.0.1")));
+
+        acl.clear_stored();
+        assert!(!acl.permits(ip("192.168.3.4")));
+        assert!(acl.permits(ip("10.9.0.1")));
+    }
+
+    #[test]
+    fn counts_refused_clients() {
+        let acl = acl(&[], &["10.0.0.0/8"]);
+        acl.permits(ip("10.0.0.1"));
+        acl.permits(ip("10.0.0.2"));
+        acl.permits(ip("192.168.0.1"));
+        assert_eq!(acl.denied.load(Ordering::Relaxed), 2);
+    }
+}
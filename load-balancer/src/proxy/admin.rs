@@ -0,0 +1,294 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::{event, Level};
+
+use crate::error::LoadBalancerError;
+
+use super::{
+    body::{full, ProxyBody},
+    listener::accept,
+    load_balancer::ProxyContext,
+};
+
+const PROMETHEUS_CONTENT_TYPE: HeaderValue =
+    HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8");
+const JSON_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("application/json");
+/// A split update is a single small JSON object
+const MAX_UPDATE_BYTES: usize = 1024;
+
+/// Body of `PUT /splits/{listener}`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SplitUpdate {
+    percentage: f64,
+}
+
+/// Serves `GET /metrics` for Prometheus to scrape, and shows and moves the listeners'
+/// traffic splits on `/splits`. Moving a split takes the `token`, or a loopback client
+/// when there is none
+pub(crate) async fn serve_admin(listener: TcpListener, token: Option<String>, ctx: ProxyContext) {
+    let token: Option<Arc<str>> = token.map(Into::into);
+    loop {
+        let (inbound, client_addr) = accept(&listener).await;
+
+        let ctx = ctx.clone();
+        let token = token.clone();
+        let service = service_fn(move |request| {
+            let ctx = ctx.clone();
+            let allowed = authorized(&request, token.as_deref(), client_addr);
+            async move { Ok::<_, Infallible>(handle(request, allowed, &ctx).await) }
+        });
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(inbound), service)
+                .await
+            {
+                event!(
+                    Level::DEBUG,
+                    "Admin connection from {client_addr} failed. {e}"
+                );
+            }
+        });
+    }
+}
+
+/// Whether the request may change anything
+fn authorized<B>(request: &Request<B>, token: Option<&str>, client_addr: SocketAddr) -> bool {
+    let Some(token) = token else {
+        return client_addr.ip().is_loopback();
+    };
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+}
+
+/// Compares without returning early, so the time taken says nothing about the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    authorized: bool,
+    ctx: &ProxyContext,
+) -> Response<ProxyBody> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut response = Response::new(full(ctx.metrics().render()));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE);
+            response
+        }
+        (&Method::GET, "/splits") => json(StatusCode::OK, &ctx.splits().statuses()),
+        (&Method::PUT, path) if path.starts_with("/splits/") => {
+            if !authorized {
+                return text(StatusCode::UNAUTHORIZED, "Unauthorized");
+            }
+            update_split(request, ctx).await
+        }
+        _ => text(StatusCode::NOT_FOUND, "Not Found"),
+    }
+}
+
+/// Moves a listener's split to the percentage in the body, `{"percentage": 25}`
+async fn update_split(request: Request<Incoming>, ctx: &ProxyContext) -> Response<ProxyBody> {
+    let listener = request.uri().path().trim_start_matches("/splits/");
+    let Some(split) = listener
+        .parse::<SocketAddr>()
+        .ok()
+        .and_then(|listener| ctx.splits().get(listener))
+    else {
+        return text(StatusCode::NOT_FOUND, "No traffic split on that listener");
+    };
+
+    let body = match Limited::new(request.into_body(), MAX_UPDATE_BYTES)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return text(StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+        }
+        Err(e) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let update: SplitUpdate = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        Err(e) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    match split.set_percentage(update.percentage).await {
+        Ok(()) => json(StatusCode::OK, &split.status()),
+        Err(e @ LoadBalancerError::InvalidSplit { .. }) => {
+            text(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        Err(e) => {
+            event!(Level::ERROR, "Traffic split not changed. {e}");
+            text(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+fn text(status: StatusCode, body: impl Into<String>) -> Response<ProxyBody> {
+    let mut response = Response::new(full(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<ProxyBody> {
+    let mut response = text(status, serde_json::to_string(value).unwrap_or_default());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, JSON_CONTENT_TYPE);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Empty;
+    use hyper::{body::Bytes, client::conn::http1 as client};
+    use tokio::net::TcpStream;
+
+    use crate::utils::config::{PoolConfig, SplitConfig};
+
+    use super::{
+        super::{pool::WorkerPool, split::TrafficSplit},
+        *,
+    };
+
+    const LISTENER: &str = "127.0.0.1:8080";
+
+    fn request(authorization: Option<&str>) -> Request<Empty<Bytes>> {
+        let mut request = Request::new(Empty::new());
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.parse().unwrap());
+        }
+        request
+    }
+
+    /// An admin listener over a split of `LISTENER` between two pools, 10% to the canary
+    async fn admin(token: Option<&str>) -> SocketAddr {
+        let pool = |name: &str| {
+            WorkerPool::new(
+                name.into(),
+                vec!["127.0.0.1:9001".parse().unwrap()],
+                &PoolConfig::default(),
+            )
+            .unwrap()
+        };
+        let ctx = ProxyContext::for_pools(vec![pool("stable"), pool("canary")]);
+        let config = SplitConfig {
+            canary: "canary".into(),
+            percentage: 10.0,
+            database: false,
+            reload_interval_secs: 30,
+        };
+        let split = TrafficSplit::new(LISTENER.parse().unwrap(), "stable", &config, &ctx);
+        ctx.splits().register(Arc::new(split.unwrap()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_admin(listener, token.map(Into::into), ctx));
+        addr
+    }
+
+    async fn put(
+        admin: SocketAddr,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let stream = TcpStream::connect(admin).await.unwrap();
+        let (mut sender, connection) = client::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+
+        let mut request = Request::put(format!("http://{admin}/splits/{LISTENER}"));
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let request = request.body(body.to_string()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn a_token_takes_a_matching_bearer_header() {
+        let remote = "10.0.0.1:50000".parse().unwrap();
+        let local = "127.0.0.1:50000".parse().unwrap();
+        let token = Some("secret");
+
+        assert!(authorized(&request(Some("Bearer secret")), token, remote));
+        for header in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer secret2"),
+            Some("Basic secret"),
+            Some("bearer secret"),
+        ] {
+            assert!(!authorized(&request(header), token, remote), "{header:?}");
+        }
+        // with a token, loopback clients need it too
+        assert!(!authorized(&request(None), token, local));
+    }
+
+    #[test]
+    fn without_a_token_only_loopback_clients_are_allowed() {
+        for client in ["127.0.0.1:50000", "[::1]:50000"] {
+            assert!(authorized(&request(None), None, client.parse().unwrap()));
+        }
+        for client in ["10.0.0.1:50000", "[2001:db8::1]:50000"] {
+            let client = client.parse().unwrap();
+            assert!(!authorized(&request(None), None, client));
+            assert!(!authorized(&request(Some("Bearer x")), None, client));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_split_updates_without_the_token() {
+        let admin = admin(Some("secret")).await;
+
+        let (status, _) = put(admin, None, r#"{"percentage": 50}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = put(admin, Some("Bearer wrong"), r#"{"percentage": 50}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = put(admin, Some("Bearer secret"), r#"{"percentage": 50}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""percentage":50.0"#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn rejects_out_of_range_percentages() {
+        let admin = admin(None).await;
+
+        for body in [
+            r#"{"percentage": -5}"#,
+            r#"{"percentage": 150}"#,
+            r#"{"percent": 5}"#,
+            "five",
+        ] {
+            let (status, _) = put(admin, None, body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        }
+        let (status, body) = put(admin, None, r#"{"percentage": 100}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""percentage":100.0"#), "{body}");
+    }
+}
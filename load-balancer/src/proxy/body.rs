@@ -0,0 +1,134 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Frame, SizeHint};
+
+use super::grpc::grpc_status;
+
+/// Body of every response the balancer sends to clients
+pub(crate) type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+pub(crate) fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// What a [`CountingBody`] saw, read once the body has been dropped
+#[derive(Debug, Default)]
+pub(crate) struct BodyStats {
+    bytes: AtomicU64,
+    failed: AtomicBool,
+    abandoned: AtomicBool,
+    grpc_status: OnceLock<u16>,
+}
+
+impl BodyStats {
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// The wrapped body itself returned an error
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// The body was dropped before its end without failing, e.g. a client that hung up
+    /// part way through a download
+    pub fn abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+
+    /// From the body's trailers, where gRPC reports how a call ended
+    pub fn grpc_status(&self) -> Option<u16> {
+        self.grpc_status.get().copied()
+    }
+
+    /// Counts bytes that bypassed the body, such as an upgraded connection's
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Passes a body through while counting its bytes. `held` is dropped with the body, so a
+/// request can be finished once its response has been fully sent or abandoned
+pub(crate) struct CountingBody<B, T = ()> {
+    inner: B,
+    stats: Arc<BodyStats>,
+    ended: bool,
+    _held: T,
+}
+
+impl<B: Body> CountingBody<B> {
+    pub fn new(inner: B, stats: Arc<BodyStats>) -> Self {
+        Self::holding(inner, stats, ())
+    }
+}
+
+impl<B: Body, T> CountingBody<B, T> {
+    pub fn holding(inner: B, stats: Arc<BodyStats>, held: T) -> Self {
+        Self {
+            ended: inner.is_end_stream(),
+            inner,
+            stats,
+            _held: held,
+        }
+    }
+}
+
+impl<B, T> Body for CountingBody<B, T>
+where
+    B: Body<Data = Bytes> + Unpin,
+    T: Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.stats.add(data.len() as u64);
+                } else if let Some(status) = frame.trailers_ref().and_then(grpc_status) {
+                    let _ = self.stats.grpc_status.set(status);
+                }
+                self.ended = self.inner.is_end_stream();
+            }
+            Poll::Ready(Some(Err(_))) => {
+                self.stats.failed.store(true, Ordering::Relaxed);
+                self.ended = true;
+            }
+            Poll::Ready(None) => self.ended = true,
+            Poll::Pending => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B, T> Drop for CountingBody<B, T> {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.stats.abandoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,710 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hyper::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH,
+        DATE, ETAG, EXPIRES, IF_NONE_MATCH, PRAGMA, SET_COOKIE, VARY,
+    },
+    http::response::Parts,
+    Method, Request, Response, StatusCode,
+};
+use lru::LruCache;
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::{
+        access_log::CacheStatus,
+        config::HttpCacheConfig,
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+use super::{
+    body::{full, ProxyBody},
+    headers::requested_upgrade,
+    router::request_host,
+};
+
+/// Statuses HTTP lets caches reuse, stored when the response allows it
+const CACHEABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::PERMANENT_REDIRECT,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+    StatusCode::GONE,
+    StatusCode::URI_TOO_LONG,
+    StatusCode::NOT_IMPLEMENTED,
+];
+
+/// A listener's stored responses, bounded in bytes and evicted least recently used first
+#[derive(Debug)]
+pub(crate) struct HttpCache {
+    listener: SocketAddr,
+    max_bytes: usize,
+    max_object_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidated: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Entries {
+    /// Responses by host and path, one per combination of the request headers they vary on
+    variants: LruCache<String, Vec<Arc<CachedResponse>>>,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// The request headers named by `Vary`, with the values this response was stored for
+    vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    /// When the response was received, less the `Age` it already had
+    born: Instant,
+    fresh_for: Duration,
+    size: usize,
+}
+
+/// A GET or HEAD request the cache may answer
+#[derive(Debug)]
+pub(crate) struct CacheableRequest {
+    key: String,
+    head: bool,
+    headers: HeaderMap,
+    directives: Directives,
+}
+
+pub(crate) enum Lookup {
+    Fresh(Arc<CachedResponse>),
+    /// Stored, but has to be confirmed by a worker before it can be used
+    Stale(Arc<CachedResponse>),
+    Miss,
+}
+
+/// The `Cache-Control` directives the cache acts on
+#[derive(Debug, Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl Directives {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = argument.and_then(|argument| argument.parse().ok());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+impl HttpCache {
+    pub fn new(listener: SocketAddr, config: &HttpCacheConfig) -> Result<Self> {
+        let invalid = |reason| LoadBalancerError::InvalidHttpCache {
+            addr: listener,
+            reason,
+        };
+        if config.max_bytes == 0 || config.max_object_bytes == 0 {
+            return Err(invalid("max_bytes and max_object_bytes must be above 0"));
+        }
+        if config.max_object_bytes > config.max_bytes {
+            return Err(invalid("max_object_bytes cannot be above max_bytes"));
+        }
+
+        Ok(Self {
+            listener,
+            max_bytes: config.max_bytes,
+            max_object_bytes: config.max_object_bytes,
+            entries: Mutex::new(Entries {
+                variants: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            revalidated: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        })
+    }
+
+    /// `None` for requests the cache stays out of: other methods, upgrades, requests with
+    /// credentials and requests asking for `no-store`
+    pub fn cacheable<B>(&self, request: &Request<B>, pool: &str) -> Option<CacheableRequest> {
+        let head = request.method() == Method::HEAD;
+        if (request.method() != Method::GET && !head)
+            || requested_upgrade(request.headers()).is_some()
+            || request.headers().contains_key(AUTHORIZATION)
+        {
+            return None;
+        }
+
+        let mut directives = Directives::parse(request.headers());
+        if !request.headers().contains_key(CACHE_CONTROL) {
+            directives.no_cache = request
+                .headers()
+                .get(PRAGMA)
+                .is_some_and(|pragma| pragma.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        }
+        if directives.no_store {
+            return None;
+        }
+
+        Some(CacheableRequest {
+            key: cache_key(request, pool),
+            head,
+            headers: request.headers().clone(),
+            directives,
+        })
+    }
+
+    pub fn lookup(&self, request: &CacheableRequest) -> Lookup {
+        let Ok(mut entries) = self.entries.lock() else {
+            return Lookup::Miss;
+        };
+        let Some(entry) = entries.variants.get(&request.key).and_then(|variants| {
+            variants
+                .iter()
+                .find(|entry| entry.matches(&request.headers))
+                .cloned()
+        }) else {
+            return Lookup::Miss;
+        };
+
+        let age = entry.born.elapsed();
+        let fresh = !request.directives.no_cache
+            && age < entry.fresh_for
+            && request
+                .directives
+                .max_age
+                .is_none_or(|max_age| age <= Duration::from_secs(max_age));
+        if fresh {
+            Lookup::Fresh(entry)
+        } else {
+            Lookup::Stale(entry)
+        }
+    }
+
+    /// Whether the response to the request should be stored, checked before its body is
+    /// read. Responses with no explicit freshness are only stored when they carry an
+    /// `ETag` to revalidate them with
+    pub fn should_store(&self, request: &CacheableRequest, response: &Parts) -> bool {
+        let headers = &response.headers;
+        let directives = Directives::parse(headers);
+        let content_length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+
+        !request.head
+            && CACHEABLE_STATUSES.contains(&response.status)
+            && content_length.is_some_and(|length| length <= self.max_object_bytes)
+            && !headers.contains_key(SET_COOKIE)
+            && !directives.no_store
+            && !directives.private
+            && vary_names(headers).is_some()
+            && (headers.contains_key(ETAG)
+                || (!directives.no_cache
+                    && freshness(headers, &directives).is_some_and(|fresh| !fresh.is_zero())))
+    }
+
+    pub fn store(&self, request: &CacheableRequest, response: &Parts, body: Bytes) {
+        let Some(names) = vary_names(&response.headers) else {
+            return;
+        };
+        let vary = names
+            .into_iter()
+            .map(|name| {
+                let values = request.headers.get_all(&name).iter().cloned().collect();
+                (name, values)
+            })
+            .collect();
+        let entry = CachedResponse::new(response.status, response.headers.clone(), body, vary);
+        self.insert(&request.key, entry);
+    }
+
+    /// Refreshes a stale response from the headers of the worker's `304 Not Modified`
+    pub fn revalidated(
+        &self,
+        request: &CacheableRequest,
+        stale: &CachedResponse,
+        not_modified: &HeaderMap,
+    ) -> Arc<CachedResponse> {
+        let mut headers = stale.headers.clone();
+        for name in not_modified.keys() {
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        let entry = CachedResponse::new(
+            stale.status,
+            headers,
+            stale.body.clone(),
+            stale.vary.clone(),
+        );
+        self.insert(&request.key, entry)
+    }
+
+    /// A request that changes the resource, such as a POST or DELETE, drops what the pool
+    /// it went to had stored for it
+    pub fn invalidate<B>(&self, request: &Request<B>, pool: &str) {
+        if request.method().is_safe() {
+            return;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if let Some(variants) = entries.variants.pop(&cache_key(request, pool)) {
+            entries.bytes -= variants.iter().map(|entry| entry.size).sum::<usize>();
+        }
+    }
+
+    pub fn count(&self, status: CacheStatus) {
+        let counter = match status {
+            CacheStatus::Hit => &self.hits,
+            CacheStatus::Miss => &self.misses,
+            CacheStatus::Revalidated => &self.revalidated,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stores the response in place of any variant for the same request headers, then
+    /// evicts until the cache is within its size
+    fn insert(&self, key: &str, entry: CachedResponse) -> Arc<CachedResponse> {
+        let entry = Arc::new(entry);
+        if entry.size > self.max_bytes {
+            return entry;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return entry;
+        };
+        let entries = &mut *entries;
+
+        if !entries.variants.contains(key) {
+            entries.variants.put(key.to_string(), Vec::new());
+        }
+        if let Some(variants) = entries.variants.get_mut(key) {
+            variants.retain(|variant| {
+                let replaced = variant.vary == entry.vary;
+                if replaced {
+                    entries.bytes -= variant.size;
+                }
+                !replaced
+            });
+            variants.push(entry.clone());
+            entries.bytes += entry.size;
+        }
+
+        while entries.bytes > self.max_bytes {
+            let Some((_, evicted)) = entries.variants.pop_lru() else {
+                break;
+            };
+            entries.bytes -= evicted.iter().map(|entry| entry.size).sum::<usize>();
+            self.evictions
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        }
+        entry
+    }
+}
+
+impl CachedResponse {
+    fn new(
+        status: StatusCode,
+        mut headers: HeaderMap,
+        body: Bytes,
+        vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    ) -> Self {
+        let directives = Directives::parse(&headers);
+        let fresh_for = if directives.no_cache {
+            Duration::ZERO
+        } else {
+            freshness(&headers, &directives).unwrap_or_default()
+        };
+        let age = headers
+            .remove(AGE)
+            .and_then(|age| age.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let born = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        let size = body.len()
+            + headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+
+        Self {
+            status,
+            headers,
+            body,
+            vary,
+            born,
+            fresh_for,
+            size,
+        }
+    }
+
+    pub fn etag(&self) -> Option<&HeaderValue> {
+        self.headers.get(ETAG)
+    }
+
+    /// The stored response with its `Age`, or `304 Not Modified` when the client already
+    /// has it
+    pub fn respond(&self, request: &CacheableRequest) -> Response<ProxyBody> {
+        let not_modified = self.etag().is_some_and(|etag| {
+            request
+                .headers
+                .get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|tag| tag.trim() == "*" || weak_eq(tag.trim().as_bytes(), etag.as_bytes()))
+        });
+
+        let body = if request.head || not_modified {
+            Bytes::new()
+        } else {
+            self.body.clone()
+        };
+        let mut response = Response::new(full(body));
+        *response.status_mut() = if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            self.status
+        };
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(AGE, HeaderValue::from(self.born.elapsed().as_secs()));
+        response
+    }
+
+    fn matches(&self, request: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| request.get_all(name).iter().eq(values.iter()))
+    }
+}
+
+impl MetricSource for HttpCache {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let listener = self.listener.to_string();
+        let labels = [("listener", listener.as_str())];
+
+        metrics.counter(
+            "lb_http_cache_hits_total",
+            "Requests answered from the HTTP cache",
+            &labels,
+            self.hits.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_http_cache_misses_total",
+            "Cacheable requests sent to a worker",
+            &labels,
+            self.misses.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_http_cache_revalidated_total",
+            "Stale responses a worker confirmed with 304 Not Modified",
+            &labels,
+            self.revalidated.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_http_cache_evictions_total",
+            "Responses evicted to keep the HTTP cache within max_bytes",
+            &labels,
+            self.evictions.load(Ordering::Relaxed),
+        );
+
+        let (stored, bytes) = match self.entries.lock() {
+            Ok(entries) => (
+                entries
+                    .variants
+                    .iter()
+                    .map(|(_, variants)| variants.len())
+                    .sum(),
+                entries.bytes,
+            ),
+            Err(_) => (0, 0),
+        };
+        metrics.gauge(
+            "lb_http_cache_responses",
+            "Responses held in the HTTP cache",
+            &labels,
+            stored as f64,
+        );
+        metrics.gauge(
+            "lb_http_cache_bytes",
+            "Bodies and headers held in the HTTP cache",
+            &labels,
+            bytes as f64,
+        );
+    }
+}
+
+/// The pool, host and path with query. The cache belongs to one listener, so the scheme is
+/// the same for every request
+fn cache_key<B>(request: &Request<B>, pool: &str) -> String {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    format!("{pool} {}{path}", request_host(request).unwrap_or_default())
+}
+
+/// The request headers named by `Vary`, or `None` for `Vary: *`, which can never match
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    let values = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok());
+    for name in values.flat_map(|value| value.split(',')) {
+        let name = name.trim();
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            names.push(name);
+        }
+    }
+    Some(names)
+}
+
+/// How long the response stays fresh from `s-maxage`, `max-age` or `Expires`. An `Expires`
+/// that does not parse means it already expired
+fn freshness(headers: &HeaderMap, directives: &Directives) -> Option<Duration> {
+    if let Some(seconds) = directives.s_maxage.or(directives.max_age) {
+        return Some(Duration::from_secs(seconds));
+    }
+    let expires = headers.get(EXPIRES)?;
+    let Some(expires) = http_date(expires) else {
+        return Some(Duration::ZERO);
+    };
+    let date = headers
+        .get(DATE)
+        .and_then(http_date)
+        .unwrap_or_else(Utc::now);
+    Some((expires - date).to_std().unwrap_or_default())
+}
+
+fn http_date(value: &HeaderValue) -> Option<DateTime<Utc>> {
+    let date = DateTime::parse_from_rfc2822(value.to_str().ok()?).ok()?;
+    Some(date.with_timezone(&Utc))
+}
+
+/// `If-None-Match` compares entity tags weakly, ignoring the `W/` prefix
+fn weak_eq(a: &[u8], b: &[u8]) -> bool {
+    a.strip_prefix(b"W/").unwrap_or(a) == b.strip_prefix(b"W/").unwrap_or(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_bytes: usize) -> HttpCache {
+        let config = HttpCacheConfig {
+            max_bytes,
+            max_object_bytes: max_bytes,
+        };
+        HttpCache::new("127.0.0.1:8080".parse().unwrap(), &config).unwrap()
+    }
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut request = Request::get(path).header("host", "example.com");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn response(body: &'static str, headers: &[(&str, &str)]) -> (Parts, Bytes) {
+        let mut response = Response::builder().header(CONTENT_LENGTH, body.len());
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        (response.body(()).unwrap().into_parts().0, Bytes::from(body))
+    }
+
+    /// Stores the response to the request as sent through the pool
+    fn store(cache: &HttpCache, pool: &str, request: &Request<()>, response: (Parts, Bytes)) {
+        let request = cache.cacheable(request, pool).unwrap();
+        let (parts, body) = response;
+        assert!(cache.should_store(&request, &parts));
+        cache.store(&request, &parts, body);
+    }
+
+    fn lookup(cache: &HttpCache, pool: &str, request: &Request<()>) -> &'static str {
+        match cache.lookup(&cache.cacheable(request, pool).unwrap()) {
+            Lookup::Fresh(_) => "fresh",
+            Lookup::Stale(_) => "stale",
+            Lookup::Miss => "miss",
+        }
+    }
+
+    const FRESH: (&str, &str) = ("cache-control", "max-age=60");
+
+    #[test]
+    fn keeps_each_pools_responses_apart() {
+        let cache = cache(1 << 20);
+        let request = get("/page", &[]);
+        store(&cache, "stable", &request, response("stable", &[FRESH]));
+
+        assert_eq!(lookup(&cache, "stable", &request), "fresh");
+        assert_eq!(lookup(&cache, "canary", &request), "miss");
+
+        // a POST drops only what its own pool stored
+        let post = Request::post("/page")
+            .header("host", "example.com")
+            .body(())
+            .unwrap();
+        cache.invalidate(&post, "canary");
+        assert_eq!(lookup(&cache, "stable", &request), "fresh");
+        cache.invalidate(&post, "stable");
+        assert_eq!(lookup(&cache, "stable", &request), "miss");
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let measure = cache(1 << 20);
+        store(&measure, "web", &get("/a", &[]), response("aaaa", &[FRESH]));
+        let size = measure.entries.lock().unwrap().bytes;
+
+        let cache = cache(size * 5 / 2);
+        let (a, b, c) = (get("/a", &[]), get("/b", &[]), get("/c", &[]));
+        store(&cache, "web", &a, response("aaaa", &[FRESH]));
+        store(&cache, "web", &b, response("bbbb", &[FRESH]));
+        // reading /a leaves /b the least recently used
+        assert_eq!(lookup(&cache, "web", &a), "fresh");
+        store(&cache, "web", &c, response("cccc", &[FRESH]));
+
+        assert_eq!(lookup(&cache, "web", &a), "fresh");
+        assert_eq!(lookup(&cache, "web", &b), "miss");
+        assert_eq!(lookup(&cache, "web", &c), "fresh");
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.entries.lock().unwrap().bytes, size * 2);
+    }
+
+    #[test]
+    fn keeps_a_copy_per_vary_value() {
+        let cache = cache(1 << 20);
+        let vary = ("vary", "accept-encoding");
+        let gzip = get("/page", &[("accept-encoding", "gzip")]);
+        let br = get("/page", &[("accept-encoding", "br")]);
+
+        store(&cache, "web", &gzip, response("gzipped", &[FRESH, vary]));
+        assert_eq!(lookup(&cache, "web", &gzip), "fresh");
+        assert_eq!(lookup(&cache, "web", &br), "miss");
+        assert_eq!(lookup(&cache, "web", &get("/page", &[])), "miss");
+
+        store(&cache, "web", &br, response("brotli", &[FRESH, vary]));
+        assert_eq!(lookup(&cache, "web", &gzip), "fresh");
+        assert_eq!(lookup(&cache, "web", &br), "fresh");
+
+        // `Vary: *` can never be matched, so it is not stored
+        let request = cache.cacheable(&gzip, "web").unwrap();
+        let (parts, _) = response("any", &[FRESH, ("vary", "*")]);
+        assert!(!cache.should_store(&request, &parts));
+    }
+
+    #[test]
+    fn goes_stale_by_age_and_request_directives() {
+        let cache = cache(1 << 20);
+        let request = get("/page", &[]);
+        store(
+            &cache,
+            "web",
+            &request,
+            response("old", &[FRESH, ("age", "120")]),
+        );
+        assert_eq!(lookup(&cache, "web", &request), "stale");
+
+        store(
+            &cache,
+            "web",
+            &request,
+            response("new", &[FRESH, ("age", "30")]),
+        );
+        assert_eq!(lookup(&cache, "web", &request), "fresh");
+        let no_cache = get("/page", &[("cache-control", "no-cache")]);
+        assert_eq!(lookup(&cache, "web", &no_cache), "stale");
+        let max_age = get("/page", &[("cache-control", "max-age=10")]);
+        assert_eq!(lookup(&cache, "web", &max_age), "stale");
+
+        // nothing to say how long it is fresh for, but an ETag to revalidate it with
+        let request = get("/tagged", &[]);
+        store(
+            &cache,
+            "web",
+            &request,
+            response("tagged", &[("etag", "\"v1\"")]),
+        );
+        assert_eq!(lookup(&cache, "web", &request), "stale");
+    }
+
+    #[test]
+    fn reads_freshness_from_the_response() {
+        let fresh_for = |headers: &[(&str, &str)]| {
+            let (parts, _) = response("", headers);
+            freshness(&parts.headers, &Directives::parse(&parts.headers))
+        };
+
+        assert_eq!(fresh_for(&[FRESH]), Some(Duration::from_secs(60)));
+        assert_eq!(
+            fresh_for(&[("cache-control", "max-age=60, s-maxage=5")]),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            fresh_for(&[
+                ("date", "Mon, 19 Oct 2026 10:00:00 GMT"),
+                ("expires", "Mon, 19 Oct 2026 10:05:00 GMT"),
+            ]),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(fresh_for(&[("expires", "0")]), Some(Duration::ZERO));
+        assert_eq!(fresh_for(&[]), None);
+
+        // without an ETag, a response fresh for no time is not worth storing
+        let cache = cache(1 << 20);
+        let request = cache.cacheable(&get("/", &[]), "web").unwrap();
+        let (parts, _) = response("", &[("cache-control", "max-age=0")]);
+        assert!(!cache.should_store(&request, &parts));
+    }
+}
@@ -0,0 +1,296 @@
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Mutex, time::Instant};
+
+use crate::utils::config::CircuitBreakerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CircuitState {
+    /// Traffic flows normally
+    Closed,
+    /// The worker failed too often and is skipped
+    Open,
+    /// Letting a few requests through to see whether the worker recovered
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "closed"),
+            Self::Open => write!(f, "open"),
+            Self::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// A circuit per worker of a pool. Without a config every circuit stays closed
+#[derive(Debug, Default)]
+pub(crate) struct CircuitBreakers {
+    config: Option<CircuitBreakerConfig>,
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    opened_at: Instant,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    consecutive_failures: u32,
+    /// Requests let through since the circuit went half-open
+    probes: u32,
+    opened_total: u64,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            state: CircuitState::Closed,
+            opened_at: now,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            probes: 0,
+            opened_total: 0,
+        }
+    }
+
+    fn open(&mut self) {
+        self.state = CircuitState::Open;
+        self.opened_at = Instant::now();
+        self.opened_total += 1;
+        self.reset_window();
+    }
+
+    fn reset_window(&mut self) {
+        self.window_start = Instant::now();
+        self.requests = 0;
+        self.failures = 0;
+        self.consecutive_failures = 0;
+        self.probes = 0;
+    }
+}
+
+impl CircuitBreakers {
+    pub fn new(config: Option<CircuitBreakerConfig>) -> Self {
+        Self {
+            config,
+            circuits: Mutex::default(),
+        }
+    }
+
+    /// Whether the worker can be given a connection. An open circuit whose time is up goes
+    /// half-open here, and a half-open one only allows its probes
+    pub fn allows(&self, worker: SocketAddr) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        let Ok(mut circuits) = self.circuits.lock() else {
+            return true;
+        };
+        let Some(circuit) = circuits.get_mut(&worker) else {
+            return true;
+        };
+
+        if circuit.state == CircuitState::Open && circuit.opened_at.elapsed() >= config.open_for() {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.probes = 0;
+        }
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => circuit.probes < config.half_open_requests.max(1),
+        }
+    }
+
+    /// Counts a connection given to the worker against its half-open probes
+    pub fn selected(&self, worker: SocketAddr) {
+        if self.config.is_none() {
+            return;
+        }
+        if let Ok(mut circuits) = self.circuits.lock() {
+            if let Some(circuit) = circuits.get_mut(&worker) {
+                if circuit.state == CircuitState::HalfOpen {
+                    circuit.probes += 1;
+                }
+            }
+        }
+    }
+
+    /// Records how a connection or request to the worker went, returning the circuit's new
+    /// state when this changed it
+    pub fn record(&self, worker: SocketAddr, success: bool) -> Option<CircuitState> {
+        let config = self.config.as_ref()?;
+        let mut circuits = self.circuits.lock().ok()?;
+        let circuit = circuits.entry(worker).or_insert_with(Circuit::new);
+
+        match circuit.state {
+            // outcomes of connections made before the circuit opened
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if !success => {
+                circuit.open();
+                Some(CircuitState::Open)
+            }
+            CircuitState::HalfOpen => {
+                circuit.requests += 1;
+                if circuit.requests < config.half_open_requests.max(1) {
+                    return None;
+                }
+                circuit.state = CircuitState::Closed;
+                circuit.reset_window();
+                Some(CircuitState::Closed)
+            }
+            CircuitState::Closed => {
+                if circuit.window_start.elapsed() >= config.window() {
+                    circuit.reset_window();
+                }
+                circuit.requests += 1;
+                if success {
+                    circuit.consecutive_failures = 0;
+                    return None;
+                }
+                circuit.failures += 1;
+                circuit.consecutive_failures += 1;
+
+                let error_rate = f64::from(circuit.failures) / f64::from(circuit.requests);
+                let tripped = circuit.consecutive_failures >= config.consecutive_failures.max(1)
+                    || (circuit.requests >= config.min_requests && error_rate >= config.error_rate);
+                if !tripped {
+                    return None;
+                }
+                circuit.open();
+                Some(CircuitState::Open)
+            }
+        }
+    }
+
+    /// Each circuit that has seen traffic, with how often it opened
+    pub fn states(&self) -> Vec<(SocketAddr, CircuitState, u64)> {
+        if self.config.is_none() {
+            return Vec::new();
+        }
+        let Ok(circuits) = self.circuits.lock() else {
+            return Vec::new();
+        };
+        circuits
+            .iter()
+            .map(|(worker, circuit)| (*worker, circuit.state, circuit.opened_total))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const WORKER: &str = "127.0.0.1:9001";
+
+    fn breakers(config: CircuitBreakerConfig) -> (CircuitBreakers, SocketAddr) {
+        (CircuitBreakers::new(Some(config)), WORKER.parse().unwrap())
+    }
+
+    fn state(breakers: &CircuitBreakers) -> (CircuitState, u64) {
+        let states = breakers.states();
+        let (_, state, opened) = states[0];
+        (state, opened)
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let (breakers, worker) = breakers(CircuitBreakerConfig {
+            consecutive_failures: 3,
+            ..Default::default()
+        });
+
+        assert_eq!(breakers.record(worker, false), None);
+        assert_eq!(breakers.record(worker, false), None);
+        // a success starts the count again
+        assert_eq!(breakers.record(worker, true), None);
+        assert_eq!(breakers.record(worker, false), None);
+        assert_eq!(breakers.record(worker, false), None);
+        assert!(breakers.allows(worker));
+        assert_eq!(breakers.record(worker, false), Some(CircuitState::Open));
+        assert!(!breakers.allows(worker));
+        // outcomes of connections made before it opened change nothing
+        assert_eq!(breakers.record(worker, true), None);
+        assert_eq!(state(&breakers), (CircuitState::Open, 1));
+    }
+
+    #[test]
+    fn opens_over_the_error_rate() {
+        let (breakers, worker) = breakers(CircuitBreakerConfig {
+            consecutive_failures: 100,
+            error_rate: 0.5,
+            min_requests: 4,
+            ..Default::default()
+        });
+
+        assert_eq!(breakers.record(worker, false), None);
+        assert_eq!(breakers.record(worker, true), None);
+        assert_eq!(breakers.record(worker, false), None);
+        assert_eq!(breakers.record(worker, false), Some(CircuitState::Open));
+    }
+
+    #[test]
+    fn closes_once_half_open_probes_succeed() {
+        let (breakers, worker) = breakers(CircuitBreakerConfig {
+            consecutive_failures: 1,
+            open_secs: 0,
+            half_open_requests: 2,
+            ..Default::default()
+        });
+        assert_eq!(breakers.record(worker, false), Some(CircuitState::Open));
+
+        // its time is up at once, so it goes half-open and lets two probes through
+        assert!(breakers.allows(worker));
+        assert_eq!(state(&breakers).0, CircuitState::HalfOpen);
+        breakers.selected(worker);
+        assert!(breakers.allows(worker));
+        breakers.selected(worker);
+        assert!(!breakers.allows(worker));
+
+        assert_eq!(breakers.record(worker, true), None);
+        assert_eq!(breakers.record(worker, true), Some(CircuitState::Closed));
+        assert!(breakers.allows(worker));
+        assert_eq!(state(&breakers), (CircuitState::Closed, 1));
+    }
+
+    #[test]
+    fn reopens_when_a_probe_fails() {
+        let (breakers, worker) = breakers(CircuitBreakerConfig {
+            consecutive_failures: 1,
+            open_secs: 30,
+            ..Default::default()
+        });
+        assert_eq!(breakers.record(worker, false), Some(CircuitState::Open));
+        assert!(!breakers.allows(worker));
+
+        // as if its time were up
+        breakers
+            .circuits
+            .lock()
+            .unwrap()
+            .get_mut(&worker)
+            .unwrap()
+            .opened_at -= Duration::from_secs(30);
+        assert!(breakers.allows(worker));
+        breakers.selected(worker);
+        assert_eq!(breakers.record(worker, false), Some(CircuitState::Open));
+        assert!(!breakers.allows(worker));
+        assert_eq!(state(&breakers), (CircuitState::Open, 2));
+    }
+
+    #[test]
+    fn stays_closed_without_a_config() {
+        let breakers = CircuitBreakers::new(None);
+        let worker = WORKER.parse().unwrap();
+        for _ in 0..10 {
+            assert_eq!(breakers.record(worker, false), None);
+        }
+        assert!(breakers.allows(worker));
+        assert!(breakers.states().is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::config::{AdaptiveConcurrencyConfig, ConcurrencyAlgorithm},
+};
+
+/// Weight of a new sample in the short term latency average, roughly the last 10 samples
+const SHORT_WEIGHT: f64 = 0.1;
+/// Weight of a new sample in the long term latency average, roughly the last 500 samples
+const LONG_WEIGHT: f64 = 0.004;
+/// How far the gradient limit moves towards its new value on each sample
+const SMOOTHING: f64 = 0.2;
+/// Latency samples are counted as at least this many milliseconds, as a worker on the same
+/// host can answer in less time than the clock shows and the gradient divides by it
+const MIN_LATENCY_MS: f64 = 0.001;
+
+/// A pool's adaptive limit on connections and requests in progress. Without a config
+/// nothing is limited
+#[derive(Debug, Default)]
+pub(crate) struct ConcurrencyLimit {
+    config: Option<AdaptiveConcurrencyConfig>,
+    state: Mutex<State>,
+    shed: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limit: f64,
+    in_flight: u32,
+    /// Latency averages in milliseconds, zero until the first sample
+    short_latency: f64,
+    long_latency: f64,
+}
+
+/// Counts against the [`ConcurrencyLimit`] until dropped
+#[derive(Debug)]
+pub(crate) struct InFlight(Option<Arc<ConcurrencyLimit>>);
+
+impl ConcurrencyLimit {
+    pub fn new(pool: &str, config: Option<AdaptiveConcurrencyConfig>) -> Result<Self> {
+        let invalid = |reason| LoadBalancerError::InvalidPool {
+            pool: pool.to_string(),
+            reason,
+        };
+        let limit = match &config {
+            Some(config) => {
+                if config.min_limit == 0 || config.min_limit > config.max_limit {
+                    return Err(invalid(
+                        "adaptive concurrency needs 0 < min_limit <= max_limit",
+                    ));
+                }
+                let ratio = config.backoff_ratio;
+                if ratio.is_nan() || ratio <= 0.0 || ratio >= 1.0 {
+                    return Err(invalid("backoff_ratio must be between 0 and 1"));
+                }
+                if config.tolerance.is_nan() || config.tolerance < 1.0 {
+                    return Err(invalid("tolerance must be at least 1"));
+                }
+                config
+                    .initial_limit
+                    .clamp(config.min_limit, config.max_limit)
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            config,
+            state: Mutex::new(State {
+                limit: f64::from(limit),
+                ..State::default()
+            }),
+            shed: AtomicU64::new(0),
+        })
+    }
+
+    /// A slot for one more connection or request, or `None` when the pool is at its limit
+    /// and it should fail straight away
+    pub fn acquire(self: &Arc<Self>) -> Option<InFlight> {
+        if self.config.is_none() {
+            return Some(InFlight(None));
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return Some(InFlight(None));
+        };
+        if f64::from(state.in_flight) >= state.limit.floor() {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlight(Some(self.clone())))
+    }
+
+    /// Moves the limit by how long a worker took to connect or respond
+    fn record(&self, latency: Duration, success: bool) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let latency = (latency.as_secs_f64() * 1000.0).max(MIN_LATENCY_MS);
+        // the limit only grows while it is what holds traffic back
+        let limited = f64::from(state.in_flight) * 2.0 >= state.limit;
+
+        let limit = match config.algorithm {
+            ConcurrencyAlgorithm::Aimd => {
+                if !success || latency > config.latency_threshold().as_secs_f64() * 1000.0 {
+                    state.limit * config.backoff_ratio
+                } else if limited {
+                    state.limit + 1.0
+                } else {
+                    state.limit
+                }
+            }
+            // a failure that came back quickly says nothing about how loaded the workers are
+            ConcurrencyAlgorithm::Gradient if !success => state.limit,
+            ConcurrencyAlgorithm::Gradient => {
+                if state.long_latency == 0.0 {
+                    state.short_latency = latency;
+                    state.long_latency = latency;
+                }
+                state.short_latency += (latency - state.short_latency) * SHORT_WEIGHT;
+                state.long_latency += (latency - state.long_latency) * LONG_WEIGHT;
+                // latency well below the long term average means the workers recovered,
+                // forget the slow period sooner
+                if state.long_latency > state.short_latency * 2.0 {
+                    state.long_latency *= 0.95;
+                }
+
+                let gradient =
+                    (config.tolerance * state.long_latency / state.short_latency).clamp(0.5, 1.0);
+                let mut target = state.limit * gradient + state.limit.sqrt();
+                if !limited {
+                    target = target.min(state.limit);
+                }
+                state.limit + (target - state.limit) * SMOOTHING
+            }
+        };
+        if limit.is_finite() {
+            state.limit = limit.clamp(f64::from(config.min_limit), f64::from(config.max_limit));
+        }
+    }
+
+    /// The current limit, in flight and shed counts, when the pool has a limit
+    pub fn stats(&self) -> Option<(u32, u32, u64)> {
+        self.config.as_ref()?;
+        let state = self.state.lock().ok()?;
+        Some((
+            state.limit.floor() as u32,
+            state.in_flight,
+            self.shed.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+impl InFlight {
+    /// Feeds how long the worker took to accept the connection, or to answer the request,
+    /// and whether it failed
+    pub fn observe(&self, latency: Duration, success: bool) {
+        if let Some(limit) = &self.0 {
+            limit.record(latency, success);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(limit) = &self.0 {
+            if let Ok(mut state) = limit.state.lock() {
+                state.in_flight = state.in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(algorithm: ConcurrencyAlgorithm, initial_limit: u32) -> Arc<ConcurrencyLimit> {
+        let config = AdaptiveConcurrencyConfig {
+            algorithm,
+            initial_limit,
+            min_limit: 2,
+            max_limit: 100,
+            latency_threshold_ms: 100,
+            ..Default::default()
+        };
+        Arc::new(ConcurrencyLimit::new("web", Some(config)).unwrap())
+    }
+
+    fn current(limit: &ConcurrencyLimit) -> f64 {
+        limit.state.lock().unwrap().limit
+    }
+
+    const FAST: Duration = Duration::from_millis(10);
+    const SLOW: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn sheds_past_the_limit() {
+        let limit = limit(ConcurrencyAlgorithm::Aimd, 2);
+        let first = limit.acquire().unwrap();
+        let _second = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+        assert_eq!(limit.stats(), Some((2, 2, 1)));
+
+        drop(first);
+        assert!(limit.acquire().is_some());
+        assert!(ConcurrencyLimit::default().stats().is_none());
+    }
+
+    #[test]
+    fn aimd_grows_by_one_and_backs_off_by_the_ratio() {
+        let limit = limit(ConcurrencyAlgorithm::Aimd, 4);
+        let slots = [limit.acquire().unwrap(), limit.acquire().unwrap()];
+
+        // half the limit in flight counts as limited, so a fast success grows it
+        slots[0].observe(FAST, true);
+        assert_eq!(current(&limit), 5.0);
+        slots[0].observe(SLOW, true);
+        assert_eq!(current(&limit), 4.5);
+        slots[0].observe(FAST, false);
+        assert_eq!(current(&limit), 4.05);
+
+        // with little in flight the limit is not what holds traffic back
+        drop(slots);
+        let slot = limit.acquire().unwrap();
+        slot.observe(FAST, true);
+        assert_eq!(current(&limit), 4.05);
+
+        for _ in 0..20 {
+            slot.observe(FAST, false);
+        }
+        assert_eq!(current(&limit), 2.0);
+    }
+
+    #[test]
+    fn gradient_grows_while_latency_holds_and_shrinks_when_it_rises() {
+        let limit = limit(ConcurrencyAlgorithm::Gradient, 4);
+        let slots: Vec<_> = (0..3).map(|_| limit.acquire().unwrap()).collect();
+
+        for _ in 0..10 {
+            slots[0].observe(FAST, true);
+        }
+        let grown = current(&limit);
+        assert!(grown > 4.0);
+
+        for _ in 0..10 {
+            slots[0].observe(SLOW, true);
+        }
+        assert!(current(&limit) < grown);
+
+        // a failure says nothing about load
+        let before = current(&limit);
+        slots[0].observe(Duration::from_millis(1), false);
+        assert_eq!(current(&limit), before);
+    }
+
+    #[test]
+    fn gradient_survives_zero_latency() {
+        let limit = limit(ConcurrencyAlgorithm::Gradient, 4);
+        let slots: Vec<_> = (0..3).map(|_| limit.acquire().unwrap()).collect();
+
+        for _ in 0..5 {
+            slots[0].observe(Duration::ZERO, true);
+        }
+        slots[0].observe(FAST, true);
+        slots[0].observe(Duration::ZERO, true);
+
+        let current = current(&limit);
+        assert!(current.is_finite());
+        assert!((2.0..=100.0).contains(&current));
+    }
+}
@@ -0,0 +1,383 @@
+use std::net::SocketAddr;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::{
+    client::conn::http2,
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, TE},
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tracing::{event, Level};
+
+use crate::error::{LoadBalancerError, Result};
+
+use super::{
+    body::{full, ProxyBody},
+    pool::WorkerPool,
+};
+
+const GRPC_STATUS: HeaderName = HeaderName::from_static("grpc-status");
+const GRPC_MESSAGE: HeaderName = HeaderName::from_static("grpc-message");
+const GRPC_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("application/grpc");
+
+pub(crate) const GRPC_OK: u16 = 0;
+/// Rate limited, the client should back off before retrying
+const GRPC_RESOURCE_EXHAUSTED: u16 = 8;
+/// The worker could not take the call, the status gRPC clients retry on
+pub(crate) const GRPC_UNAVAILABLE: u16 = 14;
+
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+const SERVING: u64 = 1;
+
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// The call's status, from the trailers or from the headers of a trailers-only response
+pub(crate) fn grpc_status(headers: &HeaderMap) -> Option<u16> {
+    headers.get(GRPC_STATUS)?.to_str().ok()?.parse().ok()
+}
+
+/// gRPC clients expect failures as a trailers-only response with a gRPC status rather than
+/// an HTTP error status
+pub(crate) fn error_response(status: StatusCode) -> Response<ProxyBody> {
+    let mut response = Response::new(full(Bytes::new()));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, GRPC_CONTENT_TYPE);
+    let grpc_status = match status {
+        StatusCode::TOO_MANY_REQUESTS => GRPC_RESOURCE_EXHAUSTED,
+        _ => GRPC_UNAVAILABLE,
+    };
+    headers.insert(GRPC_STATUS, HeaderValue::from(grpc_status));
+    if let Some(reason) = status.canonical_reason() {
+        headers.insert(GRPC_MESSAGE, HeaderValue::from_static(reason));
+    }
+    response
+}
+
+/// Calls `grpc.health.v1.Health/Check` on the worker over HTTP/2, healthy only when the
+/// call succeeds and answers `SERVING`
+pub(crate) async fn check_worker_health(pool: &WorkerPool, worker: SocketAddr) -> Result<()> {
+    let upstream_error = |source| LoadBalancerError::UpstreamHttp { worker, source };
+
+    let stream = pool.connect(worker, None).await?;
+    let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+        .await
+        .map_err(upstream_error)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            event!(
+                Level::DEBUG,
+                "Health check connection to {worker} closed. {e}"
+            );
+        }
+    });
+
+    let scheme = if pool.uses_tls() { "https" } else { "http" };
+    let uri = format!("{scheme}://{worker}{HEALTH_CHECK_PATH}")
+        .parse::<Uri>()
+        .unwrap_or_else(|_| Uri::from_static(HEALTH_CHECK_PATH));
+    let mut request = Request::new(full(encode_message(&health_check_request(
+        &pool.health_check.service,
+    ))));
+    *request.method_mut() = Method::POST;
+    *request.uri_mut() = uri;
+    request
+        .headers_mut()
+        .insert(CONTENT_TYPE, GRPC_CONTENT_TYPE);
+    request
+        .headers_mut()
+        .insert(TE, HeaderValue::from_static("trailers"));
+
+    let response = sender.send_request(request).await.map_err(upstream_error)?;
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.map_err(upstream_error)?;
+    let status = grpc_status(&parts.headers).or_else(|| body.trailers().and_then(grpc_status));
+
+    let not_serving = |reason: String| LoadBalancerError::GrpcNotServing { worker, reason };
+    match status {
+        Some(GRPC_OK) => {}
+        Some(status) => return Err(not_serving(format!("grpc-status {status}"))),
+        None => return Err(not_serving(format!("http status {}", parts.status))),
+    }
+    match serving_status(&body.to_bytes()) {
+        Some(SERVING) => Ok(()),
+        Some(status) => Err(not_serving(format!("serving status {status}"))),
+        None => Err(not_serving("malformed HealthCheckResponse".to_string())),
+    }
+}
+
+/// `HealthCheckRequest { string service = 1; }`, left empty for the default service
+fn health_check_request(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);
+        put_varint(&mut message, service.len() as u64);
+        message.extend_from_slice(service.as_bytes());
+    }
+    message
+}
+
+/// Prefixes a message with the uncompressed flag and its length
+fn encode_message(message: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(5 + message.len());
+    framed.put_u8(0);
+    framed.put_u32(message.len() as u32);
+    framed.put_slice(message);
+    framed.freeze()
+}
+
+/// Reads `status` (field 1) from a framed `HealthCheckResponse`
+fn serving_status(body: &[u8]) -> Option<u64> {
+    let len = u32::from_be_bytes(body.get(1..5)?.try_into().ok()?) as usize;
+    let mut message = body.get(5..5 + len)?;
+
+    // proto3 leaves out default values, an empty message is UNKNOWN (0)
+    let mut status = 0;
+    while !message.is_empty() {
+        let tag = read_varint(&mut message)?;
+        match (tag >> 3, tag & 0x7) {
+            (1, 0) => status = read_varint(&mut message)?,
+            (_, 0) => {
+                read_varint(&mut message)?;
+            }
+            (_, 2) => {
+                let len = read_varint(&mut message)? as usize;
+                message = message.get(len..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::{server::conn::http2 as server, service::service_fn};
+    use tokio::net::TcpListener;
+
+    use crate::utils::config::PoolConfig;
+
+    use super::*;
+
+    /// A framed `HealthCheckResponse` made of `fields`, already encoded
+    fn response(fields: &[u8]) -> Bytes {
+        encode_message(fields)
+    }
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, value);
+        buf
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX.into(),
+            u64::MAX,
+        ] {
+            let encoded = varint(value);
+            let mut buf = encoded.as_slice();
+            assert_eq!(read_varint(&mut buf), Some(value), "{value}");
+            assert!(buf.is_empty());
+        }
+        assert_eq!(varint(300), [0xac, 0x02]);
+    }
+
+    #[test]
+    fn read_varint_leaves_the_rest() {
+        let mut buf: &[u8] = &[0x96, 0x01, 0x08];
+        assert_eq!(read_varint(&mut buf), Some(150));
+        assert_eq!(buf, [0x08]);
+    }
+
+    #[test]
+    fn read_varint_rejects_truncated_and_overlong_input() {
+        assert_eq!(read_varint(&mut &[][..]), None);
+        assert_eq!(read_varint(&mut &[0x80, 0x80][..]), None);
+        assert_eq!(read_varint(&mut &[0xff; 11][..]), None);
+    }
+
+    #[test]
+    fn frames_messages_with_their_length() {
+        let framed = encode_message(b"hello");
+        assert_eq!(&framed[..], b"\x00\x00\x00\x00\x05hello");
+        assert_eq!(&encode_message(&[])[..], [0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_the_service_name() {
+        assert!(health_check_request("").is_empty());
+
+        let request = health_check_request("my.Service");
+        assert_eq!(request[0], 0x0a);
+        let mut rest = &request[1..];
+        assert_eq!(read_varint(&mut rest), Some(10));
+        assert_eq!(rest, b"my.Service");
+
+        // a name past 127 bytes takes a two byte length
+        let long = "s".repeat(200);
+        let request = health_check_request(&long);
+        assert_eq!(&request[..3], [0x0a, 0xc8, 0x01]);
+        assert_eq!(request.len(), 203);
+    }
+
+    #[test]
+    fn reads_the_serving_status() {
+        assert_eq!(serving_status(&response(&[0x08, 0x01])), Some(SERVING));
+        // NOT_SERVING
+        assert_eq!(serving_status(&response(&[0x08, 0x02])), Some(2));
+        // proto3 leaves out UNKNOWN
+        assert_eq!(serving_status(&response(&[])), Some(0));
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        let mut fields = vec![0x10];
+        fields.extend(varint(1_000));
+        fields.extend([0x1a, 0x03]);
+        fields.extend(b"abc");
+        fields.extend([0x08, 0x01]);
+        assert_eq!(serving_status(&response(&fields)), Some(SERVING));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        // no room for the length prefix
+        assert_eq!(serving_status(&[0, 0, 0]), None);
+        // a length past the end of the body
+        assert_eq!(serving_status(&[0, 0, 0, 0, 5, 0x08, 0x01]), None);
+        // fixed64 and fixed32 fields are not expected in the response
+        assert_eq!(
+            serving_status(&response(&[0x09, 0, 0, 0, 0, 0, 0, 0, 0])),
+            None
+        );
+        assert_eq!(serving_status(&response(&[0x0d, 0, 0, 0, 0])), None);
+        // a status cut off mid varint, or a length delimited field past the message
+        assert_eq!(serving_status(&response(&[0x08, 0x80])), None);
+        assert_eq!(serving_status(&response(&[0x1a, 0x05, b'a'])), None);
+    }
+
+    #[test]
+    fn maps_http_errors_to_grpc_statuses() {
+        for (status, grpc) in [
+            (StatusCode::TOO_MANY_REQUESTS, GRPC_RESOURCE_EXHAUSTED),
+            (StatusCode::SERVICE_UNAVAILABLE, GRPC_UNAVAILABLE),
+            (StatusCode::BAD_GATEWAY, GRPC_UNAVAILABLE),
+            (StatusCode::GATEWAY_TIMEOUT, GRPC_UNAVAILABLE),
+        ] {
+            let response = error_response(status);
+            let headers = response.headers();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(is_grpc(headers));
+            assert_eq!(grpc_status(headers), Some(grpc));
+            assert_eq!(
+                headers.get(GRPC_MESSAGE).unwrap(),
+                status.canonical_reason().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn recognises_grpc_requests() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_grpc(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        assert!(is_grpc(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_grpc(&headers));
+
+        headers.insert(GRPC_STATUS, HeaderValue::from_static("14"));
+        assert_eq!(grpc_status(&headers), Some(14));
+        headers.insert(GRPC_STATUS, HeaderValue::from_static("unavailable"));
+        assert_eq!(grpc_status(&headers), None);
+    }
+
+    /// A worker answering every health check with `status`, in a response with `grpc_status`
+    async fn health_worker(grpc_status: u16, status: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(move |request: Request<_>| async move {
+                    assert_eq!(request.uri().path(), HEALTH_CHECK_PATH);
+                    let mut response = Response::new(full(encode_message(&[0x08, status])));
+                    let headers = response.headers_mut();
+                    headers.insert(CONTENT_TYPE, GRPC_CONTENT_TYPE);
+                    headers.insert(GRPC_STATUS, HeaderValue::from(grpc_status));
+                    Ok::<_, Infallible>(response)
+                });
+                tokio::spawn(
+                    server::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn checks_the_worker_over_http2() {
+        let serving = health_worker(GRPC_OK, 1).await;
+        let not_serving = health_worker(GRPC_OK, 2).await;
+        let failing = health_worker(GRPC_UNAVAILABLE, 1).await;
+        let pool = WorkerPool::new(
+            "grpc".into(),
+            vec![serving, not_serving, failing],
+            &PoolConfig::default(),
+        )
+        .unwrap();
+
+        assert!(check_worker_health(&pool, serving).await.is_ok());
+        for (worker, expected) in [
+            (not_serving, "serving status 2"),
+            (failing, "grpc-status 14"),
+        ] {
+            match check_worker_health(&pool, worker).await {
+                Err(LoadBalancerError::GrpcNotServing { reason, .. }) => {
+                    assert_eq!(reason, expected)
+                }
+                other => panic!("{worker}: {other:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::net::IpAddr;
+
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONNECTION, FORWARDED, HOST, PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    HeaderMap, Request,
+};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Headers that only apply to one hop and are never forwarded
+const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Removes the hop-by-hop headers, including any the `Connection` header names
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+
+    for name in listed.iter().chain(HOP_BY_HOP_HEADERS.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Tells the worker who the client is. A trusted proxy's headers are kept and this hop is
+/// appended, from anyone else they are replaced
+pub(crate) fn set_forwarded_headers(
+    headers: &mut HeaderMap,
+    client: IpAddr,
+    proto: &'static str,
+    trusted: bool,
+) {
+    let client = client.to_canonical();
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(String::from);
+
+    if !trusted {
+        for name in [
+            &X_FORWARDED_FOR,
+            &X_FORWARDED_PROTO,
+            &X_FORWARDED_HOST,
+            &FORWARDED,
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    append_to_list(headers, X_FORWARDED_FOR, &client.to_string());
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+
+    let node = match client {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    };
+    let mut element = format!("for={node};proto={proto}");
+    if let Some(host) = host {
+        element.push_str(&format!(";host={}", forwarded_value(&host)));
+        if !headers.contains_key(&X_FORWARDED_HOST) {
+            if let Ok(host) = HeaderValue::from_str(&host) {
+                headers.insert(X_FORWARDED_HOST, host);
+            }
+        }
+    }
+    append_to_list(headers, FORWARDED, &element);
+}
+
+/// Joins every existing line of the header and the new value into one comma separated line
+fn append_to_list(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut values: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|existing| existing.to_str().ok())
+        .collect();
+    values.push(value);
+
+    if let Ok(combined) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(name, combined);
+    }
+}
+
+/// RFC 7239 values are tokens, anything else (such as `host:port`) has to be quoted
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        return value.to_string();
+    }
+
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+/// HTTP/2 carries the host in the `:authority` pseudo-header, HTTP/1.1 workers need `Host`
+pub(crate) fn set_host_from_authority<B>(request: &mut Request<B>) {
+    if request.headers().contains_key(HOST) {
+        return;
+    }
+    let host = request
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+    if let Some(host) = host {
+        request.headers_mut().insert(HOST, host);
+    }
+}
+
+/// The protocol a request asks to switch to with `Connection: upgrade`, e.g. `websocket`
+pub(crate) fn requested_upgrade(headers: &HeaderMap) -> Option<HeaderValue> {
+    let upgrading = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    upgrading.then(|| headers.get(UPGRADE).cloned()).flatten()
+}
+
+/// Puts back the upgrade headers [`strip_hop_by_hop`] removed, so the next hop switches too
+pub(crate) fn keep_upgrade(headers: &mut HeaderMap, protocol: &HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::try_from(*name).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn sets_the_headers_for_a_direct_client() {
+        let mut headers = headers(&[("host", "example.com")]);
+        set_forwarded_headers(&mut headers, ip("203.0.113.7"), "http", false);
+
+        assert_eq!(get(&headers, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+        assert_eq!(get(&headers, "x-forwarded-host"), Some("example.com"));
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=203.0.113.7;proto=http;host=example.com")
+        );
+    }
+
+    #[test]
+    fn replaces_headers_spoofed_by_an_untrusted_client() {
+        let mut headers = headers(&[
+            ("host", "example.com"),
+            ("x-forwarded-for", "10.0.0.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.example"),
+            ("forwarded", "for=10.0.0.1"),
+        ]);
+        set_forwarded_headers(&mut headers, ip("203.0.113.7"), "http", false);
+
+        assert_eq!(get(&headers, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(headers.get_all("x-forwarded-for").iter().count(), 1);
+        assert_eq!(get(&headers, "x-forwarded-proto"), Some("http"));
+        assert_eq!(get(&headers, "x-forwarded-host"), Some("example.com"));
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=203.0.113.7;proto=http;host=example.com")
+        );
+    }
+
+    #[test]
+    fn appends_to_a_trusted_proxys_chain() {
+        let mut headers = headers(&[
+            ("host", "example.com"),
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-forwarded-for", "198.51.100.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "public.example.com"),
+            ("forwarded", "for=198.51.100.1;proto=https"),
+        ]);
+        set_forwarded_headers(&mut headers, ip("10.0.0.5"), "http", true);
+
+        assert_eq!(
+            get(&headers, "x-forwarded-for"),
+            Some("198.51.100.1, 198.51.100.2, 10.0.0.5")
+        );
+        // the first hop saw the client's protocol and host
+        assert_eq!(get(&headers, "x-forwarded-proto"), Some("https"));
+        assert_eq!(
+            get(&headers, "x-forwarded-host"),
+            Some("public.example.com")
+        );
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=198.51.100.1;proto=https, for=10.0.0.5;proto=http;host=example.com")
+        );
+    }
+
+    #[test]
+    fn a_trusted_proxy_without_headers_starts_the_chain() {
+        let mut headers = HeaderMap::new();
+        set_forwarded_headers(&mut headers, ip("10.0.0.5"), "https", true);
+
+        assert_eq!(get(&headers, "x-forwarded-for"), Some("10.0.0.5"));
+        assert_eq!(get(&headers, "x-forwarded-proto"), Some("https"));
+        assert_eq!(get(&headers, "x-forwarded-host"), None);
+        assert_eq!(get(&headers, "forwarded"), Some("for=10.0.0.5;proto=https"));
+    }
+
+    #[test]
+    fn quotes_ipv6_clients_in_forwarded() {
+        let mut headers = HeaderMap::new();
+        set_forwarded_headers(&mut headers, ip("2001:db8::1"), "http", false);
+
+        assert_eq!(get(&headers, "x-forwarded-for"), Some("2001:db8::1"));
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=\"[2001:db8::1]\";proto=http")
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_are_written_as_ipv4() {
+        let mut headers = HeaderMap::new();
+        set_forwarded_headers(&mut headers, ip("::ffff:203.0.113.7"), "http", false);
+
+        assert_eq!(get(&headers, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=203.0.113.7;proto=http")
+        );
+    }
+
+    #[test]
+    fn quotes_hosts_that_are_not_tokens() {
+        let mut headers = headers(&[("host", "example.com:8080")]);
+        set_forwarded_headers(&mut headers, ip("203.0.113.7"), "http", false);
+
+        assert_eq!(get(&headers, "x-forwarded-host"), Some("example.com:8080"));
+        assert_eq!(
+            get(&headers, "forwarded"),
+            Some("for=203.0.113.7;proto=http;host=\"example.com:8080\"")
+        );
+        assert_eq!(forwarded_value("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(forwarded_value(""), "\"\"");
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers_and_those_connection_names() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, x-secret"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-kept", "1"),
+        ]);
+        strip_hop_by_hop(&mut headers);
+
+        let names: Vec<_> = headers.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["x-kept"]);
+    }
+}
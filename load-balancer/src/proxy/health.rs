@@ -0,0 +1,170 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result, WorkerPhase},
+    services::history::HistoryWriter,
+    utils::{config::HealthCheckKind, stream_reader::read_status_code},
+};
+
+use super::{grpc, pool::WorkerPool, udp};
+
+/// Probes every worker in the pool, applies the result and records health transitions
+pub(crate) async fn check_workers_health(
+    pool: &WorkerPool,
+    history: Option<&HistoryWriter>,
+) -> HashMap<Arc<SocketAddr>, bool> {
+    let worker_addrs = pool.workers.read().await.worker_addrs.clone();
+
+    let mut worker_health_map = HashMap::new();
+    for worker in worker_addrs {
+        let healthy = match check_worker_health(pool, *worker).await {
+            Ok(()) => true,
+            Err(e) => {
+                event!(Level::WARN, "{e}");
+                false
+            }
+        };
+        worker_health_map.insert(worker, healthy);
+    }
+
+    let transitions = pool
+        .workers
+        .write()
+        .await
+        .update_healthy_workers(worker_health_map.clone());
+    for (worker, healthy) in &worker_health_map {
+        if !healthy {
+            pool.keep_alive.evict(**worker);
+        }
+    }
+    pool.keep_alive.evict_expired();
+    if let Some(history) = history {
+        for (worker, healthy) in transitions {
+            history.record_health_transition(*worker, healthy);
+        }
+    }
+
+    worker_health_map
+}
+
+async fn check_worker_health(pool: &WorkerPool, worker: SocketAddr) -> Result<()> {
+    let timed_out = || {
+        LoadBalancerError::worker(worker, WorkerPhase::HealthCheck)(
+            std::io::ErrorKind::TimedOut.into(),
+        )
+    };
+    match pool.health_check.kind {
+        HealthCheckKind::Http => {}
+        HealthCheckKind::Tcp => {
+            return timeout(pool.health_check.timeout(), pool.connect(worker, None))
+                .await
+                .map_err(|_| timed_out())?
+                .map(drop);
+        }
+        HealthCheckKind::Grpc => {
+            return timeout(
+                pool.health_check.timeout(),
+                grpc::check_worker_health(pool, worker),
+            )
+            .await
+            .map_err(|_| timed_out())?;
+        }
+        HealthCheckKind::Udp => return check_udp(worker, pool.health_check.timeout()).await,
+    }
+
+    let probe = async {
+        let mut stream = pool.connect(worker, None).await?;
+
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    pool.health_check.path, worker
+                )
+                .as_bytes(),
+            )
+            .await
+            .map_err(LoadBalancerError::worker(worker, WorkerPhase::HealthCheck))?;
+
+        let mut buf = Vec::new();
+        stream
+            .read_to_end(&mut buf)
+            .await
+            .map_err(LoadBalancerError::worker(worker, WorkerPhase::HealthCheck))?;
+        Ok::<_, LoadBalancerError>(buf)
+    };
+
+    let buf = timeout(pool.health_check.timeout(), probe)
+        .await
+        .map_err(|_| timed_out())??;
+
+    let response = String::from_utf8_lossy(&buf);
+
+    let status_code = read_status_code(response.lines().next().unwrap_or(""));
+
+    // assuming all responses under 400 are healthy
+    if status_code >= 400 {
+        return Err(LoadBalancerError::UnhealthyWorker {
+            worker,
+            status_code,
+        });
+    }
+
+    Ok(())
+}
+
+/// A reply and silence both count as healthy, only an ICMP port unreachable, left on the
+/// connected socket as a refused connection, fails the check
+async fn check_udp(worker: SocketAddr, wait: Duration) -> Result<()> {
+    let failed = || LoadBalancerError::worker(worker, WorkerPhase::HealthCheck);
+    let socket = udp::connect(worker).await.map_err(failed())?;
+    socket.send(&[]).await.map_err(failed())?;
+
+    let mut reply = [0u8; 512];
+    match timeout(wait, socket.recv(&mut reply)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(failed()(e)),
+        // the error does not always wake a pending receive, so it is read off the socket
+        Err(_) => match socket.take_error() {
+            Ok(Some(e)) => Err(failed()(e)),
+            _ => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(200);
+
+    #[tokio::test]
+    async fn udp_check_fails_only_on_port_unreachable() {
+        let answering = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let answering_addr = answering.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut datagram = [0u8; 16];
+            while let Ok((_, from)) = answering.recv_from(&mut datagram).await {
+                let _ = answering.send_to(b"pong", from).await;
+            }
+        });
+        assert!(check_udp(answering_addr, WAIT).await.is_ok());
+
+        // like syslog, never replies
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(check_udp(silent.local_addr().unwrap(), WAIT).await.is_ok());
+
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        assert!(check_udp(closed_addr, WAIT).await.is_err());
+    }
+}
@@ -0,0 +1,889 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Incoming},
+    client::conn::{http1 as client_http1, http2 as client_http2},
+    header::{HeaderValue, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, RETRY_AFTER, TE},
+    service::service_fn,
+    upgrade::{self, OnUpgrade},
+    Method, Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result, WorkerPhase},
+    utils::{
+        access_log::{duration_ms, AccessLogRecord, CacheStatus, TerminationReason},
+        config::ListenerConfig,
+    },
+};
+
+use super::{
+    acl::Acl,
+    body::{full, BodyStats, CountingBody, ProxyBody},
+    cache::{CacheableRequest, HttpCache, Lookup},
+    concurrency::InFlight,
+    grpc::{self, grpc_status, is_grpc, GRPC_UNAVAILABLE},
+    headers::{
+        keep_upgrade, requested_upgrade, set_forwarded_headers, set_host_from_authority,
+        strip_hop_by_hop,
+    },
+    keepalive::UpstreamSender,
+    limits::ConnectionPermit,
+    listener::accept,
+    load_balancer::ProxyContext,
+    mirror::{Mirror, MirrorJob, TeeBody},
+    pool::WorkerPool,
+    proxy_protocol::{read_header, ProxiedConnection},
+    rate_limit::RateLimiter,
+    router::{request_host, HttpRouter},
+    split::TrafficSplit,
+    stream::{splice, BoxedStream, Side},
+};
+
+/// Offered to TLS clients on HTTP listeners, h2 first
+pub(crate) const HTTP_ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// What the connections to an HTTP listener share, besides the listener's config
+pub(crate) struct HttpListener {
+    pub tls: Option<TlsAcceptor>,
+    pub router: Arc<HttpRouter>,
+    pub cache: Option<Arc<HttpCache>>,
+    pub mirror: Option<Arc<Mirror>>,
+}
+
+/// Serves HTTP/1.1 and HTTP/2 (negotiated by ALPN, or h2c with prior knowledge) on the
+/// listener, terminating TLS first when it has a certificate. Every request or stream is
+/// routed to a pool and a worker on its own and logged as its own access log record
+pub(crate) async fn serve_http(
+    listener: TcpListener,
+    config: ListenerConfig,
+    http: HttpListener,
+    limiter: Option<Arc<RateLimiter>>,
+    acl: Arc<Acl>,
+    split: Option<Arc<TrafficSplit>>,
+    ctx: ProxyContext,
+) {
+    let Some(pool) = ctx.pool(&config.pool).cloned() else {
+        event!(
+            Level::ERROR,
+            "Listener {} references unknown pool {}",
+            config.address,
+            config.pool
+        );
+        return;
+    };
+    let config = Arc::new(config);
+
+    loop {
+        let (inbound, client_addr) = accept(&listener).await;
+        // dropping the stream closes it before anything is read
+        if !acl.permits(client_addr.ip()) {
+            event!(Level::DEBUG, "ACL denied {client_addr}");
+            ctx.report(AccessLogRecord::new(
+                config.address,
+                client_addr,
+                TerminationReason::Denied,
+            ));
+            continue;
+        }
+
+        let connection = HttpConnection {
+            client_addr,
+            local_addr: inbound.local_addr().unwrap_or(config.address),
+            config: config.clone(),
+            pool: pool.clone(),
+            router: http.router.clone(),
+            cache: http.cache.clone(),
+            mirror: http.mirror.clone(),
+            split: split.clone(),
+            limiter: limiter.clone(),
+            server_name: None,
+            tls: http.tls.is_some(),
+            ctx: ctx.clone(),
+        };
+        tokio::spawn(connection.serve(inbound, http.tls.clone()));
+    }
+}
+
+struct HttpConnection {
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    config: Arc<ListenerConfig>,
+    pool: WorkerPool,
+    router: Arc<HttpRouter>,
+    cache: Option<Arc<HttpCache>>,
+    mirror: Option<Arc<Mirror>>,
+    split: Option<Arc<TrafficSplit>>,
+    limiter: Option<Arc<RateLimiter>>,
+    server_name: Option<String>,
+    tls: bool,
+    ctx: ProxyContext,
+}
+
+impl HttpConnection {
+    async fn serve(mut self, mut inbound: TcpStream, tls: Option<TlsAcceptor>) {
+        if self.config.accept_proxy_protocol {
+            match read_header(&mut inbound).await {
+                Ok(Some(proxied)) => {
+                    self.client_addr = proxied.source;
+                    self.local_addr = proxied.destination;
+                }
+                Ok(None) => {}
+                Err(reason) => {
+                    let e = LoadBalancerError::ProxyProtocol {
+                        client: self.client_addr,
+                        reason,
+                    };
+                    event!(Level::WARN, "{e}");
+                    let mut record = AccessLogRecord::new(
+                        self.config.address,
+                        self.client_addr,
+                        TerminationReason::InvalidProxyHeader,
+                    );
+                    record.error = Some(e.to_string());
+                    self.ctx.report(record);
+                    return;
+                }
+            }
+        }
+        let client_addr = self.client_addr;
+
+        let inbound: BoxedStream = match tls {
+            Some(acceptor) => match acceptor.accept(inbound).await {
+                Ok(tls_stream) => {
+                    self.server_name = tls_stream.get_ref().1.server_name().map(String::from);
+                    Box::new(tls_stream)
+                }
+                Err(source) => {
+                    let e = LoadBalancerError::TlsHandshake {
+                        client: client_addr,
+                        source,
+                    };
+                    event!(Level::WARN, "{e}");
+                    let mut record = AccessLogRecord::new(
+                        self.config.address,
+                        client_addr,
+                        TerminationReason::TlsHandshakeFailed,
+                    );
+                    record.error = Some(e.to_string());
+                    self.ctx.report(record);
+                    return;
+                }
+            },
+            None => Box::new(inbound),
+        };
+
+        let connection = Arc::new(self);
+        let service = service_fn(move |request| {
+            let connection = connection.clone();
+            async move { Ok::<_, Infallible>(connection.proxy(request).await) }
+        });
+
+        if let Err(source) = auto::Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(TokioIo::new(inbound), service)
+            .await
+        {
+            let e = LoadBalancerError::HttpConnection {
+                client: client_addr,
+                source,
+            };
+            event!(Level::DEBUG, "{e}");
+        }
+    }
+
+    async fn proxy(self: &Arc<Self>, mut request: Request<Incoming>) -> Response<ProxyBody> {
+        let started = Instant::now();
+        set_host_from_authority(&mut request);
+
+        let mut record = AccessLogRecord::new(
+            self.config.address,
+            self.client_addr,
+            TerminationReason::Completed,
+        );
+        record.server_name = self.server_name.clone();
+        record.method = Some(request.method().to_string());
+        record.host = request_host(&request).map(String::from);
+        record.path = Some(request.uri().path().to_string());
+
+        if let Some(limiter) = &self.limiter {
+            if let Err(retry_after) = limiter.check_request(self.client_addr.ip(), &request) {
+                record.status = Some(StatusCode::TOO_MANY_REQUESTS.as_u16());
+                record.termination = TerminationReason::RateLimited;
+                record.duration_ms = duration_ms(started.elapsed());
+                self.ctx.report(record);
+
+                let mut response =
+                    error_response(StatusCode::TOO_MANY_REQUESTS, is_grpc(request.headers()));
+                let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return response;
+            }
+        }
+
+        let route = self.router.route(&request);
+        let pool = match (route, &self.split) {
+            (Some(route), _) => route.pool.clone(),
+            (None, Some(split)) => split.pick().clone(),
+            (None, None) => self.pool.clone(),
+        };
+        record.pool = Some(pool.name.clone());
+
+        // answered before taking a connection permit, hits never reach a worker. Each pool
+        // has its own entries, as routes and the split send one URL to different pools
+        let cacheable = self.cache.as_ref().and_then(|cache| {
+            cache.invalidate(&request, &pool.name);
+            cache.cacheable(&request, &pool.name)
+        });
+        let mut stale = None;
+        if let (Some(cache), Some(cacheable)) = (&self.cache, &cacheable) {
+            match cache.lookup(cacheable) {
+                Lookup::Fresh(entry) => {
+                    let response = entry.respond(cacheable);
+                    cache.count(CacheStatus::Hit);
+                    record.cache = Some(CacheStatus::Hit);
+                    record.status = Some(response.status().as_u16());
+                    record.bytes_out = response.body().size_hint().exact().unwrap_or_default();
+                    record.duration_ms = duration_ms(started.elapsed());
+                    self.ctx.report(record);
+                    return response;
+                }
+                // revalidated on the client's behalf, unless it sent a condition of its own
+                Lookup::Stale(entry) => {
+                    let conditional = request.headers().contains_key(IF_NONE_MATCH)
+                        || request.headers().contains_key(IF_MODIFIED_SINCE);
+                    if let (Some(etag), false) = (entry.etag(), conditional) {
+                        request.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+                        stale = Some(entry);
+                    }
+                }
+                Lookup::Miss => {}
+            }
+        }
+
+        let Some(permit) = self.ctx.admit(true).await else {
+            event!(
+                Level::WARN,
+                "At max connections, refusing request from {}",
+                self.client_addr
+            );
+            record.status = Some(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+            record.termination = TerminationReason::OverCapacity;
+            record.duration_ms = duration_ms(started.elapsed());
+            self.ctx.report(record);
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, is_grpc(request.headers()));
+        };
+
+        let Some(in_flight) = pool.concurrency.acquire() else {
+            event!(
+                Level::WARN,
+                "Pool {} over its concurrency limit, refusing request from {}",
+                pool.name,
+                self.client_addr
+            );
+            record.status = Some(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+            record.termination = TerminationReason::Shed;
+            record.duration_ms = duration_ms(started.elapsed());
+            self.ctx.report(record);
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, is_grpc(request.headers()));
+        };
+
+        if let Some(http) = &self.config.http {
+            if http.forwarded_headers {
+                let client = self.client_addr.ip().to_canonical();
+                let trusted = http.trusted_proxies.iter().any(|net| net.contains(&client));
+                let proto = if self.tls { "https" } else { "http" };
+                set_forwarded_headers(request.headers_mut(), client, proto, trusted);
+            }
+        }
+        let rewrite = route.map(|route| &route.rewrite);
+        if let Some(rewrite) = rewrite {
+            rewrite.request(&mut request);
+        }
+
+        // WebSockets and other upgrades, and CONNECT tunnels, become a raw splice once the
+        // worker agrees to switch
+        let upgrade = requested_upgrade(request.headers());
+        let is_connect = request.method() == Method::CONNECT;
+        let client_upgrade = (upgrade.is_some() || is_connect).then(|| upgrade::on(&mut request));
+        let grpc = is_grpc(request.headers());
+
+        // copied after the rewrite, so the shadow pool is sent what the primary worker is
+        let (request, primary) = match (&self.mirror, &client_upgrade) {
+            (Some(mirror), None) => {
+                let (request, mirrored) = mirror.sample(request);
+                let primary = mirrored.map(|mirrored| {
+                    tokio::spawn(self.clone().mirror(mirrored.job));
+                    mirrored.primary
+                });
+                (request, primary)
+            }
+            _ => (request.map(TeeBody::passthrough), None),
+        };
+
+        let mut connect_start = Instant::now();
+        let connected = self
+            .connect(&pool, &mut record, true, &mut connect_start)
+            .await;
+        let (worker, sender) = match connected {
+            Ok(connected) => connected,
+            Err(status) => {
+                if record.worker.is_some() {
+                    in_flight.observe(connect_start.elapsed(), false);
+                }
+                record.status = Some(status.as_u16());
+                record.duration_ms = duration_ms(started.elapsed());
+                self.ctx.report(record);
+                return error_response(status, grpc);
+            }
+        };
+        record.worker = Some(worker);
+        record.connect_latency_ms = Some(duration_ms(connect_start.elapsed()));
+
+        let mut guard = RequestGuard {
+            record: Some(record),
+            started,
+            request_stats: Arc::default(),
+            response_stats: Arc::default(),
+            pool: pool.clone(),
+            worker,
+            ctx: self.ctx.clone(),
+            in_flight,
+            _permit: permit,
+        };
+
+        let request_stats = guard.request_stats.clone();
+        match self
+            .forward(
+                &pool,
+                worker,
+                sender,
+                request,
+                request_stats,
+                upgrade.as_ref(),
+            )
+            .await
+        {
+            Ok(mut response) => {
+                if let Some(primary) = primary {
+                    let _ = primary.send((response.status(), connect_start.elapsed()));
+                }
+                guard.in_flight.observe(
+                    connect_start.elapsed(),
+                    !response.status().is_server_error(),
+                );
+                guard.set_status(response.status());
+                guard.set_response_latency(connect_start.elapsed());
+                if grpc {
+                    guard.set_grpc_status(grpc_status(response.headers()));
+                }
+                if let (Some(cache), Some(cacheable)) = (&self.cache, &cacheable) {
+                    if let (Some(stale), StatusCode::NOT_MODIFIED) = (&stale, response.status()) {
+                        let entry = cache.revalidated(cacheable, stale, response.headers());
+                        let response = entry.respond(cacheable);
+                        cache.count(CacheStatus::Revalidated);
+                        guard.set_cache(CacheStatus::Revalidated);
+                        guard.set_status(response.status());
+                        guard
+                            .response_stats
+                            .add(response.body().size_hint().exact().unwrap_or_default());
+                        return response;
+                    }
+                    cache.count(CacheStatus::Miss);
+                    guard.set_cache(CacheStatus::Miss);
+                }
+                let switched = response.status() == StatusCode::SWITCHING_PROTOCOLS
+                    || (is_connect && response.status().is_success());
+                if let (true, Some(client_upgrade)) = (switched, client_upgrade) {
+                    let worker_upgrade = upgrade::on(&mut response);
+                    tokio::spawn(splice_upgraded(
+                        self.client_addr,
+                        client_upgrade,
+                        worker_upgrade,
+                        guard,
+                    ));
+
+                    let (mut parts, _) = response.into_parts();
+                    if let Some(rewrite) = rewrite {
+                        rewrite.response(&mut parts.headers);
+                    }
+                    if let Some(protocol) = &upgrade {
+                        keep_upgrade(&mut parts.headers, protocol);
+                    }
+                    return Response::from_parts(parts, full(Bytes::new()));
+                }
+
+                let (mut parts, body) = response.into_parts();
+                if let Some(rewrite) = rewrite {
+                    rewrite.response(&mut parts.headers);
+                }
+                let stats = guard.response_stats.clone();
+                let body = CountingBody::holding(body, stats, guard);
+                match (&self.cache, &cacheable) {
+                    (Some(cache), Some(cacheable)) if cache.should_store(cacheable, &parts) => {
+                        store_response(cache, cacheable, parts, body, grpc).await
+                    }
+                    _ => Response::from_parts(parts, body.boxed()),
+                }
+            }
+            // a request body the client broke off fails the send too, which is no fault of
+            // the worker's
+            Err(e) if guard.request_stats.failed() => {
+                event!(
+                    Level::DEBUG,
+                    "Client {} aborted the request. {e}",
+                    self.client_addr
+                );
+                guard.fail(TerminationReason::ClientAborted, &e);
+                guard.set_status(StatusCode::BAD_GATEWAY);
+                error_response(StatusCode::BAD_GATEWAY, grpc)
+            }
+            Err(e) => {
+                event!(Level::ERROR, "{e}");
+                guard.in_flight.observe(connect_start.elapsed(), false);
+                guard.fail(TerminationReason::TransferError, &e);
+                guard.set_status(StatusCode::BAD_GATEWAY);
+                error_response(StatusCode::BAD_GATEWAY, grpc)
+            }
+        }
+    }
+
+    /// Picks a worker and gets a connection to it, retrying once on another worker like the
+    /// TCP path. Failures are recorded and returned as the status to answer with. Without
+    /// `wait` a pool at capacity fails straight away instead of queueing. `connect_start` is
+    /// set as each attempt begins, so the connect latency is the last attempt's alone
+    async fn connect(
+        &self,
+        pool: &WorkerPool,
+        record: &mut AccessLogRecord,
+        wait: bool,
+        connect_start: &mut Instant,
+    ) -> std::result::Result<(SocketAddr, UpstreamSender), StatusCode> {
+        let workers = &pool.workers;
+        let mut worker = match pool.select(record, wait).await {
+            Ok(worker) => worker,
+            Err(termination) => {
+                record.termination = termination;
+                if termination == TerminationReason::NoHealthyWorkers {
+                    event!(Level::ERROR, "workers all unhealthy in pool {}", pool.name);
+                    self.ctx.health_check(pool);
+                } else {
+                    event!(Level::WARN, "Workers in pool {} at capacity", pool.name);
+                }
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+
+        *connect_start = Instant::now();
+        match self.open(pool, *worker).await {
+            Ok(sender) => return Ok((*worker, sender)),
+            Err(e) => {
+                event!(Level::WARN, "{e}");
+                pool.record_outcome(*worker, false);
+                workers.write().await.decrease_worker_count(*worker);
+                self.ctx.health_check(pool);
+
+                let Ok(next) = pool.select(record, wait).await else {
+                    record.worker = Some(*worker);
+                    record.termination = TerminationReason::UpstreamConnectFailed;
+                    record.error = Some(e.to_string());
+                    return Err(StatusCode::BAD_GATEWAY);
+                };
+                worker = next;
+            }
+        }
+
+        event!(Level::INFO, "Second attempt sent to {}", worker);
+        *connect_start = Instant::now();
+        match self.open(pool, *worker).await {
+            Ok(sender) => Ok((*worker, sender)),
+            Err(e) => {
+                event!(Level::ERROR, "Request failed. {e}");
+                workers.write().await.decrease_worker_count(*worker);
+                record.worker = Some(*worker);
+                record.termination = TerminationReason::UpstreamConnectFailed;
+                record.error = Some(e.to_string());
+                Err(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    /// Sends a mirrored request to the shadow pool once its body has been read, and compares
+    /// the answer with the primary worker's. The response is read and discarded, and nothing
+    /// is written to the access log
+    async fn mirror(self: Arc<Self>, mut job: MirrorJob) {
+        let Some(request) = job.request().await else {
+            return;
+        };
+        let pool = job.mirror.pool().clone();
+        let started = Instant::now();
+        let mut record = AccessLogRecord::new(
+            self.config.address,
+            self.client_addr,
+            TerminationReason::Completed,
+        );
+        // a copy never queues for a shadow worker, which could outlast its timeout
+        let Ok((worker, sender)) = self
+            .connect(&pool, &mut record, false, &mut Instant::now())
+            .await
+        else {
+            job.finish(None).await;
+            return;
+        };
+
+        let exchange = async {
+            let response = self
+                .forward(&pool, worker, sender, request, Arc::default(), None)
+                .await?;
+            let outcome = (response.status(), started.elapsed());
+            response
+                .into_body()
+                .collect()
+                .await
+                .map_err(|source| LoadBalancerError::UpstreamHttp { worker, source })?;
+            Ok::<_, LoadBalancerError>(outcome)
+        };
+        let shadow = match tokio::time::timeout(job.mirror.timeout(), exchange).await {
+            Ok(Ok(outcome)) => Some(outcome),
+            Ok(Err(e)) => {
+                event!(Level::DEBUG, "Mirrored request failed. {e}");
+                None
+            }
+            Err(_) => {
+                event!(Level::DEBUG, "Mirrored request to {worker} timed out");
+                None
+            }
+        };
+        pool.record_outcome(worker, shadow.is_some());
+        pool.workers.write().await.decrease_worker_count(worker);
+        job.finish(shadow).await;
+    }
+
+    /// Reuses an idle connection to the worker when the pool has one
+    async fn open(&self, pool: &WorkerPool, worker: SocketAddr) -> Result<UpstreamSender> {
+        match pool.keep_alive.checkout(worker) {
+            Some(sender) => Ok(sender),
+            None => self.handshake(pool, worker).await,
+        }
+    }
+
+    /// Opens a new connection to the worker in the pool's HTTP version
+    async fn handshake(&self, pool: &WorkerPool, worker: SocketAddr) -> Result<UpstreamSender> {
+        let upstream_error = |source| LoadBalancerError::UpstreamHttp { worker, source };
+        let client = Some(ProxiedConnection {
+            source: self.client_addr,
+            destination: self.local_addr,
+        });
+        let io = TokioIo::new(pool.connect(worker, client).await?);
+
+        if pool.http2 {
+            let (sender, connection) = client_http2::handshake(TokioExecutor::new(), io)
+                .await
+                .map_err(upstream_error)?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    event!(
+                        Level::DEBUG,
+                        "Worker {worker} connection closed. Error: {e}"
+                    );
+                }
+            });
+            return Ok(UpstreamSender::Http2(sender));
+        }
+
+        let (sender, connection) = client_http1::handshake(io).await.map_err(upstream_error)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.with_upgrades().await {
+                event!(
+                    Level::DEBUG,
+                    "Worker {worker} connection closed. Error: {e}"
+                );
+            }
+        });
+        Ok(UpstreamSender::Http1(sender))
+    }
+
+    /// Sends the request to the worker in the pool's HTTP version, whichever the client spoke. When
+    /// a reused connection turns out to be closed the request is sent again on a new one, and
+    /// the connection goes back to the pool once the response is done with it. An upgrading
+    /// request keeps its upgrade headers and its connection
+    async fn forward<B>(
+        &self,
+        pool: &WorkerPool,
+        worker: SocketAddr,
+        mut sender: UpstreamSender,
+        request: Request<B>,
+        request_stats: Arc<BodyStats>,
+        upgrade: Option<&HeaderValue>,
+    ) -> Result<Response<Incoming>>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + Unpin + 'static,
+    {
+        let upstream_error = |source| LoadBalancerError::UpstreamHttp { worker, source };
+
+        let (mut parts, body) = request.into_parts();
+        // gRPC needs `te: trailers`, the one TE value HTTP/2 allows
+        let trailers = parts
+            .headers
+            .get(TE)
+            .is_some_and(|te| te.as_bytes().eq_ignore_ascii_case(b"trailers"));
+        strip_hop_by_hop(&mut parts.headers);
+        if let Some(protocol) = upgrade {
+            keep_upgrade(&mut parts.headers, protocol);
+        }
+        let upgrading = upgrade.is_some() || parts.method == Method::CONNECT;
+
+        if pool.http2 {
+            parts.version = Version::HTTP_2;
+            if trailers {
+                parts
+                    .headers
+                    .insert(TE, HeaderValue::from_static("trailers"));
+            }
+            if parts.method != Method::CONNECT {
+                parts.uri = absolute_uri(&parts, pool, worker);
+            }
+        } else {
+            parts.version = Version::HTTP_11;
+            // workers expect origin-form, even when the client sent an absolute URI. CONNECT
+            // keeps its authority-form target
+            if parts.method != Method::CONNECT {
+                parts.uri = parts
+                    .uri
+                    .path_and_query()
+                    .cloned()
+                    .map(Uri::from)
+                    .unwrap_or_else(|| Uri::from_static("/"));
+            }
+        }
+        let request = Request::from_parts(parts, CountingBody::new(body, request_stats).boxed());
+
+        let mut response = match sender.send(request).await {
+            Ok(response) => response,
+            Err(mut e) => match e.take_message() {
+                Some(request) => {
+                    event!(
+                        Level::DEBUG,
+                        "Idle connection to {worker} was closed, reconnecting"
+                    );
+                    sender = self.handshake(pool, worker).await?;
+                    sender
+                        .send(request)
+                        .await
+                        .map_err(|e| upstream_error(e.into_error()))?
+                }
+                None => return Err(upstream_error(e.into_error())),
+            },
+        };
+        if !upgrading {
+            pool.keep_alive.checkin(worker, sender);
+        }
+
+        strip_hop_by_hop(response.headers_mut());
+        Ok(response)
+    }
+}
+
+/// HTTP/2 carries the scheme and authority as pseudo-headers, taken from the request's Host
+/// or, without one, the worker's address
+fn absolute_uri(parts: &hyper::http::request::Parts, pool: &WorkerPool, worker: SocketAddr) -> Uri {
+    let scheme = if pool.uses_tls() { "https" } else { "http" };
+    let authority = parts
+        .headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map_or_else(|| worker.to_string(), String::from);
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query(path)
+        .build()
+        .unwrap_or_else(|_| parts.uri.clone())
+}
+
+/// Reads the whole response so it can be stored, then sends it on. A body that fails
+/// midway is answered with 502 and logged as a transfer error by its stats
+async fn store_response(
+    cache: &HttpCache,
+    cacheable: &CacheableRequest,
+    parts: hyper::http::response::Parts,
+    body: CountingBody<Incoming, RequestGuard>,
+    grpc: bool,
+) -> Response<ProxyBody> {
+    match body.collect().await {
+        Ok(collected) => {
+            let body = collected.to_bytes();
+            cache.store(cacheable, &parts, body.clone());
+            Response::from_parts(parts, full(body))
+        }
+        Err(e) => {
+            event!(Level::WARN, "Response to cache failed midway. {e}");
+            error_response(StatusCode::BAD_GATEWAY, grpc)
+        }
+    }
+}
+
+/// Splices the client and worker once both have switched protocols. The guard holds the
+/// worker's load count for as long as the tunnel is open
+async fn splice_upgraded(
+    client_addr: SocketAddr,
+    client: OnUpgrade,
+    worker: OnUpgrade,
+    mut guard: RequestGuard,
+) {
+    let worker_addr = guard.worker;
+    let upgraded = async {
+        let worker = worker
+            .await
+            .map_err(|source| LoadBalancerError::UpstreamHttp {
+                worker: worker_addr,
+                source,
+            })?;
+        let client = client
+            .await
+            .map_err(|source| LoadBalancerError::HttpConnection {
+                client: client_addr,
+                source: Box::new(source),
+            })?;
+        Ok::<_, LoadBalancerError>((TokioIo::new(client), TokioIo::new(worker)))
+    };
+    let (mut client, mut worker) = match upgraded.await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            event!(Level::ERROR, "Upgrade failed. {e}");
+            let termination = match e {
+                LoadBalancerError::HttpConnection { .. } => TerminationReason::ClientAborted,
+                _ => TerminationReason::TransferError,
+            };
+            guard.fail(termination, &e);
+            return;
+        }
+    };
+
+    match splice(&mut client, &mut worker).await {
+        Ok((bytes_in, bytes_out)) => {
+            guard.request_stats.add(bytes_in);
+            guard.response_stats.add(bytes_out);
+        }
+        Err((Side::Client, source)) => {
+            let e = LoadBalancerError::ClientTransfer {
+                client: client_addr,
+                source,
+            };
+            event!(Level::DEBUG, "{e}");
+            guard.fail(TerminationReason::ClientAborted, &e);
+        }
+        Err((Side::Worker, e)) => {
+            let e = LoadBalancerError::worker(worker_addr, WorkerPhase::Transfer)(e);
+            event!(Level::ERROR, "{e}");
+            guard.fail(TerminationReason::TransferError, &e);
+        }
+    }
+}
+
+fn error_response(status: StatusCode, grpc: bool) -> Response<ProxyBody> {
+    if grpc {
+        return grpc::error_response(status);
+    }
+
+    let mut response = Response::new(full(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Owns a request's record and its slot in the worker's load count until the response
+/// body is dropped, then logs the request and releases the worker
+struct RequestGuard {
+    record: Option<AccessLogRecord>,
+    started: Instant,
+    request_stats: Arc<BodyStats>,
+    response_stats: Arc<BodyStats>,
+    pool: WorkerPool,
+    worker: SocketAddr,
+    ctx: ProxyContext,
+    /// Counts against the pool's adaptive concurrency limit
+    in_flight: InFlight,
+    _permit: ConnectionPermit,
+}
+
+impl RequestGuard {
+    fn set_status(&mut self, status: StatusCode) {
+        if let Some(record) = &mut self.record {
+            record.status = Some(status.as_u16());
+        }
+    }
+
+    fn set_response_latency(&mut self, latency: Duration) {
+        if let Some(record) = &mut self.record {
+            record.response_latency_ms = Some(duration_ms(latency));
+        }
+    }
+
+    fn set_cache(&mut self, status: CacheStatus) {
+        if let Some(record) = &mut self.record {
+            record.cache = Some(status);
+        }
+    }
+
+    fn set_grpc_status(&mut self, status: Option<u16>) {
+        if let Some(record) = &mut self.record {
+            record.grpc_status = status;
+        }
+    }
+
+    fn fail(&mut self, termination: TerminationReason, e: &LoadBalancerError) {
+        if let Some(record) = &mut self.record {
+            record.termination = termination;
+            record.error = Some(e.to_string());
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.bytes_in = self.request_stats.bytes();
+            record.bytes_out = self.response_stats.bytes();
+            // the worker's body failing is its fault, a client dropping the body is not
+            if record.termination == TerminationReason::Completed {
+                if self.response_stats.failed() {
+                    record.termination = TerminationReason::TransferError;
+                } else if self.response_stats.abandoned() {
+                    record.termination = TerminationReason::ClientAborted;
+                }
+            }
+            record.duration_ms = duration_ms(self.started.elapsed());
+            record.grpc_status = record.grpc_status.or(self.response_stats.grpc_status());
+
+            // a worker answering UNAVAILABLE is checked like one that refused a connection
+            if record.grpc_status == Some(GRPC_UNAVAILABLE) {
+                self.ctx.health_check(&self.pool);
+            }
+            self.ctx.report(record);
+        }
+
+        let workers = self.pool.workers.clone();
+        let worker = self.worker;
+        tokio::spawn(async move {
+            workers.write().await.decrease_worker_count(worker);
+        });
+    }
+}
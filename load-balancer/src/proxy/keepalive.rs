@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use hyper::{
+    body::Incoming,
+    client::conn::{http1, http2, TrySendError},
+    Request, Response,
+};
+
+use crate::utils::config::KeepAliveConfig;
+
+use super::body::ProxyBody;
+
+/// Sends requests over one connection to a worker
+#[derive(Debug)]
+pub(crate) enum UpstreamSender {
+    Http1(http1::SendRequest<ProxyBody>),
+    /// Shared by every request to the worker, each one is a stream on the connection
+    Http2(http2::SendRequest<ProxyBody>),
+}
+
+impl UpstreamSender {
+    /// Hands the request back in the error when it never made it onto the connection
+    pub async fn send(
+        &mut self,
+        request: Request<ProxyBody>,
+    ) -> std::result::Result<Response<Incoming>, TrySendError<Request<ProxyBody>>> {
+        match self {
+            Self::Http1(sender) => sender.try_send_request(request).await,
+            Self::Http2(sender) => sender.try_send_request(request).await,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        match self {
+            Self::Http1(sender) => sender.is_ready(),
+            Self::Http2(sender) => sender.is_ready(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Http1(sender) => sender.is_closed(),
+            Self::Http2(sender) => sender.is_closed(),
+        }
+    }
+}
+
+/// Idle keep-alive connections to a pool's workers. HTTP/1.1 connections are lent out one
+/// request at a time, most recently used first, while an HTTP/2 connection is shared
+#[derive(Debug)]
+pub(crate) struct KeepAlivePool {
+    config: KeepAliveConfig,
+    idle: Mutex<HashMap<SocketAddr, Vec<IdleConnection>>>,
+}
+
+#[derive(Debug)]
+struct IdleConnection {
+    sender: UpstreamSender,
+    since: Instant,
+}
+
+impl KeepAlivePool {
+    pub fn new(config: KeepAliveConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::default(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(KeepAliveConfig {
+            max_idle: 0,
+            max_idle_per_worker: 0,
+            ..KeepAliveConfig::default()
+        })
+    }
+
+    /// An idle connection to the worker that is still open and ready for a request
+    pub fn checkout(&self, worker: SocketAddr) -> Option<UpstreamSender> {
+        let mut idle = self.idle.lock().ok()?;
+        let connections = idle.get_mut(&worker)?;
+
+        while let Some(connection) = connections.pop() {
+            if connection.since.elapsed() >= self.config.idle_timeout()
+                || !connection.sender.is_ready()
+            {
+                continue;
+            }
+            if let UpstreamSender::Http2(sender) = &connection.sender {
+                let shared = UpstreamSender::Http2(sender.clone());
+                connections.push(IdleConnection {
+                    sender: connection.sender,
+                    since: Instant::now(),
+                });
+                return Some(shared);
+            }
+            return Some(connection.sender);
+        }
+        None
+    }
+
+    /// Keeps the connection for the next request if it is still open and the limits allow,
+    /// once an HTTP/1.1 connection has finished its current response
+    pub fn checkin(self: &Arc<Self>, worker: SocketAddr, sender: UpstreamSender) {
+        if self.config.max_idle_per_worker == 0 || self.config.max_idle == 0 {
+            return;
+        }
+
+        match sender {
+            UpstreamSender::Http1(mut sender) => {
+                let pool = self.clone();
+                tokio::spawn(async move {
+                    if sender.ready().await.is_ok() {
+                        pool.insert(worker, UpstreamSender::Http1(sender));
+                    }
+                });
+            }
+            UpstreamSender::Http2(_) => self.insert(worker, sender),
+        }
+    }
+
+    fn insert(&self, worker: SocketAddr, sender: UpstreamSender) {
+        let Ok(mut idle) = self.idle.lock() else {
+            return;
+        };
+        self.retain_usable(&mut idle);
+
+        let total: usize = idle.values().map(Vec::len).sum();
+        let connections = idle.entry(worker).or_default();
+        // a worker's HTTP/2 connection is already shared from the pool
+        let shared = matches!(sender, UpstreamSender::Http2(_))
+            && connections
+                .iter()
+                .any(|c| matches!(c.sender, UpstreamSender::Http2(_)));
+        if !shared
+            && total < self.config.max_idle
+            && connections.len() < self.config.max_idle_per_worker
+        {
+            connections.push(IdleConnection {
+                sender,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes every idle connection to the worker
+    pub fn evict(&self, worker: SocketAddr) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.remove(&worker);
+        }
+    }
+
+    /// Closes idle connections past the idle timeout or already closed by their worker
+    pub fn evict_expired(&self) {
+        if let Ok(mut idle) = self.idle.lock() {
+            self.retain_usable(&mut idle);
+        }
+    }
+
+    fn retain_usable(&self, idle: &mut HashMap<SocketAddr, Vec<IdleConnection>>) {
+        let timeout = self.config.idle_timeout();
+        for connections in idle.values_mut() {
+            connections.retain(|c| c.since.elapsed() < timeout && !c.sender.is_closed());
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use hyper::{server::conn, service::service_fn};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::{io::duplex, time::sleep};
+
+    use super::{super::body::full, *};
+
+    const A: &str = "127.0.0.1:9001";
+    const B: &str = "127.0.0.1:9002";
+
+    fn keep_alive(max_idle: usize, max_idle_per_worker: usize) -> Arc<KeepAlivePool> {
+        Arc::new(KeepAlivePool::new(KeepAliveConfig {
+            max_idle,
+            max_idle_per_worker,
+            ..KeepAliveConfig::default()
+        }))
+    }
+
+    fn worker(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// A connection to a worker answering every request with 200, served over memory
+    async fn connect(http2: bool) -> UpstreamSender {
+        let (client, server) = duplex(64 * 1024);
+        let service = service_fn(|_| async { Ok::<_, Infallible>(Response::new(full("ok"))) });
+        if http2 {
+            tokio::spawn(
+                conn::http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(server), service),
+            );
+            let (sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(client))
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            UpstreamSender::Http2(sender)
+        } else {
+            tokio::spawn(
+                conn::http1::Builder::new().serve_connection(TokioIo::new(server), service),
+            );
+            let (sender, connection) = http1::handshake(TokioIo::new(client)).await.unwrap();
+            tokio::spawn(connection);
+            UpstreamSender::Http1(sender)
+        }
+    }
+
+    /// Checks the connection in and waits for it to become ready and idle
+    async fn checkin(pool: &Arc<KeepAlivePool>, addr: &str, sender: UpstreamSender) {
+        pool.checkin(worker(addr), sender);
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    fn idle(pool: &KeepAlivePool, addr: &str) -> usize {
+        let idle = pool.idle.lock().unwrap();
+        idle.get(&worker(addr)).map_or(0, Vec::len)
+    }
+
+    #[tokio::test]
+    async fn lends_an_http1_connection_to_one_request_at_a_time() {
+        let pool = keep_alive(8, 8);
+        assert!(pool.checkout(worker(A)).is_none());
+
+        checkin(&pool, A, connect(false).await).await;
+        assert_eq!(idle(&pool, A), 1);
+
+        let mut sender = pool.checkout(worker(A)).unwrap();
+        assert!(matches!(sender, UpstreamSender::Http1(_)));
+        assert!(pool.checkout(worker(A)).is_none());
+        assert!(pool.checkout(worker(B)).is_none());
+
+        let request = Request::new(full(""));
+        let response = sender.send(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        checkin(&pool, A, sender).await;
+        assert_eq!(idle(&pool, A), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_max_idle_per_worker() {
+        let pool = keep_alive(8, 2);
+        for _ in 0..3 {
+            checkin(&pool, A, connect(false).await).await;
+        }
+        checkin(&pool, B, connect(false).await).await;
+
+        assert_eq!(idle(&pool, A), 2);
+        assert_eq!(idle(&pool, B), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_max_idle_across_workers() {
+        let pool = keep_alive(3, 2);
+        for addr in [A, A, B, B] {
+            checkin(&pool, addr, connect(false).await).await;
+        }
+
+        assert_eq!(idle(&pool, A), 2);
+        assert_eq!(idle(&pool, B), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_nothing_when_disabled() {
+        let pool = Arc::new(KeepAlivePool::disabled());
+        checkin(&pool, A, connect(false).await).await;
+        checkin(&pool, A, connect(true).await).await;
+        assert_eq!(idle(&pool, A), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_connections_past_the_idle_timeout() {
+        let pool = keep_alive(8, 8);
+        checkin(&pool, A, connect(false).await).await;
+        checkin(&pool, A, connect(false).await).await;
+        checkin(&pool, B, connect(false).await).await;
+
+        let expired = Instant::now() - pool.config.idle_timeout() - Duration::from_secs(1);
+        for connection in pool.idle.lock().unwrap().get_mut(&worker(A)).unwrap() {
+            connection.since = expired;
+        }
+        assert!(pool.checkout(worker(A)).is_none());
+        assert_eq!(idle(&pool, A), 0);
+
+        pool.idle.lock().unwrap().get_mut(&worker(B)).unwrap()[0].since = expired;
+        pool.evict_expired();
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_connections_closed_by_their_worker() {
+        let pool = keep_alive(8, 8);
+        let (client, server) = duplex(64 * 1024);
+        let (sender, connection) = http1::handshake(TokioIo::new(client)).await.unwrap();
+        tokio::spawn(connection);
+        checkin(&pool, A, UpstreamSender::Http1(sender)).await;
+        assert_eq!(idle(&pool, A), 1);
+
+        drop(server);
+        sleep(Duration::from_millis(20)).await;
+        pool.evict_expired();
+        assert_eq!(idle(&pool, A), 0);
+    }
+
+    #[tokio::test]
+    async fn shares_one_http2_connection_per_worker() {
+        let pool = keep_alive(8, 8);
+        checkin(&pool, A, connect(true).await).await;
+        assert_eq!(idle(&pool, A), 1);
+
+        // every checkout shares the connection, which stays in the pool
+        let mut first = pool.checkout(worker(A)).unwrap();
+        let second = pool.checkout(worker(A)).unwrap();
+        assert!(matches!(first, UpstreamSender::Http2(_)));
+        assert!(matches!(second, UpstreamSender::Http2(_)));
+        assert_eq!(idle(&pool, A), 1);
+
+        let response = first.send(Request::new(full(""))).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        // handing the shared copies back does not add them again
+        checkin(&pool, A, first).await;
+        checkin(&pool, A, second).await;
+        assert_eq!(idle(&pool, A), 1);
+
+        // a second HTTP/2 connection to the worker is not kept either
+        checkin(&pool, A, connect(true).await).await;
+        assert_eq!(idle(&pool, A), 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_a_workers_connections() {
+        let pool = keep_alive(8, 8);
+        checkin(&pool, A, connect(false).await).await;
+        checkin(&pool, B, connect(true).await).await;
+
+        pool.evict(worker(A));
+        assert_eq!(idle(&pool, A), 0);
+        assert_eq!(idle(&pool, B), 1);
+    }
+}
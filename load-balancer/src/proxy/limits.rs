@@ -0,0 +1,258 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::utils::{
+    config::{LimitsConfig, QueueConfig},
+    metrics::{MetricSource, MetricsWriter},
+};
+
+/// A bounded number of connections waiting for capacity, and how many were turned away
+#[derive(Debug)]
+pub(crate) struct WaitQueue {
+    size: usize,
+    timeout: Duration,
+    waiting: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// A place in a [`WaitQueue`], given up when dropped
+pub(crate) struct QueueSlot<'a>(&'a WaitQueue);
+
+impl WaitQueue {
+    pub fn new(config: &QueueConfig) -> Self {
+        Self {
+            size: config.size,
+            timeout: config.timeout(),
+            waiting: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Joins the queue unless it is full
+    pub fn enter(&self) -> Option<QueueSlot<'_>> {
+        let entered = self
+            .waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < self.size).then_some(waiting + 1)
+            })
+            .is_ok();
+        if !entered {
+            self.reject();
+            return None;
+        }
+        Some(QueueSlot(self))
+    }
+
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The balancer wide cap on connections, HTTP requests and UDP flows in progress
+#[derive(Debug)]
+pub(crate) struct ConnectionLimit {
+    max: Option<usize>,
+    total: usize,
+    permits: Arc<Semaphore>,
+    queue: WaitQueue,
+}
+
+/// Counts against the [`ConnectionLimit`] until dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectionLimit {
+    pub fn new(config: &LimitsConfig) -> Self {
+        let total = config
+            .max_connections
+            .unwrap_or(Semaphore::MAX_PERMITS)
+            .min(Semaphore::MAX_PERMITS);
+        Self {
+            max: config.max_connections,
+            total,
+            permits: Arc::new(Semaphore::new(total)),
+            queue: WaitQueue::new(&config.queue),
+        }
+    }
+
+    /// A permit for one more connection. At the limit, waits in the queue when `wait` is set
+    /// and otherwise gives up straight away
+    pub async fn acquire(&self, wait: bool) -> Option<ConnectionPermit> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(ConnectionPermit { _permit: permit });
+        }
+        if !wait {
+            self.queue.reject();
+            return None;
+        }
+
+        let _slot = self.queue.enter()?;
+        match timeout(self.queue.timeout(), self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Some(ConnectionPermit { _permit: permit }),
+            _ => {
+                self.queue.reject();
+                None
+            }
+        }
+    }
+}
+
+impl MetricSource for ConnectionLimit {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let available = self.permits.available_permits();
+        metrics.gauge(
+            "lb_connections_active",
+            "TCP connections, HTTP requests and UDP flows in progress",
+            &[],
+            self.total.saturating_sub(available) as f64,
+        );
+        if let Some(max) = self.max {
+            metrics.gauge(
+                "lb_connections_max",
+                "Connections allowed at once across the balancer",
+                &[],
+                max as f64,
+            );
+        }
+        metrics.gauge(
+            "lb_connections_queued",
+            "Connections waiting for the balancer wide limit",
+            &[],
+            self.queue.waiting() as f64,
+        );
+        metrics.counter(
+            "lb_connections_rejected_total",
+            "Connections turned away at the balancer wide limit",
+            &[],
+            self.queue.rejected(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn limit(max_connections: usize, size: usize, timeout_ms: u64) -> Arc<ConnectionLimit> {
+        Arc::new(ConnectionLimit::new(&LimitsConfig {
+            max_connections: Some(max_connections),
+            queue: QueueConfig { size, timeout_ms },
+        }))
+    }
+
+    /// Acquires on another task, once it is waiting in the queue
+    async fn queued(limit: &Arc<ConnectionLimit>) -> tokio::task::JoinHandle<bool> {
+        let waiting = limit.clone();
+        let handle = tokio::spawn(async move { waiting.acquire(true).await.is_some() });
+        while limit.queue.waiting() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        handle
+    }
+
+    #[tokio::test]
+    async fn caps_connections_across_the_balancer() {
+        let limit = limit(2, 10, 1000);
+        let first = limit.acquire(false).await.unwrap();
+        let _second = limit.acquire(false).await.unwrap();
+        assert!(limit.acquire(false).await.is_none());
+        assert_eq!(limit.queue.rejected(), 1);
+
+        drop(first);
+        assert!(limit.acquire(false).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn unlimited_without_a_maximum() {
+        let limit = ConnectionLimit::new(&LimitsConfig::default());
+        let mut permits = Vec::new();
+        for _ in 0..1000 {
+            permits.push(limit.acquire(false).await.unwrap());
+        }
+        assert_eq!(limit.queue.rejected(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_connection_gets_a_released_permit() {
+        let limit = limit(1, 10, 5000);
+        let permit = limit.acquire(true).await.unwrap();
+
+        let handle = queued(&limit).await;
+        drop(permit);
+        let acquired = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert!(acquired.unwrap().unwrap());
+        assert_eq!(limit.queue.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_once_the_queue_is_full() {
+        let limit = limit(1, 1, 5000);
+        let _permit = limit.acquire(true).await.unwrap();
+
+        let handle = queued(&limit).await;
+        assert!(limit.acquire(true).await.is_none());
+        assert_eq!(limit.queue.rejected(), 1);
+        assert_eq!(limit.queue.waiting(), 1);
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_queue_timeout() {
+        let limit = limit(1, 10, 50);
+        let _permit = limit.acquire(true).await.unwrap();
+
+        let started = Instant::now();
+        assert!(limit.acquire(true).await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(limit.queue.rejected(), 1);
+        assert_eq!(limit.queue.waiting(), 0);
+    }
+
+    #[test]
+    fn queue_slots_are_given_back() {
+        let queue = WaitQueue::new(&QueueConfig {
+            size: 2,
+            timeout_ms: 1000,
+        });
+        let first = queue.enter().unwrap();
+        let _second = queue.enter().unwrap();
+        assert!(queue.enter().is_none());
+        assert_eq!((queue.waiting(), queue.rejected()), (2, 1));
+
+        drop(first);
+        let _third = queue.enter().unwrap();
+        assert_eq!(queue.waiting(), 2);
+    }
+}
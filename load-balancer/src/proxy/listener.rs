@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::sleep,
+};
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::config::ListenerConfig,
+};
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Back off before accepting again when the listener errors, e.g. when out of file descriptors
+pub(crate) const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+pub(crate) fn bind_tcp(config: &ListenerConfig) -> Result<TcpListener> {
+    let addr = config.address;
+    let bind_error = |source| LoadBalancerError::Bind { addr, source };
+
+    let socket = bind(config, Type::STREAM, Protocol::TCP)?;
+    socket.listen(LISTEN_BACKLOG).map_err(bind_error)?;
+
+    TcpListener::from_std(socket.into()).map_err(bind_error)
+}
+
+pub(crate) fn bind_udp(config: &ListenerConfig) -> Result<UdpSocket> {
+    let addr = config.address;
+    let socket = bind(config, Type::DGRAM, Protocol::UDP)?;
+
+    UdpSocket::from_std(socket.into()).map_err(|source| LoadBalancerError::Bind { addr, source })
+}
+
+/// Binds through socket2 so IPv6 listeners can choose between v6 only and dual-stack
+fn bind(config: &ListenerConfig, socket_type: Type, protocol: Protocol) -> Result<Socket> {
+    let addr = config.address;
+    let bind_error = |source| LoadBalancerError::Bind { addr, source };
+
+    let socket =
+        Socket::new(Domain::for_address(addr), socket_type, Some(protocol)).map_err(bind_error)?;
+    socket.set_reuse_address(true).map_err(bind_error)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!config.dual_stack).map_err(bind_error)?;
+    }
+    socket.set_nonblocking(true).map_err(bind_error)?;
+    socket.bind(&addr.into()).map_err(bind_error)?;
+
+    Ok(socket)
+}
+
+/// Waits for the next connection, logging accept errors instead of returning them
+pub(crate) async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => {
+                event!(Level::TRACE, "incoming request");
+                return accepted;
+            }
+            Err(e) => {
+                event!(Level::ERROR, "{}", LoadBalancerError::Accept(e));
+                sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
@@ -1,217 +1,529 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tracing::{event, Level};
 
-use tokio::{
-    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
-    time::sleep,
-};
+use tokio::{net::TcpListener, task::JoinSet, time::sleep};
 
-use futures::FutureExt;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    services::postgres_store::PostgresWorkerStore, utils::stream_reader::read_status_code,
+    error::{LoadBalancerError, Result},
+    services::{history::HistoryWriter, postgres_store::PostgresWorkerStore},
+    utils::{
+        access_log::{AccessLog, AccessLogRecord},
+        config::{AdminConfig, HealthCheckKind, LimitsConfig, ListenerConfig, PoolConfig},
+        metrics::Metrics,
+    },
 };
 
-use super::workers::Workers;
+use super::{
+    acl::{load_acl, reload_acl, Acl},
+    admin::serve_admin,
+    cache::HttpCache,
+    health::check_workers_health,
+    http::{serve_http, HttpListener, HTTP_ALPN_PROTOCOLS},
+    limits::{ConnectionLimit, ConnectionPermit},
+    listener::{bind_tcp, bind_udp},
+    mirror::Mirror,
+    pool::WorkerPool,
+    rate_limit::RateLimiter,
+    router::HttpRouter,
+    sni::SniRouter,
+    split::{load_split, reload_split, TrafficSplit, TrafficSplits},
+    tcp::{serve_tcp, ListenerMode},
+    tls::tls_acceptor,
+    udp::serve_udp,
+};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum LoadBalancerAlgorithm {
     RoundRobin,
     Random,
     LeastConnections,
 }
 
+/// State shared by every listener task
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyContext {
+    pools: Arc<HashMap<String, WorkerPool>>,
+    access_log: AccessLog,
+    history: Option<HistoryWriter>,
+    metrics: Metrics,
+    connections: Arc<ConnectionLimit>,
+    splits: TrafficSplits,
+}
+
+impl ProxyContext {
+    pub fn pool(&self, name: &str) -> Option<&WorkerPool> {
+        self.pools.get(name)
+    }
+
+    pub fn report(&self, record: AccessLogRecord) {
+        let pool = record.pool.as_deref().and_then(|pool| self.pool(pool));
+        if let (Some(pool), Some(worker)) = (pool, record.worker) {
+            pool.record_outcome(worker, !record.is_worker_error());
+        }
+        if let Some(history) = &self.history {
+            history.record_connection(&record);
+        }
+        self.access_log.log(record);
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn splits(&self) -> &TrafficSplits {
+        &self.splits
+    }
+
+    /// Counts a connection against the balancer wide limit, `None` when it is turned away
+    pub async fn admit(&self, wait: bool) -> Option<ConnectionPermit> {
+        self.connections.acquire(wait).await
+    }
+
+    /// Out of band check for a pool, run when a worker refuses a connection. It runs on its
+    /// own task so the failing connection is answered straight away, and is skipped while
+    /// another check of the pool is still running
+    pub fn health_check(&self, pool: &WorkerPool) {
+        let Some(sweep) = pool.start_sweep() else {
+            return;
+        };
+        let pool = pool.clone();
+        let history = self.history.clone();
+        tokio::spawn(async move {
+            let worker_health_map = check_workers_health(&pool, history.as_ref()).await;
+            event!(
+                Level::INFO,
+                "Worker Health (pool {}) {:?}",
+                pool.name,
+                worker_health_map
+            );
+            drop(sweep);
+        });
+
+        // TODO Potentially useful after dynamic worker Socket adders
+        // let healthy_workers: Vec<Arc<SocketAddr>> = worker_health_map
+        //     .iter()
+        //     .filter(|(_, healthy)| **healthy == true)
+        //     .map(|(addr, _)| addr.clone())
+        //     .collect();
+    }
+}
+
+#[cfg(test)]
+impl ProxyContext {
+    /// A context over `pools` alone, without history or a balancer wide limit
+    pub(crate) fn for_pools(pools: Vec<WorkerPool>) -> Self {
+        let pools = pools
+            .into_iter()
+            .map(|pool| (pool.name.clone(), pool))
+            .collect();
+        Self {
+            pools: Arc::new(pools),
+            access_log: AccessLog::discard(),
+            history: None,
+            metrics: Metrics::default(),
+            connections: Arc::new(ConnectionLimit::new(&LimitsConfig::default())),
+            splits: TrafficSplits::default(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LoadBalancer {
-    workers: Arc<RwLock<Workers>>,
-    health_check_interval: Duration,
-    _db_connection: PostgresWorkerStore,
+    ctx: ProxyContext,
+    db_connection: Option<PostgresWorkerStore>,
 }
 
 impl LoadBalancer {
-    pub fn new(raw_workers: Vec<String>, _db_connection: PostgresWorkerStore) -> Self {
-        let workers = Workers::new(raw_workers);
+    /// Without a worker store the balancer still proxies, it just does not persist history.
+    /// A pool whose settings cannot be applied is logged and left without workers
+    pub fn new(
+        pools: HashMap<String, Vec<SocketAddr>>,
+        pool_configs: &HashMap<String, PoolConfig>,
+        db_connection: Option<PostgresWorkerStore>,
+        access_log: AccessLog,
+        limits: &LimitsConfig,
+    ) -> Self {
+        let default_config = PoolConfig::default();
+        let pools: HashMap<String, WorkerPool> = pools
+            .into_iter()
+            .map(|(name, worker_addresses)| {
+                let config = pool_configs.get(&name).unwrap_or(&default_config);
+                let pool =
+                    WorkerPool::new(name.clone(), worker_addresses, config).unwrap_or_else(|e| {
+                        event!(Level::ERROR, "Disabling pool {name}. {e}");
+                        WorkerPool::disabled(name.clone())
+                    });
+                (name, pool)
+            })
+            .collect();
+        let history = db_connection.clone().map(HistoryWriter::new);
+
+        let metrics = Metrics::default();
+        let connections = Arc::new(ConnectionLimit::new(limits));
+        metrics.register(connections.clone());
+        for pool in pools.values() {
+            metrics.register(Arc::new(pool.clone()));
+        }
 
         Self {
-            workers: Arc::new(RwLock::new(workers)),
-            health_check_interval: Duration::from_secs(60),
-            _db_connection,
+            ctx: ProxyContext {
+                pools: Arc::new(pools),
+                access_log,
+                history,
+                metrics,
+                connections,
+                splits: TrafficSplits::default(),
+            },
+            db_connection,
         }
     }
 
-    pub async fn run(&mut self, listener: TcpListener) -> std::io::Result<()> {
-        let workers = self.workers.clone();
-        let duration = self.health_check_interval.clone();
+    /// Binds every listener and serves them until all have stopped. Listeners that fail to
+    /// bind are logged and skipped, it is only an error when none of them could start
+    pub async fn run(
+        &mut self,
+        listeners: Vec<ListenerConfig>,
+        admin: Option<AdminConfig>,
+    ) -> Result<()> {
+        // Task spawned per pool checking the health of its workers on the pool's interval
+        for pool in self.ctx.pools.values() {
+            let pool = pool.clone();
+            let history = self.ctx.history.clone();
+            tokio::spawn(async move {
+                loop {
+                    check_workers_health(&pool, history.as_ref()).await;
+                    event!(Level::TRACE, "Routine health check done for pool {}", pool.name);
 
-        // Task spawned checking health of each worker
-        tokio::spawn(async move {
-            loop {
-                let mut worker_health_map = HashMap::new();
-                for worker in workers.read().await.worker_addrs.iter() {
-                    let stream = TcpStream::connect(**worker).await;
-
-                    if let Ok(mut stream) = stream {
-                        let _ = stream
-                            .write_all(
-                                format!(
-                        "GET /health_check HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-                        worker
-                    )
-                                .as_bytes(),
-                            )
-                            .await;
-
-                        let mut buf = Vec::new();
-                        let _ = stream.read_to_end(&mut buf).await;
-
-                        let response = String::from_utf8_lossy(&buf);
-
-                        let status_code = read_status_code(response.lines().next().unwrap_or(""));
-
-                        // assuming all responses under 400 are healthy
-                        worker_health_map.insert(worker.clone(), status_code < 400);
-                        if status_code >= 400 {
-                            event!(Level::WARN, "Worker Check Failed {}", worker);
-                        }
-                    } else {
-                        worker_health_map.insert(worker.clone(), false);
-                        event!(Level::ERROR, "Worker Stream Failed {}", worker);
-                    }
+                    let _ = sleep(pool.health_check.interval()).await;
                 }
-                event!(Level::TRACE, "Routine health check done");
-
-                workers
-                    .write()
-                    .await
-                    .update_healthy_workers(worker_health_map.clone());
+            });
+        }
 
-                let _ = sleep(duration).await;
+        if let Some(admin) = admin {
+            match TcpListener::bind(admin.address).await {
+                Ok(listener) => {
+                    event!(Level::INFO, "Admin listening at addr: {}", admin.address);
+                    tokio::spawn(serve_admin(listener, admin.token, self.ctx.clone()));
+                }
+                Err(source) => event!(
+                    Level::ERROR,
+                    "{}",
+                    LoadBalancerError::Bind {
+                        addr: admin.address,
+                        source
+                    }
+                ),
             }
-        });
-        
-        while let Ok((mut inbound, _)) = listener.accept().await {
-            event!(Level::TRACE, "incoming request");
-            let mut workers = self.workers.clone();
-            // NOTE this includes using all current healthy workers. meaning there are no healthy
-            // workers left
-            let try_outbound = workers.write().await.get_next().await;
-            if let Some(mut outbound_addr) = try_outbound {
-                let connection = TcpStream::connect(*outbound_addr).await;
-
-                if let Err(e) = connection {
-                    event!(
-                        Level::WARN,
-                        "Failed to connect to {outbound_addr}. Error: {e}"
-                    );
-                    self.health_check().await;
-
-                    workers = self.workers.clone();
-                    outbound_addr = if let Some(addr) = workers.write().await.get_next().await {
-                        addr
-                    } else {
-                        event!(
-                            Level::ERROR,
-                            "Connection failed healthy server not found"
-                        );
-                            continue;
-                    };
-
-                    event!(Level::INFO, "Second attempt sent to {}", outbound_addr);
+        }
+
+        let mut listener_tasks = JoinSet::new();
+        for config in listeners {
+            let address = config.address;
+            let limiter = match self.rate_limiter(&config) {
+                Ok(limiter) => limiter,
+                Err(e) => {
+                    event!(Level::ERROR, "Skipping listener {address}. {e}");
+                    continue;
                 }
+            };
+            let acl = self.acl(&config).await;
 
-                let connection = TcpStream::connect(*outbound_addr).await;
-
-                match connection {
-                    Ok(mut outbound) => {
-                        tokio::spawn(async move {
-                            // TODO handle return of of bidirectional result
-                            copy_bidirectional(&mut inbound, &mut outbound)
-                                .map(|r| {
-                                    if let Err(e) = r {
-                                        event!(Level::ERROR, "Failed to transfer. Error: {:?}", e);
-                                    }
-                                })
-                                .await;
-                            workers.write().await.decrease_worker_count(*outbound_addr);
-                            event!(
-                                Level::INFO,
-                                "Response Sent: counts: {:?}",
-                                workers.read().await.current_worker_loads
-                            )
-                        });
-                    }
-                    Err(e) => {
-                        event!(
-                            Level::ERROR,
-                            "Failed to connect to {outbound_addr}. request failed. Error: {e}"
-                        );
-                        self.health_check().await;
-                        workers.write().await.decrease_worker_count(*outbound_addr);
-                    }
+            if config.udp.is_some() {
+                if let Err(e) = self.serve_udp(&mut listener_tasks, config, limiter, acl) {
+                    event!(Level::ERROR, "Skipping listener {address}. {e}");
                 }
-            } else {
-                event!(
-                    Level::ERROR,
-                    "workers all unhealthy {:?}",
-                    workers.read().await.workers_health
-                );
-                event!(Level::TRACE, "run health check");
-                self.health_check().await;
+                continue;
+            }
+            let split = self.split(&config).await;
+
+            let listener = match bind_tcp(&config) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    event!(Level::ERROR, "{e}");
+                    continue;
+                }
+            };
+            if let Err(e) = self.serve(&mut listener_tasks, listener, config, limiter, acl, split) {
+                event!(Level::ERROR, "Skipping listener {address}. {e}");
+            }
+        }
+
+        if listener_tasks.is_empty() {
+            return Err(LoadBalancerError::NoListeners);
+        }
+
+        while let Some(result) = listener_tasks.join_next().await {
+            if let Err(e) = result {
+                event!(Level::ERROR, "Listener task failed. Error: {e}");
             }
         }
+
         Ok(())
     }
 
-    async fn health_check(&mut self) {
-        let workers = self.workers.clone();
+    /// Starts the task serving a bound listener as HTTP or as raw TCP
+    fn serve(
+        &self,
+        listener_tasks: &mut JoinSet<()>,
+        listener: TcpListener,
+        config: ListenerConfig,
+        limiter: Option<Arc<RateLimiter>>,
+        acl: Arc<Acl>,
+        split: Option<Arc<TrafficSplit>>,
+    ) -> Result<()> {
+        let Some(http) = &config.http else {
+            let mode = self.listener_mode(&config)?;
+            self.register(&config, limiter.as_ref(), &acl, split.as_ref());
+            event!(
+                Level::INFO,
+                "Listening at addr: {} (pool {}, {mode})",
+                config.address,
+                config.pool
+            );
+            listener_tasks.spawn(serve_tcp(
+                listener,
+                config,
+                mode,
+                limiter,
+                acl,
+                split,
+                self.ctx.clone(),
+            ));
+            return Ok(());
+        };
+
+        if config.passthrough.is_some() {
+            return Err(LoadBalancerError::ConflictingListenerModes {
+                addr: config.address,
+                first: "http",
+                second: "tls passthrough",
+            });
+        }
+        let tls = config
+            .tls
+            .as_ref()
+            .map(|tls| tls_acceptor(tls, HTTP_ALPN_PROTOCOLS))
+            .transpose()?;
+        let router = Arc::new(HttpRouter::new(config.address, http, &self.ctx)?);
+        let cache = http
+            .cache
+            .as_ref()
+            .map(|cache| HttpCache::new(config.address, cache))
+            .transpose()?
+            .map(Arc::new);
+        if let Some(cache) = &cache {
+            self.ctx.metrics.register(cache.clone());
+        }
+        // mirroring is only ever a copy, so a bad mirror leaves the listener serving
+        let mirror = match &http.mirror {
+            Some(mirror) => match Mirror::new(&config, mirror, &self.ctx) {
+                Ok(mirror) => Some(Arc::new(mirror)),
+                Err(e) => {
+                    event!(Level::WARN, "Not mirroring requests. {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(mirror) = &mirror {
+            self.ctx.metrics.register(mirror.clone());
+        }
+        self.register(&config, limiter.as_ref(), &acl, split.as_ref());
 
-        // Task spawned checking health of each worker
-        let mut worker_health_map = HashMap::new();
-        for worker in workers.read().await.worker_addrs.iter() {
-            let stream = TcpStream::connect(**worker).await;
+        event!(
+            Level::INFO,
+            "Listening at addr: {} (pool {}, {})",
+            config.address,
+            config.pool,
+            if tls.is_some() { "https" } else { "http" }
+        );
+        listener_tasks.spawn(serve_http(
+            listener,
+            config,
+            HttpListener {
+                tls,
+                router,
+                cache,
+                mirror,
+            },
+            limiter,
+            acl,
+            split,
+            self.ctx.clone(),
+        ));
+        Ok(())
+    }
 
-            if let Ok(mut stream) = stream {
-                let _ = stream
-                    .write_all(
-                        format!(
-                            "GET /health_check HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-                            worker
-                        )
-                        .as_bytes(),
-                    )
-                    .await;
+    /// Binds a UDP listener and starts the task relaying its flows
+    fn serve_udp(
+        &self,
+        listener_tasks: &mut JoinSet<()>,
+        config: ListenerConfig,
+        limiter: Option<Arc<RateLimiter>>,
+        acl: Arc<Acl>,
+    ) -> Result<()> {
+        let conflict = [
+            (config.tls.is_some(), "tls termination"),
+            (config.passthrough.is_some(), "tls passthrough"),
+            (config.http.is_some(), "http"),
+            (config.accept_proxy_protocol, "proxy protocol"),
+            (config.split.is_some(), "traffic split"),
+        ]
+        .into_iter()
+        .find_map(|(set, mode)| set.then_some(mode));
+        if let Some(second) = conflict {
+            return Err(LoadBalancerError::ConflictingListenerModes {
+                addr: config.address,
+                first: "udp",
+                second,
+            });
+        }
 
-                let mut buf = Vec::new();
-                let _ = stream.read_to_end(&mut buf).await;
+        // an HTTP or gRPC check fails on a worker that only speaks UDP, so none would ever
+        // be picked
+        if let Some(pool) = self.ctx.pool(&config.pool) {
+            let kind = pool.health_check.kind;
+            if matches!(kind, HealthCheckKind::Http | HealthCheckKind::Grpc) {
+                return Err(LoadBalancerError::InvalidPool {
+                    pool: config.pool.clone(),
+                    reason: "udp listeners need a tcp or udp health check",
+                });
+            }
+        }
 
-                let response = String::from_utf8_lossy(&buf);
+        let socket = bind_udp(&config)?;
+        self.register(&config, limiter.as_ref(), &acl, None);
+        event!(
+            Level::INFO,
+            "Listening at addr: {} (pool {}, udp)",
+            config.address,
+            config.pool
+        );
+        listener_tasks.spawn(serve_udp(socket, config, limiter, acl, self.ctx.clone()));
+        Ok(())
+    }
 
-                let status_code = read_status_code(response.lines().next().unwrap_or(""));
+    fn rate_limiter(&self, config: &ListenerConfig) -> Result<Option<Arc<RateLimiter>>> {
+        let Some(rate_limit) = &config.rate_limit else {
+            return Ok(None);
+        };
+        let limiter = RateLimiter::new(config.address, rate_limit)?;
+        Ok(Some(Arc::new(limiter)))
+    }
 
-                // assuming all responses under 400 are healthy
-                worker_health_map.insert(worker.clone(), status_code < 400);
-                if status_code >= 400 {
-                    event!(Level::WARN, "Worker Check Failed {}", worker);
-                }
-            } else {
-                worker_health_map.insert(worker.clone(), false);
-                event!(Level::ERROR, "Worker Stream Failed {}", worker);
-            }
+    /// The listener's ACL, read from the database when it has one. A listener without an
+    /// `acl` section gets an empty one that lets everyone in, so rules added later by a
+    /// reload still apply to it
+    async fn acl(&self, config: &ListenerConfig) -> Arc<Acl> {
+        let acl_config = config.acl.clone().unwrap_or_default();
+        load_acl(config.address, &acl_config, self.db_connection.as_ref()).await
+    }
+
+    /// The listener's traffic split, starting from the stored percentage when it has one
+    async fn split(&self, config: &ListenerConfig) -> Option<Arc<TrafficSplit>> {
+        load_split(
+            config.address,
+            &config.pool,
+            config.split.as_ref()?,
+            &self.ctx,
+            self.db_connection.as_ref(),
+        )
+        .await
+    }
+
+    /// Exports the listener's rate limiter, ACL and split with the other metrics, puts the
+    /// split on the admin listener and starts their reload tasks. Called once the listener
+    /// is bound and about to serve, so one that fails to start leaves nothing behind
+    fn register(
+        &self,
+        config: &ListenerConfig,
+        limiter: Option<&Arc<RateLimiter>>,
+        acl: &Arc<Acl>,
+        split: Option<&Arc<TrafficSplit>>,
+    ) {
+        if let Some(limiter) = limiter {
+            self.ctx.metrics.register(limiter.clone());
+        }
+        let reload_interval = config.acl.clone().unwrap_or_default().reload_interval();
+        self.ctx.metrics.register(acl.clone());
+        tokio::spawn(reload_acl(
+            acl.clone(),
+            reload_interval,
+            self.db_connection.clone(),
+        ));
+        if let (Some(split), Some(split_config)) = (split, &config.split) {
+            self.ctx.metrics.register(split.clone());
+            self.ctx.splits.register(split.clone());
+            tokio::spawn(reload_split(split.clone(), split_config.clone()));
         }
-        event!(Level::INFO, "Worker Health {:?}", worker_health_map);
+    }
 
-        workers
-            .write()
-            .await
-            .update_healthy_workers(worker_health_map.clone());
+    fn listener_mode(&self, config: &ListenerConfig) -> Result<ListenerMode> {
+        match (&config.tls, &config.passthrough) {
+            (Some(_), Some(_)) => Err(LoadBalancerError::ConflictingListenerModes {
+                addr: config.address,
+                first: "tls termination",
+                second: "tls passthrough",
+            }),
+            (Some(tls), None) => Ok(ListenerMode::TerminateTls(tls_acceptor(tls, &[])?)),
+            (None, Some(passthrough)) => Ok(ListenerMode::Passthrough(Arc::new(
+                SniRouter::new(passthrough, &self.ctx),
+            ))),
+            (None, None) => Ok(ListenerMode::Plain),
+        }
+    }
+}
 
-        // TODO Potentially useful after dynamic worker Socket adders
-        // let healthy_workers: Vec<Arc<SocketAddr>> = worker_health_map
-        //     .iter()
-        //     .filter(|(_, healthy)| **healthy == true)
-        //     .map(|(addr, _)| addr.clone())
-        //     .collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{
+        access_log::TerminationReason,
+        config::{CircuitBreakerConfig, PoolConfig},
+    };
+
+    const WORKER: &str = "127.0.0.1:9001";
+
+    fn context() -> ProxyContext {
+        let config = PoolConfig {
+            circuit_breaker: Some(CircuitBreakerConfig {
+                consecutive_failures: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let pool = WorkerPool::new("web".into(), vec![WORKER.parse().unwrap()], &config);
+        ProxyContext::for_pools(vec![pool.unwrap()])
+    }
+
+    fn record(termination: TerminationReason, status: Option<u16>) -> AccessLogRecord {
+        let mut record = AccessLogRecord::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            termination,
+        );
+        record.pool = Some("web".into());
+        record.worker = Some(WORKER.parse().unwrap());
+        record.status = status;
+        record
+    }
+
+    #[tokio::test]
+    async fn client_aborts_do_not_trip_the_breaker() {
+        let ctx = context();
+        let worker = WORKER.parse().unwrap();
+        for _ in 0..10 {
+            // the client is answered 502 once its upload breaks off
+            ctx.report(record(TerminationReason::ClientAborted, Some(502)));
+            ctx.report(record(TerminationReason::ClientAborted, None));
+        }
+        assert!(ctx.pool("web").unwrap().breakers.allows(worker));
+
+        ctx.report(record(TerminationReason::TransferError, None));
+        ctx.report(record(TerminationReason::Completed, Some(503)));
+        assert!(!ctx.pool("web").unwrap().breakers.allows(worker));
     }
 }
@@ -0,0 +1,495 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    Request, StatusCode,
+};
+use tokio::sync::oneshot;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::{
+        config::{ListenerConfig, MirrorConfig},
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+use super::{
+    body::{full, ProxyBody},
+    load_balancer::ProxyContext,
+    pool::WorkerPool,
+};
+
+/// Weight of a new sample in the latency difference average, roughly the last 20 requests
+const LATENCY_WEIGHT: f64 = 0.05;
+
+/// Copies a share of a listener's requests to a shadow pool and compares the answers with
+/// the primary worker's. The shadow pool has its own workers, so mirrored requests never
+/// count against the primary pool's
+#[derive(Debug)]
+pub(crate) struct Mirror {
+    listener: SocketAddr,
+    pool: WorkerPool,
+    config: MirrorConfig,
+    in_flight: AtomicUsize,
+    mirrored: AtomicU64,
+    skipped: AtomicU64,
+    failed: AtomicU64,
+    compared: AtomicU64,
+    mismatched: AtomicU64,
+    /// Shadow minus primary latency in milliseconds, averaged over recent requests
+    latency_difference: Mutex<f64>,
+}
+
+/// What the primary worker answered, and how long it took to connect and respond
+pub(crate) type PrimaryOutcome = (StatusCode, Duration);
+
+/// A request picked for mirroring. It can be sent once the client's body has been read
+/// for the primary worker, and compared once the primary worker has answered
+pub(crate) struct MirrorJob {
+    pub mirror: Arc<Mirror>,
+    head: Request<()>,
+    body: oneshot::Receiver<Bytes>,
+    primary: oneshot::Receiver<PrimaryOutcome>,
+}
+
+/// A picked request, and where to send the primary worker's answer for comparison
+pub(crate) struct Mirrored {
+    pub job: MirrorJob,
+    pub primary: oneshot::Sender<PrimaryOutcome>,
+}
+
+impl Mirror {
+    /// The shadow pool must be one the listener never sends its own requests to, or
+    /// mirrored requests would take workers from the requests they copy
+    pub fn new(
+        listener: &ListenerConfig,
+        config: &MirrorConfig,
+        ctx: &ProxyContext,
+    ) -> Result<Self> {
+        let invalid = |reason: String| LoadBalancerError::InvalidMirror {
+            addr: listener.address,
+            reason,
+        };
+        let routes = listener.http.iter().flat_map(|http| &http.routes);
+        let mut primary = std::iter::once(&listener.pool)
+            .chain(routes.map(|route| &route.pool))
+            .chain(listener.split.iter().map(|split| &split.canary));
+        if primary.any(|pool| *pool == config.pool) {
+            return Err(invalid(format!(
+                "pool {} already serves the listener's requests",
+                config.pool
+            )));
+        }
+        let pool = ctx
+            .pool(&config.pool)
+            .ok_or_else(|| invalid(format!("unknown pool {}", config.pool)))?
+            .clone();
+        let percentage = config.percentage;
+        if percentage.is_nan() || !(0.0..=100.0).contains(&percentage) {
+            return Err(invalid("percentage must be between 0 and 100".into()));
+        }
+
+        Ok(Self {
+            listener: listener.address,
+            pool,
+            config: config.clone(),
+            in_flight: AtomicUsize::new(0),
+            mirrored: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            compared: AtomicU64::new(0),
+            mismatched: AtomicU64::new(0),
+            latency_difference: Mutex::new(0.0),
+        })
+    }
+
+    pub fn pool(&self) -> &WorkerPool {
+        &self.pool
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.config.timeout()
+    }
+
+    /// Decides whether to mirror the request. A picked request's body is copied as the
+    /// primary worker reads it, and the returned sender takes the primary worker's answer
+    pub fn sample<B: Body>(
+        self: &Arc<Self>,
+        request: Request<B>,
+    ) -> (Request<TeeBody<B>>, Option<Mirrored>) {
+        if rand::random::<f64>() * 100.0 >= self.config.percentage {
+            return (request.map(TeeBody::passthrough), None);
+        }
+        let too_large = request
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|length| length > self.config.max_body_bytes as u64);
+        let admitted = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_flight| {
+                (in_flight < self.config.max_in_flight).then_some(in_flight + 1)
+            })
+            .is_ok();
+        if too_large || !admitted {
+            if admitted {
+                self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return (request.map(TeeBody::passthrough), None);
+        }
+
+        let mut head = Request::new(());
+        *head.method_mut() = request.method().clone();
+        *head.uri_mut() = request.uri().clone();
+        *head.version_mut() = request.version();
+        *head.headers_mut() = request.headers().clone();
+
+        let (body_sender, body) = oneshot::channel();
+        let (primary_sender, primary) = oneshot::channel();
+        let limit = self.config.max_body_bytes;
+        let request = request.map(|inner| TeeBody::new(inner, body_sender, limit));
+        let job = MirrorJob {
+            mirror: self.clone(),
+            head,
+            body,
+            primary,
+        };
+        let mirrored = Mirrored {
+            job,
+            primary: primary_sender,
+        };
+        (request, Some(mirrored))
+    }
+
+    fn record(&self, shadow: Option<PrimaryOutcome>, primary: Option<PrimaryOutcome>, path: &str) {
+        let Some((shadow_status, shadow_latency)) = shadow else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        // the primary request failed outright, which the access log already shows
+        let Some((primary_status, primary_latency)) = primary else {
+            return;
+        };
+
+        let first = self.compared.fetch_add(1, Ordering::Relaxed) == 0;
+        if shadow_status != primary_status {
+            self.mismatched.fetch_add(1, Ordering::Relaxed);
+            event!(
+                Level::INFO,
+                "Mirror of {path} on listener {} answered {shadow_status}, primary answered {primary_status}",
+                self.listener
+            );
+        }
+        let difference = (shadow_latency.as_secs_f64() - primary_latency.as_secs_f64()) * 1000.0;
+        if let Ok(mut average) = self.latency_difference.lock() {
+            *average = if first {
+                difference
+            } else {
+                *average + (difference - *average) * LATENCY_WEIGHT
+            };
+        }
+    }
+}
+
+impl MirrorJob {
+    /// The request to send to the shadow pool, once the client's body has been read in full.
+    /// `None` when the body turned out too large or the primary request gave up on it
+    pub async fn request(&mut self) -> Option<Request<ProxyBody>> {
+        let Ok(body) = (&mut self.body).await else {
+            self.mirror.skipped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.mirror.mirrored.fetch_add(1, Ordering::Relaxed);
+
+        let mut request = Request::new(full(body));
+        *request.method_mut() = self.head.method().clone();
+        *request.uri_mut() = self.head.uri().clone();
+        *request.version_mut() = self.head.version();
+        *request.headers_mut() = std::mem::take(self.head.headers_mut());
+        Some(request)
+    }
+
+    /// Compares what the shadow pool answered, `None` when it failed or timed out, with the
+    /// primary worker's answer
+    pub async fn finish(mut self, shadow: Option<PrimaryOutcome>) {
+        let primary = (&mut self.primary).await.ok();
+        self.mirror.record(shadow, primary, self.head.uri().path());
+    }
+}
+
+impl Drop for MirrorJob {
+    fn drop(&mut self) {
+        self.mirror.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Passes the request body to the primary worker, keeping a copy for the mirror until it
+/// grows past the limit. The copy is handed over once the body ends
+pub(crate) struct TeeBody<B> {
+    inner: B,
+    copy: BytesMut,
+    limit: usize,
+    sender: Option<oneshot::Sender<Bytes>>,
+}
+
+impl<B: Body> TeeBody<B> {
+    fn new(inner: B, sender: oneshot::Sender<Bytes>, limit: usize) -> Self {
+        let mut body = Self {
+            inner,
+            copy: BytesMut::new(),
+            limit,
+            sender: Some(sender),
+        };
+        // a body without content may never be polled
+        if body.inner.is_end_stream() {
+            body.finish();
+        }
+        body
+    }
+
+    /// Copies nothing, for requests that are not mirrored
+    pub fn passthrough(inner: B) -> Self {
+        Self {
+            inner,
+            copy: BytesMut::new(),
+            limit: 0,
+            sender: None,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(std::mem::take(&mut self.copy).freeze());
+        }
+    }
+}
+
+impl<B> Body for TeeBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) if this.sender.is_some() => {
+                if let Some(data) = frame.data_ref() {
+                    if this.copy.len() + data.len() > this.limit {
+                        this.sender = None;
+                        this.copy = BytesMut::new();
+                    } else {
+                        this.copy.extend_from_slice(data);
+                    }
+                }
+                if this.inner.is_end_stream() {
+                    this.finish();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.sender = None,
+            Poll::Ready(None) => this.finish(),
+            _ => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl MetricSource for Mirror {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let listener = self.listener.to_string();
+        let labels = [
+            ("listener", listener.as_str()),
+            ("pool", self.pool.name.as_str()),
+        ];
+
+        metrics.counter(
+            "lb_mirror_requests_total",
+            "Requests copied to the shadow pool",
+            &labels,
+            self.mirrored.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_mirror_skipped_total",
+            "Requests picked for mirroring but not copied, for their body size or too many in flight",
+            &labels,
+            self.skipped.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_mirror_failures_total",
+            "Mirrored requests the shadow pool failed or did not answer in time",
+            &labels,
+            self.failed.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_mirror_compared_total",
+            "Mirrored requests whose answer was compared with the primary worker's",
+            &labels,
+            self.compared.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_mirror_status_mismatches_total",
+            "Mirrored requests answered with a different status than the primary worker's",
+            &labels,
+            self.mismatched.load(Ordering::Relaxed),
+        );
+        if let Ok(difference) = self.latency_difference.lock() {
+            metrics.gauge(
+                "lb_mirror_latency_difference_ms",
+                "Recent average of shadow minus primary latency",
+                &labels,
+                *difference,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use std::convert::Infallible;
+
+    fn mirror() -> Mirror {
+        let config = MirrorConfig {
+            pool: "shadow".into(),
+            percentage: 100.0,
+            max_body_bytes: 8,
+            max_in_flight: 1,
+            timeout_ms: 1000,
+        };
+        Mirror {
+            listener: "127.0.0.1:8080".parse().unwrap(),
+            pool: WorkerPool::disabled(config.pool.clone()),
+            config,
+            in_flight: AtomicUsize::new(0),
+            mirrored: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            compared: AtomicU64::new(0),
+            mismatched: AtomicU64::new(0),
+            latency_difference: Mutex::new(0.0),
+        }
+    }
+
+    fn chunks(
+        chunks: &[&'static str],
+    ) -> StreamBody<impl futures::Stream<Item = std::result::Result<Frame<Bytes>, Infallible>>>
+    {
+        let frames = chunks
+            .iter()
+            .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+            .collect::<Vec<_>>();
+        StreamBody::new(stream::iter(frames))
+    }
+
+    fn outcome(status: u16, millis: u64) -> Option<PrimaryOutcome> {
+        Some((
+            StatusCode::from_u16(status).unwrap(),
+            Duration::from_millis(millis),
+        ))
+    }
+
+    #[tokio::test]
+    async fn copies_a_body_under_the_limit() {
+        let (sender, copy) = oneshot::channel();
+        let body = TeeBody::new(chunks(&["abc", "defgh"]), sender, 8);
+
+        let forwarded = body.collect().await.unwrap().to_bytes();
+        assert_eq!(forwarded, "abcdefgh");
+        assert_eq!(copy.await.unwrap(), "abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn drops_the_copy_of_a_body_over_the_limit() {
+        let (sender, copy) = oneshot::channel();
+        let body = TeeBody::new(chunks(&["abcde", "fghij"]), sender, 8);
+
+        // the primary worker still gets all of it
+        let forwarded = body.collect().await.unwrap().to_bytes();
+        assert_eq!(forwarded, "abcdefghij");
+        assert!(copy.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hands_over_an_empty_body_without_polling() {
+        let (sender, copy) = oneshot::channel();
+        let _body = TeeBody::new(Empty::<Bytes>::new(), sender, 8);
+
+        assert_eq!(copy.await.unwrap(), Bytes::new());
+    }
+
+    #[test]
+    fn counts_failures_and_mismatches() {
+        let mirror = mirror();
+
+        mirror.record(None, outcome(200, 10), "/");
+        assert_eq!(mirror.failed.load(Ordering::Relaxed), 1);
+        assert_eq!(mirror.compared.load(Ordering::Relaxed), 0);
+
+        // nothing to compare against when the primary request failed
+        mirror.record(outcome(200, 10), None, "/");
+        assert_eq!(mirror.compared.load(Ordering::Relaxed), 0);
+
+        mirror.record(outcome(200, 10), outcome(200, 10), "/");
+        mirror.record(outcome(500, 10), outcome(200, 10), "/");
+        assert_eq!(mirror.compared.load(Ordering::Relaxed), 2);
+        assert_eq!(mirror.mismatched.load(Ordering::Relaxed), 1);
+        assert_eq!(mirror.failed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn averages_the_latency_difference() {
+        let mirror = mirror();
+        let average = || *mirror.latency_difference.lock().unwrap();
+
+        // the first comparison seeds the average
+        mirror.record(outcome(200, 30), outcome(200, 10), "/");
+        assert!((average() - 20.0).abs() < 1e-9);
+
+        mirror.record(outcome(200, 10), outcome(200, 30), "/");
+        let expected = 20.0 + (-20.0 - 20.0) * LATENCY_WEIGHT;
+        assert!((average() - expected).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn skips_requests_past_the_in_flight_limit() {
+        let mirror = Arc::new(mirror());
+        let request = || Request::new(Empty::<Bytes>::new());
+
+        let (_, first) = mirror.sample(request());
+        assert!(first.is_some());
+        let (_, second) = mirror.sample(request());
+        assert!(second.is_none());
+        assert_eq!(mirror.skipped.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert_eq!(mirror.in_flight.load(Ordering::Relaxed), 0);
+    }
+}
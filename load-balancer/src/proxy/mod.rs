@@ -1,2 +1,28 @@
 pub mod load_balancer;
+mod acl;
+mod admin;
+mod body;
+mod cache;
+mod circuit_breaker;
+mod concurrency;
+mod headers;
+mod grpc;
+mod health;
+mod keepalive;
+mod limits;
+mod http;
+mod listener;
+mod mirror;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod rewrite;
+mod router;
+mod sni;
+mod split;
+mod stream;
+mod tcp;
+mod tls;
+mod udp;
+mod upstream;
 mod workers;
@@ -0,0 +1,425 @@
+use std::{
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{Notify, RwLock},
+    time::{timeout_at, Instant},
+};
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result, WorkerPhase},
+    utils::{
+        access_log::{AccessLogRecord, TerminationReason},
+        config::{HealthCheckConfig, HealthCheckKind, PoolConfig, ProxyProtocolVersion},
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+use super::{
+    circuit_breaker::{CircuitBreakers, CircuitState},
+    concurrency::ConcurrencyLimit,
+    keepalive::KeepAlivePool,
+    limits::WaitQueue,
+    proxy_protocol::{encode_header, ProxiedConnection},
+    stream::BoxedStream,
+    upstream::{connect_worker, UpstreamTls},
+    workers::Workers,
+};
+
+/// A named set of workers and how to reach them
+#[derive(Debug, Clone)]
+pub(crate) struct WorkerPool {
+    pub name: String,
+    pub workers: Arc<RwLock<Workers>>,
+    pub health_check: Arc<HealthCheckConfig>,
+    pub keep_alive: Arc<KeepAlivePool>,
+    /// Shared with `workers`, so outcomes can be recorded without taking its lock
+    pub breakers: Arc<CircuitBreakers>,
+    /// Connections and requests in progress, shed once over the adaptive limit
+    pub concurrency: Arc<ConcurrencyLimit>,
+    /// HTTP listeners speak HTTP/2 to these workers instead of HTTP/1.1
+    pub http2: bool,
+    /// Connections waiting for a worker below its connection limit
+    queue: Arc<WaitQueue>,
+    /// Shared with `workers`, so a closing circuit can wake the queue without its lock
+    released: Arc<Notify>,
+    /// Set while an out of band health check of the pool is running
+    sweeping: Arc<AtomicBool>,
+    upstream_tls: Option<Arc<UpstreamTls>>,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// Held by a running out of band health check, lets the next one start once dropped
+pub(crate) struct SweepGuard(Arc<AtomicBool>);
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl WorkerPool {
+    pub fn new(
+        name: String,
+        worker_addresses: Vec<SocketAddr>,
+        config: &PoolConfig,
+    ) -> Result<Self> {
+        if config.health_check.kind == HealthCheckKind::Grpc && !config.http2 {
+            return Err(LoadBalancerError::InvalidPool {
+                pool: name,
+                reason: "grpc health checks need http2 = true",
+            });
+        }
+
+        let alpn_protocols: &[&[u8]] = if config.http2 { &[b"h2"] } else { &[] };
+        let upstream_tls = config
+            .tls
+            .as_ref()
+            .map(|tls| UpstreamTls::new(tls, alpn_protocols))
+            .transpose()?
+            .map(Arc::new);
+        // a PROXY header names the first client, so connections cannot be shared
+        let keep_alive = match config.send_proxy_protocol {
+            Some(_) => KeepAlivePool::disabled(),
+            None => KeepAlivePool::new(config.keep_alive.clone()),
+        };
+        let breakers = Arc::new(CircuitBreakers::new(config.circuit_breaker.clone()));
+        let concurrency = ConcurrencyLimit::new(&name, config.adaptive_concurrency.clone())?;
+        let workers = Workers::new(
+            worker_addresses,
+            config.algorithm.clone(),
+            config.max_connections_per_worker,
+            breakers.clone(),
+        );
+
+        Ok(Self {
+            name,
+            released: workers.released.clone(),
+            workers: Arc::new(RwLock::new(workers)),
+            health_check: Arc::new(config.health_check.clone()),
+            keep_alive: Arc::new(keep_alive),
+            breakers,
+            concurrency: Arc::new(concurrency),
+            http2: config.http2,
+            queue: Arc::new(WaitQueue::new(&config.queue)),
+            sweeping: Arc::default(),
+            upstream_tls,
+            send_proxy_protocol: config.send_proxy_protocol,
+        })
+    }
+
+    /// A pool with no workers, used in place of a pool whose settings are unusable so its
+    /// traffic fails instead of going out with the wrong transport
+    pub fn disabled(name: String) -> Self {
+        let breakers = Arc::new(CircuitBreakers::default());
+        let workers = Workers::new(vec![], None, None, breakers.clone());
+        Self {
+            name,
+            released: workers.released.clone(),
+            workers: Arc::new(RwLock::new(workers)),
+            health_check: Arc::default(),
+            keep_alive: Arc::new(KeepAlivePool::disabled()),
+            breakers,
+            concurrency: Arc::default(),
+            http2: false,
+            queue: Arc::new(WaitQueue::new(&Default::default())),
+            sweeping: Arc::default(),
+            upstream_tls: None,
+            send_proxy_protocol: None,
+        }
+    }
+
+    /// Picks a worker and counts the connection against it, recording the algorithm used.
+    /// When every healthy worker is at its connection limit, waits in the pool's queue for
+    /// one to free up if `wait` is set. Fails with why no worker could be picked
+    pub async fn select(
+        &self,
+        record: &mut AccessLogRecord,
+        wait: bool,
+    ) -> std::result::Result<Arc<SocketAddr>, TerminationReason> {
+        let deadline = Instant::now() + self.queue.timeout();
+        let mut slot = None;
+
+        loop {
+            let mut released = pin!(self.released.notified());
+            {
+                let mut workers = self.workers.write().await;
+                // listening before looking, so a worker freed or a circuit closed any time
+                // after the look wakes this connection
+                released.as_mut().enable();
+                let next = workers.get_next().await;
+                record.algorithm = Some(workers.algorithm.clone());
+                if let Some(worker) = next {
+                    return Ok(worker);
+                }
+                if !workers.at_capacity() {
+                    return Err(TerminationReason::NoHealthyWorkers);
+                }
+            }
+
+            if !wait {
+                self.queue.reject();
+                return Err(TerminationReason::OverCapacity);
+            }
+            if slot.is_none() {
+                slot = Some(self.queue.enter().ok_or(TerminationReason::OverCapacity)?);
+            }
+            if timeout_at(deadline, released).await.is_err() {
+                self.queue.reject();
+                return Err(TerminationReason::OverCapacity);
+            }
+        }
+    }
+
+    /// Feeds the worker's circuit breaker. An opened circuit also closes the worker's idle
+    /// connections, and a closed one lets queued connections try it
+    pub fn record_outcome(&self, worker: SocketAddr, success: bool) {
+        match self.breakers.record(worker, success) {
+            Some(CircuitState::Open) => {
+                event!(
+                    Level::WARN,
+                    "Circuit opened for worker {worker} in pool {}",
+                    self.name
+                );
+                self.keep_alive.evict(worker);
+            }
+            Some(state) => {
+                event!(
+                    Level::INFO,
+                    "Circuit {state} for worker {worker} in pool {}",
+                    self.name
+                );
+                self.released.notify_waiters();
+            }
+            None => {}
+        }
+    }
+
+    /// Claims the pool's out of band health check, `None` while another one is running
+    pub fn start_sweep(&self) -> Option<SweepGuard> {
+        self.sweeping
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| SweepGuard(self.sweeping.clone()))
+    }
+
+    pub fn uses_tls(&self) -> bool {
+        self.upstream_tls.is_some()
+    }
+
+    /// Opens a connection to one of this pool's workers, over TLS when the pool requires it.
+    /// `client` is announced with PROXY protocol when the pool sends it
+    pub async fn connect(
+        &self,
+        worker: SocketAddr,
+        client: Option<ProxiedConnection>,
+    ) -> Result<BoxedStream> {
+        let stream = self.connect_tcp(worker, client).await?;
+
+        match &self.upstream_tls {
+            Some(upstream_tls) => upstream_tls.connect(worker, stream).await,
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    /// Opens the TCP connection and writes the PROXY protocol header, ignoring upstream TLS
+    pub async fn connect_tcp(
+        &self,
+        worker: SocketAddr,
+        client: Option<ProxiedConnection>,
+    ) -> Result<TcpStream> {
+        let mut stream = connect_worker(worker).await?;
+
+        if let Some(version) = self.send_proxy_protocol {
+            stream
+                .write_all(&encode_header(version, client))
+                .await
+                .map_err(LoadBalancerError::worker(worker, WorkerPhase::Connect))?;
+        }
+
+        Ok(stream)
+    }
+}
+
+impl MetricSource for WorkerPool {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let pool = [("pool", self.name.as_str())];
+        metrics.gauge(
+            "lb_pool_queued",
+            "Connections waiting for a worker below its connection limit",
+            &pool,
+            self.queue.waiting() as f64,
+        );
+        metrics.counter(
+            "lb_pool_rejected_total",
+            "Connections turned away with every worker at its connection limit",
+            &pool,
+            self.queue.rejected(),
+        );
+
+        if let Some((limit, in_flight, shed)) = self.concurrency.stats() {
+            metrics.gauge(
+                "lb_concurrency_limit",
+                "The pool's adaptive limit on connections and requests in progress",
+                &pool,
+                f64::from(limit),
+            );
+            metrics.gauge(
+                "lb_concurrency_in_flight",
+                "Connections and requests in progress counted against the adaptive limit",
+                &pool,
+                f64::from(in_flight),
+            );
+            metrics.counter(
+                "lb_concurrency_shed_total",
+                "Connections and requests failed straight away over the adaptive limit",
+                &pool,
+                shed,
+            );
+        }
+
+        for (worker, state, opened) in self.breakers.states() {
+            let worker = worker.to_string();
+            let labels = [("pool", self.name.as_str()), ("worker", worker.as_str())];
+            let state = match state {
+                CircuitState::Closed => 0.0,
+                CircuitState::Open => 1.0,
+                CircuitState::HalfOpen => 2.0,
+            };
+            metrics.gauge(
+                "lb_circuit_state",
+                "Circuit breaker state per worker, 0 closed, 1 open, 2 half-open",
+                &labels,
+                state,
+            );
+            metrics.counter(
+                "lb_circuit_opened_total",
+                "Times a worker's circuit breaker opened",
+                &labels,
+                opened,
+            );
+        }
+
+        // skipped for this scrape rather than waiting on connections being balanced
+        let Ok(workers) = self.workers.try_read() else {
+            return;
+        };
+        for (worker, load) in &workers.current_worker_loads {
+            let worker = worker.to_string();
+            metrics.gauge(
+                "lb_worker_connections",
+                "Connections, HTTP requests or UDP flows in progress per worker",
+                &[("pool", self.name.as_str()), ("worker", worker.as_str())],
+                *load as f64,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proxy::load_balancer::LoadBalancerAlgorithm,
+        utils::config::{CircuitBreakerConfig, QueueConfig},
+    };
+    use std::time::Duration;
+
+    const A: &str = "127.0.0.1:9001";
+    const B: &str = "127.0.0.1:9002";
+
+    fn pool(workers: &[&str], circuit_breaker: Option<CircuitBreakerConfig>) -> WorkerPool {
+        let config = PoolConfig {
+            algorithm: Some(LoadBalancerAlgorithm::RoundRobin),
+            max_connections_per_worker: Some(1),
+            queue: QueueConfig {
+                size: 10,
+                timeout_ms: 5000,
+            },
+            circuit_breaker,
+            ..Default::default()
+        };
+        let workers = workers
+            .iter()
+            .map(|worker| worker.parse().unwrap())
+            .collect();
+        WorkerPool::new("web".into(), workers, &config).unwrap()
+    }
+
+    fn record() -> AccessLogRecord {
+        AccessLogRecord::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            TerminationReason::Completed,
+        )
+    }
+
+    /// Selects on another task, once it is waiting in the queue
+    async fn queued(pool: &WorkerPool) -> tokio::task::JoinHandle<Option<SocketAddr>> {
+        let waiting = pool.clone();
+        let handle = tokio::spawn(async move {
+            let selected = waiting.select(&mut record(), true).await;
+            selected.ok().map(|worker| *worker)
+        });
+        while pool.queue.waiting() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        handle
+    }
+
+    #[tokio::test]
+    async fn wakes_a_queued_connection_when_a_worker_frees_up() {
+        let pool = pool(&[A], None);
+        let worker = *pool.select(&mut record(), true).await.unwrap();
+        assert_eq!(
+            pool.select(&mut record(), false).await,
+            Err(TerminationReason::OverCapacity)
+        );
+
+        let handle = queued(&pool).await;
+        pool.workers.write().await.decrease_worker_count(worker);
+        let selected = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert_eq!(selected.unwrap().unwrap(), Some(worker));
+    }
+
+    #[tokio::test]
+    async fn wakes_a_queued_connection_when_a_circuit_closes() {
+        let circuit_breaker = CircuitBreakerConfig {
+            consecutive_failures: 1,
+            open_secs: 0,
+            half_open_requests: 1,
+            ..Default::default()
+        };
+        let pool = pool(&[A, B], Some(circuit_breaker));
+        let b = B.parse().unwrap();
+        // B is half-open with its one probe out, so only A can be picked
+        pool.record_outcome(b, false);
+        assert!(pool.breakers.allows(b));
+        pool.breakers.selected(b);
+        let a = pool.select(&mut record(), true).await.unwrap();
+        assert_eq!(a.to_string(), A);
+
+        let handle = queued(&pool).await;
+        pool.record_outcome(b, true);
+        let selected = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert_eq!(selected.unwrap().unwrap(), Some(b));
+    }
+
+    #[test]
+    fn runs_one_sweep_at_a_time() {
+        let pool = pool(&[A], None);
+        let sweep = pool.start_sweep().unwrap();
+        assert!(pool.clone().start_sweep().is_none());
+        drop(sweep);
+        assert!(pool.start_sweep().is_some());
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+use crate::utils::config::ProxyProtocolVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Addresses and a few TLVs fit easily, anything larger is rejected
+const V2_MAX_LEN: usize = 4096;
+
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// How long an upstream balancer has to send its header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait between peeks while the rest of the header is in flight
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+
+/// The client side of a proxied connection, as carried by a PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ProxiedConnection {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Builds the header sent to a worker before anything else. Without a connection, e.g. for
+/// health checks, the header says the balancer itself is connecting
+pub(crate) fn encode_header(
+    version: ProxyProtocolVersion,
+    connection: Option<ProxiedConnection>,
+) -> Vec<u8> {
+    let addrs = connection.map(|c| same_family(c.source, c.destination));
+
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let Some((source, destination)) = addrs else {
+                header.extend([V2_VERSION | V2_COMMAND_LOCAL, V2_FAMILY_UNSPEC, 0, 0]);
+                return header;
+            };
+
+            let (family, mut addresses) = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    (V2_FAMILY_TCP4, [src.octets(), dst.octets()].concat())
+                }
+                (src, dst) => (
+                    V2_FAMILY_TCP6,
+                    [ipv6(src).octets(), ipv6(dst).octets()].concat(),
+                ),
+            };
+            addresses.extend(source.port().to_be_bytes());
+            addresses.extend(destination.port().to_be_bytes());
+
+            header.extend([V2_VERSION | V2_COMMAND_PROXY, family]);
+            header.extend((addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header
+        }
+    }
+}
+
+/// Both addresses must share a family, IPv4 is mapped into IPv6 when they differ
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let mapped = |addr: SocketAddr| SocketAddr::new(IpAddr::V6(ipv6(addr.ip())), addr.port());
+    (mapped(source), mapped(destination))
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Reads and consumes a v1 or v2 header, leaving the stream at the client's first byte.
+/// `None` when the sender proxied nothing (`UNKNOWN` or `LOCAL`), so the peer address stands
+pub(crate) async fn read_header(
+    stream: &mut TcpStream,
+) -> std::result::Result<Option<ProxiedConnection>, &'static str> {
+    let mut buf = vec![0u8; V2_HEADER_LEN + V2_MAX_LEN];
+
+    let peek = async {
+        loop {
+            let n = stream
+                .peek(&mut buf)
+                .await
+                .map_err(|_| "failed to read from client")?;
+            if n == 0 {
+                return Err("connection closed before PROXY header");
+            }
+
+            match parse_header(&buf[..n]) {
+                Header::Incomplete => sleep(PEEK_RETRY).await,
+                Header::Invalid(reason) => return Err(reason),
+                Header::Parsed { len, connection } => return Ok((len, connection)),
+            }
+        }
+    };
+    let (len, connection) = timeout(HEADER_TIMEOUT, peek)
+        .await
+        .unwrap_or(Err("timed out waiting for PROXY header"))?;
+
+    stream
+        .read_exact(&mut buf[..len])
+        .await
+        .map_err(|_| "failed to read from client")?;
+    Ok(connection)
+}
+
+enum Header {
+    Incomplete,
+    Invalid(&'static str),
+    Parsed {
+        len: usize,
+        connection: Option<ProxiedConnection>,
+    },
+}
+
+fn parse_header(buf: &[u8]) -> Header {
+    if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if V1_PREFIX.starts_with(buf) || V2_SIGNATURE.starts_with(buf) {
+        Header::Incomplete
+    } else {
+        Header::Invalid("missing PROXY protocol header")
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Header {
+    let search = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = search.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() < V1_MAX_LEN {
+            Header::Incomplete
+        } else {
+            Header::Invalid("PROXY v1 header too long")
+        };
+    };
+    let len = end + 2;
+
+    let Ok(line) = std::str::from_utf8(&buf[..end]) else {
+        return Header::Invalid("malformed PROXY v1 header");
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let connection = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let parsed = (|| {
+                Some(ProxiedConnection {
+                    source: SocketAddr::new(source.parse().ok()?, source_port.parse().ok()?),
+                    destination: SocketAddr::new(
+                        destination.parse().ok()?,
+                        destination_port.parse().ok()?,
+                    ),
+                })
+            })();
+            match parsed {
+                Some(connection) => Some(connection),
+                None => return Header::Invalid("malformed PROXY v1 header"),
+            }
+        }
+        _ => return Header::Invalid("malformed PROXY v1 header"),
+    };
+
+    Header::Parsed { len, connection }
+}
+
+fn parse_v2(buf: &[u8]) -> Header {
+    let Some(header) = buf.get(..V2_HEADER_LEN) else {
+        return Header::Incomplete;
+    };
+    let version_command = header[12];
+    let family = header[13];
+    let address_len = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    if version_command & 0xF0 != V2_VERSION {
+        return Header::Invalid("unsupported PROXY protocol version");
+    }
+    if address_len > V2_MAX_LEN {
+        return Header::Invalid("PROXY v2 header too long");
+    }
+    let len = V2_HEADER_LEN + address_len;
+    let Some(addresses) = buf.get(V2_HEADER_LEN..len) else {
+        return Header::Incomplete;
+    };
+
+    let connection = match (version_command & 0x0F, family) {
+        (V2_COMMAND_LOCAL, _) => None,
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP4) if addresses.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addresses[at],
+                    addresses[at + 1],
+                    addresses[at + 2],
+                    addresses[at + 3],
+                ))
+            };
+            Some(ProxiedConnection {
+                source: SocketAddr::new(ip(0), port(addresses, 8)),
+                destination: SocketAddr::new(ip(4), port(addresses, 10)),
+            })
+        }
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP6) if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Some(ProxiedConnection {
+                source: SocketAddr::new(ip(0), port(addresses, 32)),
+                destination: SocketAddr::new(ip(16), port(addresses, 34)),
+            })
+        }
+        // UDP and unix sockets say nothing useful about a TCP client
+        (V2_COMMAND_PROXY, _) => None,
+        _ => return Header::Invalid("unsupported PROXY v2 command"),
+    };
+
+    Header::Parsed { len, connection }
+}
+
+fn port(addresses: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([addresses[at], addresses[at + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    fn connection(source: &str, destination: &str) -> ProxiedConnection {
+        ProxiedConnection {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    fn parsed(buf: &[u8]) -> (usize, Option<ProxiedConnection>) {
+        match parse_header(buf) {
+            Header::Parsed { len, connection } => (len, connection),
+            Header::Incomplete => panic!("header reported incomplete"),
+            Header::Invalid(reason) => panic!("header rejected: {reason}"),
+        }
+    }
+
+    fn invalid(buf: &[u8]) -> &'static str {
+        match parse_header(buf) {
+            Header::Invalid(reason) => reason,
+            Header::Incomplete => panic!("header reported incomplete"),
+            Header::Parsed { .. } => panic!("header accepted"),
+        }
+    }
+
+    /// A v2 header with the given command and family byte, followed by `payload`
+    fn v2_header(version_command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        for proxied in [
+            connection("192.0.2.1:51000", "198.51.100.2:443"),
+            connection("[2001:db8::1]:51000", "[2001:db8::2]:443"),
+        ] {
+            let header = encode_header(ProxyProtocolVersion::V1, Some(proxied));
+            assert_eq!(parsed(&header), (header.len(), Some(proxied)));
+        }
+
+        let header = encode_header(
+            ProxyProtocolVersion::V1,
+            Some(connection("192.0.2.1:51000", "198.51.100.2:443")),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 443\r\n");
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        for proxied in [
+            connection("192.0.2.1:51000", "198.51.100.2:443"),
+            connection("[2001:db8::1]:51000", "[2001:db8::2]:443"),
+        ] {
+            let header = encode_header(ProxyProtocolVersion::V2, Some(proxied));
+            assert_eq!(parsed(&header), (header.len(), Some(proxied)));
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_mapped_to_ipv6() {
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            Some(connection("192.0.2.1:51000", "[2001:db8::2]:443")),
+        );
+        assert_eq!(
+            parsed(&header).1,
+            Some(connection("[::ffff:192.0.2.1]:51000", "[2001:db8::2]:443"))
+        );
+    }
+
+    #[test]
+    fn unproxied_headers_carry_no_connection() {
+        let header = encode_header(ProxyProtocolVersion::V1, None);
+        assert_eq!(header, b"PROXY UNKNOWN\r\n");
+        assert_eq!(parsed(&header), (header.len(), None));
+
+        let header = encode_header(ProxyProtocolVersion::V2, None);
+        assert_eq!(parsed(&header), (header.len(), None));
+
+        // a v1 UNKNOWN line may still carry addresses, which are ignored
+        let header = b"PROXY UNKNOWN 192.0.2.1 198.51.100.2 51000 443\r\n";
+        assert_eq!(parsed(header), (header.len(), None));
+    }
+
+    #[test]
+    fn v2_local_skips_its_address_block() {
+        let header = v2_header(V2_VERSION | V2_COMMAND_LOCAL, V2_FAMILY_TCP4, &[1; 12]);
+        let mut buf = header.clone();
+        buf.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(parsed(&buf), (header.len(), None));
+    }
+
+    #[test]
+    fn v2_tlvs_are_consumed_with_the_header() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 2];
+        payload.extend(51000u16.to_be_bytes());
+        payload.extend(443u16.to_be_bytes());
+        // PP2_TYPE_AUTHORITY and PP2_TYPE_NOOP
+        payload.extend([0x02, 0, 11]);
+        payload.extend(b"example.com");
+        payload.extend([0x04, 0, 2, 0, 0]);
+
+        let header = v2_header(V2_VERSION | V2_COMMAND_PROXY, V2_FAMILY_TCP4, &payload);
+        let mut buf = header.clone();
+        buf.extend(b"client data");
+        assert_eq!(
+            parsed(&buf),
+            (
+                header.len(),
+                Some(connection("192.0.2.1:51000", "198.51.100.2:443"))
+            )
+        );
+    }
+
+    #[test]
+    fn truncated_headers_are_incomplete() {
+        for header in [
+            encode_header(
+                ProxyProtocolVersion::V1,
+                Some(connection("[2001:db8::1]:51000", "[2001:db8::2]:443")),
+            ),
+            encode_header(
+                ProxyProtocolVersion::V2,
+                Some(connection("[2001:db8::1]:51000", "[2001:db8::2]:443")),
+            ),
+        ] {
+            for len in 1..header.len() {
+                assert!(
+                    matches!(parse_header(&header[..len]), Header::Incomplete),
+                    "prefix of {len} bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        assert_eq!(
+            invalid(b"GET / HTTP/1.1\r\n"),
+            "missing PROXY protocol header"
+        );
+        assert_eq!(invalid(b"\r\n\r\nX"), "missing PROXY protocol header");
+    }
+
+    #[test]
+    fn rejects_malformed_v1() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 51000\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 443 extra\r\n",
+            b"PROXY TCP4 192.0.2.300 198.51.100.2 51000 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 70000\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 51000 443\r\n",
+            b"PROXY TCP4 \xff 198.51.100.2 51000 443\r\n",
+        ] {
+            assert_eq!(invalid(header), "malformed PROXY v1 header");
+        }
+
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LEN, b'x');
+        assert_eq!(invalid(&header), "PROXY v1 header too long");
+    }
+
+    #[test]
+    fn rejects_malformed_v2() {
+        let header = v2_header(0x10 | V2_COMMAND_PROXY, V2_FAMILY_TCP4, &[0; 12]);
+        assert_eq!(invalid(&header), "unsupported PROXY protocol version");
+
+        let header = v2_header(V2_VERSION | 0x02, V2_FAMILY_TCP4, &[0; 12]);
+        assert_eq!(invalid(&header), "unsupported PROXY v2 command");
+
+        let mut header = v2_header(V2_VERSION | V2_COMMAND_PROXY, V2_FAMILY_TCP4, &[]);
+        header[14..16].copy_from_slice(&(V2_MAX_LEN as u16 + 1).to_be_bytes());
+        assert_eq!(invalid(&header), "PROXY v2 header too long");
+    }
+
+    #[tokio::test]
+    async fn read_header_leaves_client_data_on_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxied = connection("192.0.2.1:51000", "198.51.100.2:443");
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let header = encode_header(ProxyProtocolVersion::V2, Some(proxied));
+            // split mid-header so the reader has to wait for the rest
+            stream.write_all(&header[..10]).await.unwrap();
+            stream.flush().await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            stream.write_all(&header[10..]).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_header(&mut stream).await, Ok(Some(proxied)));
+
+        let _client = client.await.unwrap();
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::Request;
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::{
+        config::{RateLimitConfig, RateLimitKey},
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+/// How often buckets that have refilled completely are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A listener's token buckets, one per client key
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    listener: SocketAddr,
+    per_second: f64,
+    burst: f64,
+    key: RateLimitKey,
+    buckets: Mutex<Buckets>,
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(listener: SocketAddr, config: &RateLimitConfig) -> Result<Self> {
+        let invalid = |reason| LoadBalancerError::InvalidRateLimit {
+            addr: listener,
+            reason,
+        };
+        if !config.per_second.is_finite() || config.per_second <= 0.0 {
+            return Err(invalid("per_second must be above 0"));
+        }
+        if config.burst == 0 {
+            return Err(invalid("burst must be at least 1"));
+        }
+
+        Ok(Self {
+            listener,
+            per_second: config.per_second,
+            burst: f64::from(config.burst),
+            key: config.key.clone(),
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            }),
+            allowed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a token from the client's bucket. When it is empty, returns how long until the
+    /// next token
+    pub fn check(&self, key: &str) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            buckets.swept = now;
+            buckets
+                .clients
+                .retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.clients.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.per_second,
+        ))
+    }
+
+    /// Connections and datagrams are only ever keyed on their address
+    pub fn check_client(&self, client: IpAddr) -> std::result::Result<(), Duration> {
+        self.check(&client.to_canonical().to_string())
+    }
+
+    pub fn check_request<B>(
+        &self,
+        client: IpAddr,
+        request: &Request<B>,
+    ) -> std::result::Result<(), Duration> {
+        let client = client.to_canonical();
+        match &self.key {
+            RateLimitKey::ClientIp => self.check(&client.to_string()),
+            RateLimitKey::Path => self.check(&format!("{client} {}", request.uri().path())),
+            RateLimitKey::Header(name) => match request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+            {
+                Some(value) => self.check(&format!("{name}: {value}")),
+                None => self.check(&client.to_string()),
+            },
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
+
+impl MetricSource for RateLimiter {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let listener = self.listener.to_string();
+        let labels = [("listener", listener.as_str())];
+
+        metrics.counter(
+            "lb_rate_limit_allowed_total",
+            "Connections, requests and datagrams let through by the rate limiter",
+            &labels,
+            self.allowed.load(Ordering::Relaxed),
+        );
+        metrics.counter(
+            "lb_rate_limit_rejected_total",
+            "Connections, requests and datagrams refused by the rate limiter",
+            &labels,
+            self.rejected.load(Ordering::Relaxed),
+        );
+
+        let now = Instant::now();
+        let (tracked, exhausted) = match self.buckets.lock() {
+            Ok(buckets) => (
+                buckets.clients.len(),
+                buckets
+                    .clients
+                    .values()
+                    .filter(|bucket| self.refilled(bucket, now) < 1.0)
+                    .count(),
+            ),
+            Err(_) => (0, 0),
+        };
+        metrics.gauge(
+            "lb_rate_limit_clients",
+            "Clients the rate limiter is tracking a bucket for",
+            &labels,
+            tracked as f64,
+        );
+        metrics.gauge(
+            "lb_rate_limit_exhausted_clients",
+            "Clients currently out of tokens",
+            &labels,
+            exhausted as f64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_second: f64, burst: u32, key: RateLimitKey) -> RateLimiter {
+        let config = RateLimitConfig {
+            per_second,
+            burst,
+            key,
+        };
+        RateLimiter::new("127.0.0.1:8080".parse().unwrap(), &config).unwrap()
+    }
+
+    /// Moves every bucket, and the last sweep, back in time
+    fn age(limiter: &RateLimiter, by: Duration) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        buckets.swept -= by;
+        for bucket in buckets.clients.values_mut() {
+            bucket.updated -= by;
+        }
+    }
+
+    fn request(path: &str, api_key: Option<&str>) -> Request<()> {
+        let mut request = Request::get(path);
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        request.body(()).unwrap()
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn allows_a_burst_then_rejects() {
+        let limiter = limiter(2.0, 3, RateLimitKey::ClientIp);
+        for _ in 0..3 {
+            assert!(limiter.check_client(CLIENT).is_ok());
+        }
+        let retry_after = limiter.check_client(CLIENT).unwrap_err();
+        assert!(retry_after > Duration::from_millis(400));
+        assert!(retry_after <= Duration::from_millis(500));
+        assert_eq!(limiter.allowed.load(Ordering::Relaxed), 3);
+        assert_eq!(limiter.rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let limiter = limiter(2.0, 3, RateLimitKey::ClientIp);
+        for _ in 0..3 {
+            assert!(limiter.check_client(CLIENT).is_ok());
+        }
+        assert!(limiter.check_client(CLIENT).is_err());
+
+        // half a second brings back one token at two a second
+        age(&limiter, Duration::from_millis(500));
+        assert!(limiter.check_client(CLIENT).is_ok());
+        assert!(limiter.check_client(CLIENT).is_err());
+
+        // however long it waits, no more than the burst comes back
+        age(&limiter, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limiter.check_client(CLIENT).is_ok());
+        }
+        assert!(limiter.check_client(CLIENT).is_err());
+    }
+
+    #[test]
+    fn keys_on_the_client_address() {
+        let limiter = limiter(1.0, 1, RateLimitKey::ClientIp);
+        assert!(limiter.check_request(CLIENT, &request("/a", None)).is_ok());
+        assert!(limiter.check_request(CLIENT, &request("/b", None)).is_err());
+        assert!(limiter.check_request(OTHER, &request("/a", None)).is_ok());
+
+        // an IPv4 client seen as a mapped IPv6 address shares its bucket
+        let mapped = IpAddr::V6(std::net::Ipv4Addr::new(192, 0, 2, 2).to_ipv6_mapped());
+        assert!(limiter.check_client(mapped).is_err());
+    }
+
+    #[test]
+    fn keys_on_the_client_and_path() {
+        let limiter = limiter(1.0, 1, RateLimitKey::Path);
+        assert!(limiter.check_request(CLIENT, &request("/a", None)).is_ok());
+        assert!(limiter.check_request(CLIENT, &request("/a", None)).is_err());
+        assert!(limiter.check_request(CLIENT, &request("/b", None)).is_ok());
+        assert!(limiter.check_request(OTHER, &request("/a", None)).is_ok());
+    }
+
+    #[test]
+    fn keys_on_a_header_or_else_the_address() {
+        let limiter = limiter(1.0, 1, RateLimitKey::Header("x-api-key".into()));
+        let first = request("/", Some("first"));
+        assert!(limiter.check_request(CLIENT, &first).is_ok());
+        // the key follows the header across client addresses
+        assert!(limiter.check_request(OTHER, &first).is_err());
+        assert!(limiter
+            .check_request(CLIENT, &request("/", Some("second")))
+            .is_ok());
+
+        assert!(limiter.check_request(CLIENT, &request("/", None)).is_ok());
+        assert!(limiter.check_request(CLIENT, &request("/", None)).is_err());
+        assert!(limiter.check_request(OTHER, &request("/", None)).is_ok());
+    }
+
+    #[test]
+    fn sweeps_buckets_that_refilled() {
+        let limiter = limiter(1.0, 5, RateLimitKey::ClientIp);
+        assert!(limiter.check_client(CLIENT).is_ok());
+        age(&limiter, SWEEP_INTERVAL);
+        for _ in 0..5 {
+            assert!(limiter.check_client(OTHER).is_ok());
+        }
+
+        // the sweep on the next check drops CLIENT's full bucket and keeps OTHER's empty one
+        age(&limiter, SWEEP_INTERVAL);
+        assert!(limiter.check_client(OTHER).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.clients.len(), 1);
+        assert!(buckets.clients.contains_key(&OTHER.to_string()));
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::uri::PathAndQuery,
+    HeaderMap, Request, Uri,
+};
+use regex::Regex;
+use tracing::{event, Level};
+
+use crate::utils::config::{HeaderRewriteConfig, PathRewriteConfig, RouteConfig};
+
+/// A route's changes to requests before they are forwarded and to responses before they
+/// are returned
+#[derive(Debug)]
+pub(crate) struct Rewrite {
+    strip_prefix: Option<String>,
+    path: Option<(Regex, String)>,
+    request_headers: HeaderRules,
+    response_headers: HeaderRules,
+}
+
+#[derive(Debug, Default)]
+struct HeaderRules {
+    rename: Vec<(HeaderName, HeaderName)>,
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    add: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Rewrite {
+    pub fn new(config: &RouteConfig) -> std::result::Result<Self, String> {
+        let path = config
+            .rewrite_path
+            .as_ref()
+            .map(|PathRewriteConfig { regex, replacement }| {
+                Regex::new(regex)
+                    .map(|regex| (regex, replacement.clone()))
+                    .map_err(|e| format!("rewrite_path {regex}: {e}"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            strip_prefix: config
+                .strip_prefix
+                .as_ref()
+                .map(|prefix| prefix.trim_end_matches('/').to_string()),
+            path,
+            request_headers: HeaderRules::new(&config.request_headers)?,
+            response_headers: HeaderRules::new(&config.response_headers)?,
+        })
+    }
+
+    pub fn request<B>(&self, request: &mut Request<B>) {
+        self.request_headers.apply(request.headers_mut());
+        let uri = request.uri_mut();
+
+        if self.strip_prefix.is_none() && self.path.is_none() {
+            return;
+        }
+        let path = self.rewrite_path(uri.path());
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        match PathAndQuery::try_from(path_and_query) {
+            Ok(path_and_query) => *uri = Uri::from(path_and_query),
+            Err(e) => event!(
+                Level::WARN,
+                "Rewritten path for {} is invalid, forwarding it unchanged. {e}",
+                uri.path()
+            ),
+        }
+    }
+
+    pub fn response(&self, headers: &mut HeaderMap) {
+        self.response_headers.apply(headers);
+    }
+
+    fn rewrite_path(&self, path: &str) -> String {
+        let mut path = match &self.strip_prefix {
+            Some(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.to_string(),
+                _ => path.to_string(),
+            },
+            None => path.to_string(),
+        };
+        if let Some((regex, replacement)) = &self.path {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        path
+    }
+}
+
+impl HeaderRules {
+    fn new(config: &HeaderRewriteConfig) -> std::result::Result<Self, String> {
+        let rename = config
+            .rename
+            .iter()
+            .map(|(from, to)| Ok((header_name(from)?, header_name(to)?)))
+            .collect::<std::result::Result<_, String>>()?;
+        let remove = config
+            .remove
+            .iter()
+            .map(|name| header_name(name))
+            .collect::<std::result::Result<_, String>>()?;
+
+        Ok(Self {
+            rename,
+            remove,
+            set: headers(&config.set)?,
+            add: headers(&config.add)?,
+        })
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        for (from, to) in &self.rename {
+            let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+            headers.remove(from);
+            for value in values {
+                headers.append(to, value);
+            }
+        }
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name, value.clone());
+        }
+        for (name, value) in &self.add {
+            headers.append(name, value.clone());
+        }
+    }
+}
+
+fn header_name(name: &str) -> std::result::Result<HeaderName, String> {
+    HeaderName::try_from(name).map_err(|_| format!("invalid header name {name}"))
+}
+
+fn headers(
+    config: &HashMap<String, String>,
+) -> std::result::Result<Vec<(HeaderName, HeaderValue)>, String> {
+    config
+        .iter()
+        .map(|(name, value)| {
+            let name = header_name(name)?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|_| format!("invalid value for header {name}"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rewrite from the rest of a `[[listeners.http.routes]]` table
+    fn rewrite(route: &str) -> Rewrite {
+        let config: RouteConfig = toml::from_str(&format!("pool = \"web\"\n{route}")).unwrap();
+        Rewrite::new(&config).unwrap()
+    }
+
+    fn forward(rewrite: &Rewrite, uri: &str) -> String {
+        let mut request = Request::builder().uri(uri).body(()).unwrap();
+        rewrite.request(&mut request);
+        request.uri().to_string()
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn strips_a_prefix_on_whole_segments() {
+        let rewrite = rewrite(r#"strip_prefix = "/v1/""#);
+        assert_eq!(forward(&rewrite, "/v1/work"), "/work");
+        assert_eq!(forward(&rewrite, "/v1"), "/");
+        assert_eq!(forward(&rewrite, "/v1/"), "/");
+        assert_eq!(forward(&rewrite, "/v10/work"), "/v10/work");
+        assert_eq!(forward(&rewrite, "/other"), "/other");
+    }
+
+    #[test]
+    fn replaces_the_first_match_after_stripping() {
+        let named = rewrite(
+            r#"
+            strip_prefix = "/api"
+            rewrite_path = { regex = "^/users/(?P<id>[0-9]+)", replacement = "/people/$id" }
+            "#,
+        );
+        assert_eq!(forward(&named, "/api/users/42/posts"), "/people/42/posts");
+        assert_eq!(forward(&named, "/api/users/me"), "/users/me");
+
+        let relative = rewrite(r#"rewrite_path = { regex = "^/old", replacement = "" }"#);
+        assert_eq!(forward(&relative, "/oldpage"), "/page");
+        assert_eq!(forward(&relative, "/old"), "/");
+    }
+
+    #[test]
+    fn keeps_the_query_string() {
+        let rewrite = rewrite(r#"strip_prefix = "/v1""#);
+        assert_eq!(
+            forward(&rewrite, "/v1/work?n=1&m=a%20b"),
+            "/work?n=1&m=a%20b"
+        );
+        assert_eq!(forward(&rewrite, "/v1?n=1"), "/?n=1");
+    }
+
+    #[test]
+    fn leaves_the_uri_alone_without_path_rules() {
+        let rewrite = rewrite("");
+        assert_eq!(
+            forward(&rewrite, "http://example.com/a?b=c"),
+            "http://example.com/a?b=c"
+        );
+    }
+
+    #[test]
+    fn renames_removes_sets_and_adds_in_that_order() {
+        let rewrite = rewrite(
+            r#"
+            [request_headers]
+            rename = { x-old = "x-new" }
+            remove = ["x-new", "x-drop"]
+            set = { x-drop = "set", x-multi = "one" }
+            add = { x-multi = "two", x-tag = "added" }
+            "#,
+        );
+        let mut request = Request::builder()
+            .uri("/")
+            .header("x-old", "renamed")
+            .header("x-drop", "original")
+            .header("x-multi", "a")
+            .header("x-multi", "b")
+            .header("x-tag", "kept")
+            .body(())
+            .unwrap();
+        rewrite.request(&mut request);
+        let headers = request.headers();
+
+        // renamed and then removed under its new name
+        assert!(values(headers, "x-old").is_empty());
+        assert!(values(headers, "x-new").is_empty());
+        // removed and then set again
+        assert_eq!(values(headers, "x-drop"), ["set"]);
+        // set replaces every value and add appends after it
+        assert_eq!(values(headers, "x-multi"), ["one", "two"]);
+        assert_eq!(values(headers, "x-tag"), ["kept", "added"]);
+    }
+
+    #[test]
+    fn renames_keep_every_value() {
+        let rewrite = rewrite(
+            r#"
+            [response_headers]
+            rename = { x-upstream = "x-served-by" }
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.append("x-upstream", HeaderValue::from_static("a"));
+        headers.append("x-upstream", HeaderValue::from_static("b"));
+        headers.append("x-served-by", HeaderValue::from_static("balancer"));
+        rewrite.response(&mut headers);
+
+        assert!(values(&headers, "x-upstream").is_empty());
+        assert_eq!(values(&headers, "x-served-by"), ["balancer", "a", "b"]);
+    }
+
+    #[test]
+    fn request_rules_leave_responses_alone() {
+        let rewrite = rewrite(
+            r#"
+            [request_headers]
+            set = { x-request = "1" }
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        rewrite.response(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for route in [
+            r#"rewrite_path = { regex = "(", replacement = "" }"#,
+            "request_headers = { remove = [\"bad header\"] }",
+            "response_headers = { set = { x-ok = \"bad\\nvalue\" } }",
+        ] {
+            let config: RouteConfig = toml::from_str(&format!("pool = \"web\"\n{route}")).unwrap();
+            assert!(Rewrite::new(&config).is_err(), "{route}");
+        }
+    }
+}
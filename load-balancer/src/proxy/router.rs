@@ -0,0 +1,379 @@
+use std::net::SocketAddr;
+
+use hyper::{
+    header::{HeaderName, HeaderValue, HOST},
+    Method, Request,
+};
+use regex::Regex;
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::config::{HttpListenerConfig, RouteConfig},
+};
+
+use super::{load_balancer::ProxyContext, pool::WorkerPool, rewrite::Rewrite};
+
+/// Picks the pool for an HTTP request from the listener's ordered routes
+#[derive(Debug)]
+pub(crate) struct HttpRouter {
+    routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Route {
+    host: Option<String>,
+    path: Option<PathMatch>,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    pub pool: WorkerPool,
+    pub rewrite: Rewrite,
+}
+
+#[derive(Debug)]
+enum PathMatch {
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl HttpRouter {
+    pub fn new(
+        listener: SocketAddr,
+        config: &HttpListenerConfig,
+        ctx: &ProxyContext,
+    ) -> Result<Self> {
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| Route::new(route, ctx))
+            .collect::<std::result::Result<_, String>>()
+            .map_err(|reason| LoadBalancerError::InvalidRoute { listener, reason })?;
+
+        Ok(Self { routes })
+    }
+
+    /// `None` when no route matches, leaving the listener's own pool
+    pub fn route<B>(&self, request: &Request<B>) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(request))
+    }
+}
+
+impl Route {
+    fn new(config: &RouteConfig, ctx: &ProxyContext) -> std::result::Result<Self, String> {
+        let pool = ctx
+            .pool(&config.pool)
+            .ok_or_else(|| format!("unknown pool {}", config.pool))?
+            .clone();
+
+        let path = match (&config.path_prefix, &config.path_regex) {
+            (Some(_), Some(_)) => return Err("set path_prefix or path_regex, not both".into()),
+            (Some(prefix), None) => {
+                Some(PathMatch::Prefix(prefix.trim_end_matches('/').to_string()))
+            }
+            (None, Some(pattern)) => Some(PathMatch::Regex(
+                Regex::new(pattern).map_err(|e| format!("path_regex {pattern}: {e}"))?,
+            )),
+            (None, None) => None,
+        };
+
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("invalid method {method}"))
+            })
+            .collect::<std::result::Result<_, _>>()?;
+
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::try_from(name.as_str())
+                    .map_err(|_| format!("invalid header name {name}"))?;
+                let value = HeaderValue::try_from(value.as_str())
+                    .map_err(|_| format!("invalid value for header {name}"))?;
+                Ok((name, value))
+            })
+            .collect::<std::result::Result<_, String>>()?;
+
+        Ok(Self {
+            host: config.host.as_ref().map(|host| host.to_ascii_lowercase()),
+            path,
+            methods,
+            headers,
+            pool,
+            rewrite: Rewrite::new(config)?,
+        })
+    }
+
+    fn matches<B>(&self, request: &Request<B>) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(request.method()) {
+            return false;
+        }
+
+        if let Some(host) = &self.host {
+            let Some(request_host) = request_host(request) else {
+                return false;
+            };
+            if !host_matches(host, &request_host.to_ascii_lowercase()) {
+                return false;
+            }
+        }
+
+        let path = request.uri().path();
+        let path_matches = match &self.path {
+            Some(PathMatch::Prefix(prefix)) => path
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+            Some(PathMatch::Regex(regex)) => regex.is_match(path),
+            None => true,
+        };
+
+        path_matches
+            && self
+                .headers
+                .iter()
+                .all(|(name, value)| request.headers().get(name) == Some(value))
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(_, host_parent)| host_parent == parent),
+        None => pattern == host,
+    }
+}
+
+/// Host the client asked for without the port, from the Host header or an absolute URI
+pub(crate) fn request_host<B>(request: &Request<B>) -> Option<&str> {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host())?;
+
+    // keep the brackets of an IPv6 literal, drop the port
+    Some(match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::config::PoolConfig;
+
+    use super::*;
+
+    /// A router over `routes`, written as the `[[listeners.http.routes]]` tables would be
+    fn router_of(routes: &str) -> HttpRouter {
+        let pools = ["web", "api", "v2", "static", "admin"].map(|name| {
+            WorkerPool::new(
+                name.into(),
+                vec!["127.0.0.1:9001".parse().unwrap()],
+                &PoolConfig::default(),
+            )
+            .unwrap()
+        });
+        let ctx = ProxyContext::for_pools(pools.into());
+        let config: HttpListenerConfig = toml::from_str(routes).unwrap();
+        HttpRouter::new("127.0.0.1:8080".parse().unwrap(), &config, &ctx).unwrap()
+    }
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn get(uri: &str, host: &str) -> Request<()> {
+        request(Method::GET, uri, &[("host", host)])
+    }
+
+    /// The pool a request goes to, `None` for the listener's own
+    fn pool<'a>(router: &'a HttpRouter, request: &Request<()>) -> Option<&'a str> {
+        router.route(request).map(|route| route.pool.name.as_str())
+    }
+
+    #[tokio::test]
+    async fn wildcard_hosts_match_one_label() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            host = "*.example.com"
+            pool = "api"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/", "a.example.com")), Some("api"));
+        assert_eq!(pool(&router, &get("/", "A.Example.COM:8443")), Some("api"));
+        assert_eq!(pool(&router, &get("/", "a.b.example.com")), None);
+        assert_eq!(pool(&router, &get("/", "example.com")), None);
+        assert_eq!(pool(&router, &get("/", "a.example.org")), None);
+        assert_eq!(pool(&router, &request(Method::GET, "/", &[])), None);
+    }
+
+    #[tokio::test]
+    async fn exact_hosts_ignore_the_port_and_case() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            host = "Example.com"
+            pool = "api"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/", "example.com:8080")), Some("api"));
+        assert_eq!(pool(&router, &get("/", "www.example.com")), None);
+        assert_eq!(
+            pool(&router, &request(Method::GET, "http://example.com/x", &[])),
+            Some("api")
+        );
+    }
+
+    #[tokio::test]
+    async fn path_prefixes_match_whole_segments() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            path_prefix = "/api/"
+            pool = "api"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/api", "h")), Some("api"));
+        assert_eq!(pool(&router, &get("/api/users?page=2", "h")), Some("api"));
+        assert_eq!(pool(&router, &get("/apis", "h")), None);
+        assert_eq!(pool(&router, &get("/", "h")), None);
+    }
+
+    #[tokio::test]
+    async fn the_longer_prefix_wins_when_listed_first() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            path_prefix = "/api/v2"
+            pool = "v2"
+
+            [[routes]]
+            path_prefix = "/api"
+            pool = "api"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/api/v2/users", "h")), Some("v2"));
+        assert_eq!(pool(&router, &get("/api/v2", "h")), Some("v2"));
+        assert_eq!(pool(&router, &get("/api/v21", "h")), Some("api"));
+        assert_eq!(pool(&router, &get("/api/v1/users", "h")), Some("api"));
+
+        // routes are checked in order, so a shorter prefix listed first shadows it
+        let shadowed = router_of(
+            r#"
+            [[routes]]
+            path_prefix = "/api"
+            pool = "api"
+
+            [[routes]]
+            path_prefix = "/api/v2"
+            pool = "v2"
+            "#,
+        );
+        assert_eq!(pool(&shadowed, &get("/api/v2/users", "h")), Some("api"));
+    }
+
+    #[tokio::test]
+    async fn path_regexes_match_anywhere_in_the_path() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            path_regex = "\\.(css|js)$"
+            pool = "static"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/assets/app.js", "h")), Some("static"));
+        assert_eq!(pool(&router, &get("/app.json", "h")), None);
+    }
+
+    #[tokio::test]
+    async fn methods_and_headers_must_all_match() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            methods = ["post", "PUT"]
+            headers = { x-admin = "yes", x-team = "ops" }
+            pool = "admin"
+            "#,
+        );
+        let admin = [("x-admin", "yes"), ("x-team", "ops")];
+        assert_eq!(
+            pool(&router, &request(Method::POST, "/", &admin)),
+            Some("admin")
+        );
+        assert_eq!(
+            pool(&router, &request(Method::PUT, "/", &admin)),
+            Some("admin")
+        );
+        assert_eq!(pool(&router, &request(Method::GET, "/", &admin)), None);
+        assert_eq!(
+            pool(&router, &request(Method::POST, "/", &admin[..1])),
+            None
+        );
+        assert_eq!(
+            pool(
+                &router,
+                &request(Method::POST, "/", &[("x-admin", "YES"), ("x-team", "ops")])
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn every_condition_of_a_route_must_match() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            host = "api.example.com"
+            path_prefix = "/v1"
+            methods = ["GET"]
+            pool = "api"
+            "#,
+        );
+        assert_eq!(
+            pool(&router, &get("/v1/work", "api.example.com")),
+            Some("api")
+        );
+        assert_eq!(pool(&router, &get("/v1/work", "www.example.com")), None);
+        assert_eq!(pool(&router, &get("/v2/work", "api.example.com")), None);
+        let post = request(Method::POST, "/v1/work", &[("host", "api.example.com")]);
+        assert_eq!(pool(&router, &post), None);
+    }
+
+    #[tokio::test]
+    async fn unmatched_requests_fall_back_to_the_listeners_pool() {
+        let router = router_of(
+            r#"
+            [[routes]]
+            path_prefix = "/static"
+            pool = "static"
+            "#,
+        );
+        assert_eq!(pool(&router, &get("/index.html", "h")), None);
+        assert_eq!(pool(&router_of(""), &get("/static/app.js", "h")), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_routes() {
+        let ctx = ProxyContext::for_pools(Vec::new());
+        for routes in [
+            r#"routes = [{ pool = "missing" }]"#,
+            r#"routes = [{ pool = "web", path_prefix = "/a", path_regex = "b" }]"#,
+        ] {
+            let config: HttpListenerConfig = toml::from_str(routes).unwrap();
+            let router = HttpRouter::new("127.0.0.1:8080".parse().unwrap(), &config, &ctx);
+            assert!(matches!(
+                router,
+                Err(LoadBalancerError::InvalidRoute { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tracing::{event, Level};
+
+use crate::utils::config::PassthroughConfig;
+
+use super::{load_balancer::ProxyContext, pool::WorkerPool};
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16384;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Wait between peeks while the rest of the ClientHello is in flight
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+
+/// Picks the pool for a passthrough connection from the SNI in its ClientHello
+#[derive(Debug)]
+pub(crate) struct SniRouter {
+    routes: HashMap<String, WorkerPool>,
+    client_hello_timeout: Duration,
+}
+
+impl SniRouter {
+    /// Routes naming a pool that does not exist are logged and left out
+    pub fn new(config: &PassthroughConfig, ctx: &ProxyContext) -> Self {
+        let mut routes = HashMap::new();
+        for route in &config.routes {
+            let Some(pool) = ctx.pool(&route.pool) else {
+                event!(
+                    Level::ERROR,
+                    "Passthrough route {:?} references unknown pool {}",
+                    route.server_names,
+                    route.pool
+                );
+                continue;
+            };
+            for name in &route.server_names {
+                routes.insert(name.to_ascii_lowercase(), pool.clone());
+            }
+        }
+
+        Self {
+            routes,
+            client_hello_timeout: config.client_hello_timeout(),
+        }
+    }
+
+    /// Exact names win over `*.parent` wildcards. `None` leaves the listener's own pool
+    pub fn route(&self, server_name: Option<&str>) -> Option<&WorkerPool> {
+        let server_name = server_name?;
+        if let Some(pool) = self.routes.get(server_name) {
+            return Some(pool);
+        }
+
+        server_name
+            .split_once('.')
+            .and_then(|(_, parent)| self.routes.get(&format!("*.{parent}")))
+    }
+
+    /// Reads the SNI without consuming anything, so the worker receives the handshake as sent
+    pub async fn peek_server_name(
+        &self,
+        stream: &TcpStream,
+    ) -> std::result::Result<Option<String>, &'static str> {
+        let mut buf = vec![0u8; RECORD_HEADER_LEN + MAX_RECORD_LEN];
+
+        let peek = async {
+            loop {
+                let n = stream
+                    .peek(&mut buf)
+                    .await
+                    .map_err(|_| "failed to read from client")?;
+                if n == 0 {
+                    return Err("connection closed before ClientHello");
+                }
+
+                match parse_client_hello(&buf[..n]) {
+                    ClientHello::Incomplete => sleep(PEEK_RETRY).await,
+                    ClientHello::Invalid(reason) => return Err(reason),
+                    ClientHello::ServerName(server_name) => return Ok(server_name),
+                }
+            }
+        };
+
+        timeout(self.client_hello_timeout, peek)
+            .await
+            .unwrap_or(Err("timed out waiting for ClientHello"))
+    }
+}
+
+enum ClientHello {
+    Incomplete,
+    Invalid(&'static str),
+    ServerName(Option<String>),
+}
+
+/// Only the first record is inspected, a ClientHello fragmented over several records is
+/// rejected rather than buffered
+fn parse_client_hello(buf: &[u8]) -> ClientHello {
+    let Some(header) = buf.get(..RECORD_HEADER_LEN) else {
+        return ClientHello::Incomplete;
+    };
+    if header[0] != CONTENT_TYPE_HANDSHAKE {
+        return ClientHello::Invalid("not a tls handshake");
+    }
+
+    let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if record_len > MAX_RECORD_LEN {
+        return ClientHello::Invalid("tls record too large");
+    }
+    let Some(record) = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) else {
+        return ClientHello::Incomplete;
+    };
+
+    match read_server_name(&mut Reader(record)) {
+        Some(server_name) => ClientHello::ServerName(server_name),
+        None => ClientHello::Invalid("malformed ClientHello"),
+    }
+}
+
+fn read_server_name(record: &mut Reader) -> Option<Option<String>> {
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let handshake_len = record.u24()?;
+    let mut hello = Reader(record.bytes(handshake_len)?);
+
+    // legacy_version and random
+    hello.bytes(2 + 32)?;
+    let session_id_len = hello.u8()? as usize;
+    hello.bytes(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.bytes(cipher_suites_len)?;
+    let compression_methods_len = hello.u8()? as usize;
+    hello.bytes(compression_methods_len)?;
+
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(extensions_len)?);
+
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let extension = extensions.bytes(extension_len)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut extension = Reader(extension);
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.bytes(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()? as usize;
+            let name = names.bytes(name_len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+    }
+
+    Some(None)
+}
+
+/// Big endian cursor over a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    use super::*;
+    use crate::proxy::tls::crypto_provider;
+
+    /// A TLS record holding a ClientHello with the given extensions block, or none at all
+    fn client_hello(extensions: Option<Vec<u8>>) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        // session id, one cipher suite and the null compression method
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        if let Some(extensions) = extensions {
+            hello.extend((extensions.len() as u16).to_be_bytes());
+            hello.extend(extensions);
+        }
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    fn extension(extension_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut extension = extension_type.to_be_bytes().to_vec();
+        extension.extend((data.len() as u16).to_be_bytes());
+        extension.extend(data);
+        extension
+    }
+
+    fn server_name_extension(names: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (name_type, name) in names {
+            list.push(*name_type);
+            list.extend((name.len() as u16).to_be_bytes());
+            list.extend(*name);
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend(list);
+        extension(EXTENSION_SERVER_NAME, &data)
+    }
+
+    fn server_name(buf: &[u8]) -> Option<String> {
+        match parse_client_hello(buf) {
+            ClientHello::ServerName(name) => name,
+            ClientHello::Incomplete => panic!("ClientHello reported incomplete"),
+            ClientHello::Invalid(reason) => panic!("ClientHello rejected: {reason}"),
+        }
+    }
+
+    #[test]
+    fn reads_server_name_from_rustls_client_hello() {
+        let config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client =
+            ClientConnection::new(Arc::new(config), "api.example.com".try_into().unwrap()).unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+
+        assert_eq!(server_name(&buf).as_deref(), Some("api.example.com"));
+    }
+
+    #[test]
+    fn lowercases_server_name() {
+        let extensions = server_name_extension(&[(NAME_TYPE_HOST_NAME, b"Example.COM")]);
+        let buf = client_hello(Some(extensions));
+        assert_eq!(server_name(&buf).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn skips_other_extensions_and_name_types() {
+        let mut extensions = extension(0x000a, &[0, 2, 0, 0x1d]);
+        extensions.extend(server_name_extension(&[
+            (1, b"ignored"),
+            (NAME_TYPE_HOST_NAME, b"example.com"),
+        ]));
+        let buf = client_hello(Some(extensions));
+        assert_eq!(server_name(&buf).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn client_hello_without_server_name() {
+        assert_eq!(server_name(&client_hello(None)), None);
+        assert_eq!(server_name(&client_hello(Some(Vec::new()))), None);
+
+        let extensions = extension(0x000a, &[0, 2, 0, 0x1d]);
+        assert_eq!(server_name(&client_hello(Some(extensions))), None);
+    }
+
+    #[test]
+    fn truncated_record_is_incomplete() {
+        let extensions = server_name_extension(&[(NAME_TYPE_HOST_NAME, b"example.com")]);
+        let buf = client_hello(Some(extensions));
+        for len in 0..buf.len() {
+            assert!(
+                matches!(parse_client_hello(&buf[..len]), ClientHello::Incomplete),
+                "prefix of {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_records_that_are_not_a_handshake() {
+        let mut buf = client_hello(None);
+        buf[0] = 0x17;
+        assert!(matches!(
+            parse_client_hello(&buf),
+            ClientHello::Invalid("not a tls handshake")
+        ));
+
+        // plain HTTP sent to a passthrough listener
+        assert!(matches!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::Invalid("not a tls handshake")
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_record() {
+        let mut buf = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        buf.extend((MAX_RECORD_LEN as u16 + 1).to_be_bytes());
+        assert!(matches!(
+            parse_client_hello(&buf),
+            ClientHello::Invalid("tls record too large")
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_client_hello() {
+        let is_malformed = |buf: &[u8]| {
+            matches!(
+                parse_client_hello(buf),
+                ClientHello::Invalid("malformed ClientHello")
+            )
+        };
+
+        // a handshake message other than ClientHello
+        let mut buf = client_hello(None);
+        buf[RECORD_HEADER_LEN] = 0x02;
+        assert!(is_malformed(&buf));
+
+        // handshake length running past the end of the record
+        let mut buf = client_hello(None);
+        buf[RECORD_HEADER_LEN + 3] += 1;
+        assert!(is_malformed(&buf));
+
+        // extension length running past the end of the extensions block
+        let mut extensions = server_name_extension(&[(NAME_TYPE_HOST_NAME, b"example.com")]);
+        extensions[3] += 1;
+        assert!(is_malformed(&client_hello(Some(extensions))));
+
+        // server name that is not valid UTF-8
+        let extensions = server_name_extension(&[(NAME_TYPE_HOST_NAME, &[0xff, 0xfe])]);
+        assert!(is_malformed(&client_hello(Some(extensions))));
+    }
+}
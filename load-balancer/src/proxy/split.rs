@@ -0,0 +1,384 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use serde::Serialize;
+use tokio::time::interval;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    services::postgres_store::PostgresWorkerStore,
+    utils::{
+        config::SplitConfig,
+        metrics::{MetricSource, MetricsWriter},
+    },
+};
+
+use super::{load_balancer::ProxyContext, pool::WorkerPool};
+
+/// A listener's traffic divided between its own pool and a canary pool. Each side picks
+/// its worker with its own pool's algorithm and counts
+#[derive(Debug)]
+pub(crate) struct TrafficSplit {
+    listener: SocketAddr,
+    stable: WorkerPool,
+    canary: WorkerPool,
+    /// Share sent to the canary pool, the bits of an `f64` between 0 and 100
+    percentage: AtomicU64,
+    /// Where the split is kept, when the listener asked for it to be stored
+    store: Option<PostgresWorkerStore>,
+    stable_total: AtomicU64,
+    canary_total: AtomicU64,
+}
+
+/// How a listener's traffic is split, as the admin listener shows it
+#[derive(Debug, Serialize)]
+pub(crate) struct SplitStatus {
+    pub listener: SocketAddr,
+    pub stable: String,
+    pub canary: String,
+    pub percentage: f64,
+}
+
+impl TrafficSplit {
+    pub fn new(
+        listener: SocketAddr,
+        stable: &str,
+        config: &SplitConfig,
+        ctx: &ProxyContext,
+    ) -> Result<Self> {
+        let invalid = |reason: String| LoadBalancerError::InvalidSplit {
+            addr: listener,
+            reason,
+        };
+        let pool = |name: &str| {
+            ctx.pool(name)
+                .cloned()
+                .ok_or_else(|| invalid(format!("unknown pool {name}")))
+        };
+        if config.canary == stable {
+            return Err(invalid(
+                "the canary must be another pool than the listener's".into(),
+            ));
+        }
+        let (stable, canary) = (pool(stable)?, pool(&config.canary)?);
+        if !valid_percentage(config.percentage) {
+            return Err(invalid("percentage must be between 0 and 100".into()));
+        }
+
+        Ok(Self {
+            listener,
+            stable,
+            canary,
+            percentage: AtomicU64::new(config.percentage.to_bits()),
+            store: None,
+            stable_total: AtomicU64::new(0),
+            canary_total: AtomicU64::new(0),
+        })
+    }
+
+    pub fn listener(&self) -> SocketAddr {
+        self.listener
+    }
+
+    pub fn percentage(&self) -> f64 {
+        f64::from_bits(self.percentage.load(Ordering::Relaxed))
+    }
+
+    /// The pool for one more connection or request
+    pub fn pick(&self) -> &WorkerPool {
+        if rand::random::<f64>() * 100.0 < self.percentage() {
+            self.canary_total.fetch_add(1, Ordering::Relaxed);
+            &self.canary
+        } else {
+            self.stable_total.fetch_add(1, Ordering::Relaxed);
+            &self.stable
+        }
+    }
+
+    /// Moves the split. A stored split is written to the database first, and left as it
+    /// was when that fails
+    pub async fn set_percentage(&self, percentage: f64) -> Result<()> {
+        if !valid_percentage(percentage) {
+            return Err(LoadBalancerError::InvalidSplit {
+                addr: self.listener,
+                reason: "percentage must be between 0 and 100".into(),
+            });
+        }
+        if let Some(store) = &self.store {
+            store.set_traffic_split(self.listener, percentage).await?;
+        }
+        self.percentage
+            .store(percentage.to_bits(), Ordering::Relaxed);
+        event!(
+            Level::INFO,
+            "Listener {} now sends {percentage}% to pool {}",
+            self.listener,
+            self.canary.name
+        );
+        Ok(())
+    }
+
+    pub fn status(&self) -> SplitStatus {
+        SplitStatus {
+            listener: self.listener,
+            stable: self.stable.name.clone(),
+            canary: self.canary.name.clone(),
+            percentage: self.percentage(),
+        }
+    }
+
+    /// Takes the stored split over the configured one, keeping the current split when the
+    /// read fails or nothing is stored yet
+    async fn load_stored(&self, store: &PostgresWorkerStore) {
+        match store.get_traffic_split(self.listener).await {
+            Ok(Some(percentage)) if valid_percentage(percentage) => {
+                if percentage != self.percentage() {
+                    event!(
+                        Level::INFO,
+                        "Listener {} sends {percentage}% to pool {}, as stored",
+                        self.listener,
+                        self.canary.name
+                    );
+                }
+                self.percentage
+                    .store(percentage.to_bits(), Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(e) => event!(
+                Level::WARN,
+                "Keeping the traffic split of listener {}. {e}",
+                self.listener
+            ),
+        }
+    }
+}
+
+fn valid_percentage(percentage: f64) -> bool {
+    (0.0..=100.0).contains(&percentage)
+}
+
+/// Builds the listener's split, reading the stored percentage before it starts serving. A
+/// split that cannot be built, such as one whose canary pool is missing, is turned off with
+/// a warning and the listener sends everything to its own pool
+pub(crate) async fn load_split(
+    listener: SocketAddr,
+    stable: &str,
+    config: &SplitConfig,
+    ctx: &ProxyContext,
+    store: Option<&PostgresWorkerStore>,
+) -> Option<Arc<TrafficSplit>> {
+    let mut split = match TrafficSplit::new(listener, stable, config, ctx) {
+        Ok(split) => split,
+        Err(e) => {
+            event!(Level::WARN, "Not splitting listener {listener}. {e}");
+            return None;
+        }
+    };
+    match store {
+        Some(store) if config.database => {
+            split.load_stored(store).await;
+            split.store = Some(store.clone());
+        }
+        None if config.database => event!(
+            Level::WARN,
+            "Listener {listener} wants its traffic split stored, but no database is connected"
+        ),
+        _ => {}
+    }
+    Some(Arc::new(split))
+}
+
+/// Reads the stored split on an interval, picking up changes made through other balancers
+pub(crate) async fn reload_split(split: Arc<TrafficSplit>, config: SplitConfig) {
+    let Some(store) = split.store.clone() else {
+        return;
+    };
+    let mut ticks = interval(config.reload_interval());
+    // the first tick completes straight away
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        split.load_stored(&store).await;
+    }
+}
+
+/// Every listener's split, for the admin listener to show and change
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrafficSplits {
+    splits: Arc<RwLock<BTreeMap<SocketAddr, Arc<TrafficSplit>>>>,
+}
+
+impl TrafficSplits {
+    pub fn register(&self, split: Arc<TrafficSplit>) {
+        if let Ok(mut splits) = self.splits.write() {
+            splits.insert(split.listener(), split);
+        }
+    }
+
+    pub fn get(&self, listener: SocketAddr) -> Option<Arc<TrafficSplit>> {
+        self.splits.read().ok()?.get(&listener).cloned()
+    }
+
+    pub fn statuses(&self) -> Vec<SplitStatus> {
+        self.splits
+            .read()
+            .map(|splits| splits.values().map(|split| split.status()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl MetricSource for TrafficSplit {
+    fn collect(&self, metrics: &mut MetricsWriter) {
+        let listener = self.listener.to_string();
+
+        metrics.gauge(
+            "lb_split_percentage",
+            "Share of the listener's traffic sent to the canary pool",
+            &[
+                ("listener", listener.as_str()),
+                ("canary", self.canary.name.as_str()),
+            ],
+            self.percentage(),
+        );
+        for (pool, total) in [
+            (&self.stable, &self.stable_total),
+            (&self.canary, &self.canary_total),
+        ] {
+            metrics.counter(
+                "lb_split_total",
+                "Connections and requests the listener's split sent to each pool",
+                &[
+                    ("listener", listener.as_str()),
+                    ("pool", pool.name.as_str()),
+                ],
+                total.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::config::PoolConfig;
+
+    use super::*;
+
+    const LISTENER: &str = "127.0.0.1:8080";
+
+    fn context() -> ProxyContext {
+        let pool = |name: &str, worker: &str| {
+            WorkerPool::new(
+                name.into(),
+                vec![worker.parse().unwrap()],
+                &PoolConfig::default(),
+            )
+            .unwrap()
+        };
+        ProxyContext::for_pools(vec![
+            pool("stable", "127.0.0.1:9001"),
+            pool("canary", "127.0.0.1:9002"),
+        ])
+    }
+
+    fn config(canary: &str, percentage: f64) -> SplitConfig {
+        SplitConfig {
+            canary: canary.into(),
+            percentage,
+            database: false,
+            reload_interval_secs: 30,
+        }
+    }
+
+    fn split(percentage: f64) -> TrafficSplit {
+        TrafficSplit::new(
+            LISTENER.parse().unwrap(),
+            "stable",
+            &config("canary", percentage),
+            &context(),
+        )
+        .unwrap()
+    }
+
+    fn canary_picks(split: &TrafficSplit, picks: usize) -> usize {
+        (0..picks).filter(|_| split.pick().name == "canary").count()
+    }
+
+    #[tokio::test]
+    async fn rejects_out_of_range_percentages() {
+        let ctx = context();
+        for percentage in [-1.0, 100.5, f64::NAN, f64::INFINITY] {
+            let split = TrafficSplit::new(
+                LISTENER.parse().unwrap(),
+                "stable",
+                &config("canary", percentage),
+                &ctx,
+            );
+            assert!(matches!(split, Err(LoadBalancerError::InvalidSplit { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_split_when_moved_out_of_range() {
+        let split = split(10.0);
+        for percentage in [-0.1, 101.0, f64::NAN] {
+            assert!(split.set_percentage(percentage).await.is_err());
+            assert_eq!(split.percentage(), 10.0);
+        }
+        split.set_percentage(100.0).await.unwrap();
+        assert_eq!(split.percentage(), 100.0);
+        split.set_percentage(0.0).await.unwrap();
+        assert_eq!(split.percentage(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn sends_the_configured_share_to_the_canary() {
+        let picks = 20_000;
+        let canary = canary_picks(&split(25.0), picks);
+        // a quarter, give or take well beyond the binomial spread
+        assert!((4_500..=5_500).contains(&canary), "{canary} canary picks");
+
+        assert_eq!(canary_picks(&split(0.0), picks), 0);
+        assert_eq!(canary_picks(&split(100.0), picks), picks);
+    }
+
+    #[tokio::test]
+    async fn counts_the_traffic_sent_to_each_pool() {
+        let split = split(50.0);
+        let canary = canary_picks(&split, 1_000) as u64;
+        assert_eq!(split.canary_total.load(Ordering::Relaxed), canary);
+        assert_eq!(split.stable_total.load(Ordering::Relaxed), 1_000 - canary);
+    }
+
+    #[tokio::test]
+    async fn the_canary_must_be_another_pool() {
+        let split = TrafficSplit::new(
+            LISTENER.parse().unwrap(),
+            "stable",
+            &config("stable", 10.0),
+            &context(),
+        );
+        assert!(matches!(split, Err(LoadBalancerError::InvalidSplit { .. })));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_stable_pool_without_a_canary_pool() {
+        let ctx = context();
+        let listener = LISTENER.parse().unwrap();
+        let missing = config("missing", 50.0);
+        assert!(load_split(listener, "stable", &missing, &ctx, None)
+            .await
+            .is_none());
+
+        let present = config("canary", 50.0);
+        let split = load_split(listener, "stable", &present, &ctx, None).await;
+        assert_eq!(split.unwrap().status().canary, "canary");
+    }
+}
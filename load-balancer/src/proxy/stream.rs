@@ -0,0 +1,151 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Any byte stream the proxy can splice, plain TCP or TLS
+pub(crate) trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProxyStream for T {}
+
+pub(crate) type BoxedStream = Box<dyn ProxyStream>;
+
+/// Which end of a spliced connection failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Side {
+    Client,
+    Worker,
+}
+
+/// Copies both ways until both sides have closed, returning the bytes sent to the worker
+/// and to the client. A failure names the side whose I/O failed, so a client hanging up
+/// is not held against the worker
+pub(crate) async fn splice<C, W>(
+    client: &mut C,
+    worker: &mut W,
+) -> std::result::Result<(u64, u64), (Side, io::Error)>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    W: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut client = Tracked::new(client);
+    let mut worker = Tracked::new(worker);
+    copy_bidirectional(&mut client, &mut worker)
+        .await
+        .map_err(|e| {
+            let side = if client.failed {
+                Side::Client
+            } else {
+                Side::Worker
+            };
+            (side, e)
+        })
+}
+
+/// Remembers whether any read or write on the stream failed
+struct Tracked<'a, S: ?Sized> {
+    inner: &'a mut S,
+    failed: bool,
+}
+
+impl<'a, S: ?Sized> Tracked<'a, S> {
+    fn new(inner: &'a mut S) -> Self {
+        Self {
+            inner,
+            failed: false,
+        }
+    }
+
+    fn track<T>(&mut self, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(_)) = &poll {
+            self.failed = true;
+        }
+        poll
+    }
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        self.track(poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        self.track(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut *self.inner).poll_flush(cx);
+        self.track(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut *self.inner).poll_shutdown(cx);
+        self.track(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_both_directions() {
+        let (mut client, mut client_peer) = duplex(64);
+        let (mut worker, mut worker_peer) = duplex(64);
+        let spliced = tokio::spawn(async move { splice(&mut client, &mut worker).await });
+
+        client_peer.write_all(b"request").await.unwrap();
+        let mut request = [0u8; 7];
+        worker_peer.read_exact(&mut request).await.unwrap();
+        worker_peer.write_all(b"response").await.unwrap();
+        let mut response = [0u8; 8];
+        client_peer.read_exact(&mut response).await.unwrap();
+        drop(client_peer);
+        drop(worker_peer);
+
+        assert_eq!(spliced.await.unwrap().unwrap(), (7, 8));
+    }
+
+    #[tokio::test]
+    async fn blames_a_client_that_went_away() {
+        let (mut client, client_peer) = duplex(64);
+        let (mut worker, mut worker_peer) = duplex(64);
+        let spliced = tokio::spawn(async move { splice(&mut client, &mut worker).await });
+
+        drop(client_peer);
+        worker_peer.write_all(b"response").await.unwrap();
+
+        let (side, _) = spliced.await.unwrap().unwrap_err();
+        assert_eq!(side, Side::Client);
+    }
+
+    #[tokio::test]
+    async fn blames_a_worker_that_went_away() {
+        let (mut client, mut client_peer) = duplex(64);
+        let (mut worker, worker_peer) = duplex(64);
+        let spliced = tokio::spawn(async move { splice(&mut client, &mut worker).await });
+
+        drop(worker_peer);
+        client_peer.write_all(b"request").await.unwrap();
+
+        let (side, _) = spliced.await.unwrap().unwrap_err();
+        assert_eq!(side, Side::Worker);
+    }
+}
@@ -0,0 +1,360 @@
+use std::{fmt, net::SocketAddr, sync::Arc, time::Instant};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result, WorkerPhase},
+    utils::{
+        access_log::{duration_ms, AccessLogRecord, TerminationReason},
+        config::ListenerConfig,
+    },
+};
+
+use super::{
+    acl::Acl,
+    listener::accept,
+    load_balancer::ProxyContext,
+    pool::WorkerPool,
+    proxy_protocol::{read_header, ProxiedConnection},
+    rate_limit::RateLimiter,
+    sni::SniRouter,
+    split::TrafficSplit,
+    stream::{splice, BoxedStream, Side},
+};
+
+/// What a listener does with a connection before splicing it to a worker
+#[derive(Clone)]
+pub(crate) enum ListenerMode {
+    Plain,
+    TerminateTls(TlsAcceptor),
+    Passthrough(Arc<SniRouter>),
+}
+
+impl fmt::Display for ListenerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain => write!(f, "plain"),
+            Self::TerminateTls(_) => write!(f, "tls"),
+            Self::Passthrough(_) => write!(f, "tls passthrough"),
+        }
+    }
+}
+
+/// Accepts connections and splices each one to a worker from the listener's pool,
+/// terminating TLS first when the listener has a certificate, or picking the pool by SNI
+/// when it passes TLS through
+pub(crate) async fn serve_tcp(
+    listener: TcpListener,
+    config: ListenerConfig,
+    mode: ListenerMode,
+    limiter: Option<Arc<RateLimiter>>,
+    acl: Arc<Acl>,
+    split: Option<Arc<TrafficSplit>>,
+    ctx: ProxyContext,
+) {
+    let Some(pool) = ctx.pool(&config.pool).cloned() else {
+        event!(
+            Level::ERROR,
+            "Listener {} references unknown pool {}",
+            config.address,
+            config.pool
+        );
+        return;
+    };
+    let config = Arc::new(config);
+
+    loop {
+        let (inbound, client_addr) = accept(&listener).await;
+        // dropping the stream closes it before anything is read
+        if !acl.permits(client_addr.ip()) {
+            event!(Level::DEBUG, "ACL denied {client_addr}");
+            ctx.report(AccessLogRecord::new(
+                config.address,
+                client_addr,
+                TerminationReason::Denied,
+            ));
+            continue;
+        }
+
+        let connection = Connection {
+            client_addr,
+            local_addr: inbound.local_addr().unwrap_or(config.address),
+            accepted_at: Instant::now(),
+            config: config.clone(),
+            pool: pool.clone(),
+            split: split.clone(),
+            tls_passthrough: false,
+            limiter: limiter.clone(),
+            ctx: ctx.clone(),
+        };
+        tokio::spawn(connection.handle(inbound, mode.clone()));
+    }
+}
+
+struct Connection {
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    accepted_at: Instant,
+    config: Arc<ListenerConfig>,
+    pool: WorkerPool,
+    split: Option<Arc<TrafficSplit>>,
+    tls_passthrough: bool,
+    limiter: Option<Arc<RateLimiter>>,
+    ctx: ProxyContext,
+}
+
+impl Connection {
+    async fn handle(mut self, mut inbound: TcpStream, mode: ListenerMode) {
+        if self.config.accept_proxy_protocol {
+            match read_header(&mut inbound).await {
+                Ok(Some(proxied)) => {
+                    self.client_addr = proxied.source;
+                    self.local_addr = proxied.destination;
+                }
+                Ok(None) => {}
+                Err(reason) => {
+                    let e = LoadBalancerError::ProxyProtocol {
+                        client: self.client_addr,
+                        reason,
+                    };
+                    event!(Level::WARN, "{e}");
+                    let mut record = AccessLogRecord::new(
+                        self.config.address,
+                        self.client_addr,
+                        TerminationReason::InvalidProxyHeader,
+                    );
+                    record.error = Some(e.to_string());
+                    self.finish(record);
+                    return;
+                }
+            }
+        }
+
+        if let Some(limiter) = &self.limiter {
+            if limiter.check_client(self.client_addr.ip()).is_err() {
+                event!(Level::DEBUG, "Rate limited {}", self.client_addr);
+                let record = AccessLogRecord::new(
+                    self.config.address,
+                    self.client_addr,
+                    TerminationReason::RateLimited,
+                );
+                self.finish(record);
+                return;
+            }
+        }
+
+        // held until the connection closes
+        let Some(_permit) = self.ctx.admit(true).await else {
+            event!(
+                Level::WARN,
+                "At max connections, closing {}",
+                self.client_addr
+            );
+            let record = AccessLogRecord::new(
+                self.config.address,
+                self.client_addr,
+                TerminationReason::OverCapacity,
+            );
+            self.finish(record);
+            return;
+        };
+
+        let mut record = AccessLogRecord::new(
+            self.config.address,
+            self.client_addr,
+            TerminationReason::Completed,
+        );
+        record.pool = Some(self.pool.name.clone());
+
+        let mut routed = false;
+        let inbound: BoxedStream = match mode {
+            ListenerMode::Plain => Box::new(inbound),
+            ListenerMode::TerminateTls(acceptor) => match acceptor.accept(inbound).await {
+                Ok(tls_stream) => {
+                    record.server_name = tls_stream.get_ref().1.server_name().map(String::from);
+                    Box::new(tls_stream)
+                }
+                Err(source) => {
+                    let e = LoadBalancerError::TlsHandshake {
+                        client: self.client_addr,
+                        source,
+                    };
+                    event!(Level::WARN, "{e}");
+                    record.termination = TerminationReason::TlsHandshakeFailed;
+                    record.error = Some(e.to_string());
+                    self.finish(record);
+                    return;
+                }
+            },
+            ListenerMode::Passthrough(router) => match router.peek_server_name(&inbound).await {
+                Ok(server_name) => {
+                    if let Some(pool) = router.route(server_name.as_deref()) {
+                        self.pool = pool.clone();
+                        routed = true;
+                    }
+                    record.server_name = server_name;
+                    self.tls_passthrough = true;
+                    Box::new(inbound)
+                }
+                Err(reason) => {
+                    let e = LoadBalancerError::ClientHello {
+                        client: self.client_addr,
+                        reason,
+                    };
+                    event!(Level::WARN, "{e}");
+                    record.termination = TerminationReason::InvalidClientHello;
+                    record.error = Some(e.to_string());
+                    self.finish(record);
+                    return;
+                }
+            },
+        };
+
+        // the split only divides what would have gone to the listener's pool
+        if let (false, Some(split)) = (routed, &self.split) {
+            self.pool = split.pick().clone();
+        }
+        record.pool = Some(self.pool.name.clone());
+        self.proxy(inbound, record).await;
+    }
+
+    async fn proxy(self, mut inbound: BoxedStream, mut record: AccessLogRecord) {
+        // held until the connection closes
+        let Some(in_flight) = self.pool.concurrency.acquire() else {
+            event!(
+                Level::WARN,
+                "Pool {} over its concurrency limit, closing {}",
+                self.pool.name,
+                self.client_addr
+            );
+            record.termination = TerminationReason::Shed;
+            self.finish(record);
+            return;
+        };
+
+        let workers = &self.pool.workers;
+        let mut outbound_addr = match self.pool.select(&mut record, true).await {
+            Ok(worker) => worker,
+            Err(TerminationReason::NoHealthyWorkers) => {
+                event!(
+                    Level::ERROR,
+                    "workers all unhealthy {:?}",
+                    workers.read().await.workers_health
+                );
+                record.termination = TerminationReason::NoHealthyWorkers;
+                self.finish(record);
+                event!(Level::TRACE, "run health check");
+                self.ctx.health_check(&self.pool);
+                return;
+            }
+            Err(termination) => {
+                event!(
+                    Level::WARN,
+                    "Workers in pool {} at capacity, closing {}",
+                    self.pool.name,
+                    self.client_addr
+                );
+                record.termination = termination;
+                self.finish(record);
+                return;
+            }
+        };
+
+        let mut connect_start = Instant::now();
+        let mut connection = self.connect(*outbound_addr).await;
+
+        if let Err(e) = &connection {
+            event!(Level::WARN, "{e}");
+            self.pool.record_outcome(*outbound_addr, false);
+            workers.write().await.decrease_worker_count(*outbound_addr);
+            self.ctx.health_check(&self.pool);
+
+            // queued like the first attempt, and the algorithm it was picked with recorded
+            outbound_addr = if let Ok(addr) = self.pool.select(&mut record, true).await {
+                addr
+            } else {
+                event!(
+                    Level::ERROR,
+                    "Connection failed healthy server not found"
+                );
+                record.worker = Some(*outbound_addr);
+                record.termination = TerminationReason::UpstreamConnectFailed;
+                record.error = Some(e.to_string());
+                self.finish(record);
+                return;
+            };
+
+            event!(Level::INFO, "Second attempt sent to {}", outbound_addr);
+            // the latency is the attempt that counts, not the failed one
+            connect_start = Instant::now();
+            connection = self.connect(*outbound_addr).await;
+        }
+
+        record.worker = Some(*outbound_addr);
+        record.connect_latency_ms = Some(duration_ms(connect_start.elapsed()));
+        in_flight.observe(connect_start.elapsed(), connection.is_ok());
+
+        match connection {
+            Ok(mut outbound) => {
+                match splice(&mut inbound, &mut outbound).await {
+                    Ok((bytes_in, bytes_out)) => {
+                        record.bytes_in = bytes_in;
+                        record.bytes_out = bytes_out;
+                    }
+                    Err((Side::Client, source)) => {
+                        let e = LoadBalancerError::ClientTransfer {
+                            client: self.client_addr,
+                            source,
+                        };
+                        event!(Level::DEBUG, "{e}");
+                        record.termination = TerminationReason::ClientAborted;
+                        record.error = Some(e.to_string());
+                    }
+                    Err((Side::Worker, e)) => {
+                        let e =
+                            LoadBalancerError::worker(*outbound_addr, WorkerPhase::Transfer)(e);
+                        event!(Level::ERROR, "{e}");
+                        record.termination = TerminationReason::TransferError;
+                        record.error = Some(e.to_string());
+                    }
+                }
+                workers.write().await.decrease_worker_count(*outbound_addr);
+                event!(
+                    Level::DEBUG,
+                    "Response Sent: counts: {:?}",
+                    workers.read().await.current_worker_loads
+                );
+                self.finish(record);
+            }
+            Err(e) => {
+                event!(Level::ERROR, "Request failed. {e}");
+                self.ctx.health_check(&self.pool);
+                workers.write().await.decrease_worker_count(*outbound_addr);
+                record.termination = TerminationReason::UpstreamConnectFailed;
+                record.error = Some(e.to_string());
+                self.finish(record);
+            }
+        }
+    }
+
+    /// Passthrough connections already carry the client's TLS, so the pool's upstream TLS
+    /// settings only apply to its health checks
+    async fn connect(&self, worker: SocketAddr) -> Result<BoxedStream> {
+        let client = Some(ProxiedConnection {
+            source: self.client_addr,
+            destination: self.local_addr,
+        });
+        if self.tls_passthrough {
+            let stream = self.pool.connect_tcp(worker, client).await?;
+            return Ok(Box::new(stream));
+        }
+        self.pool.connect(worker, client).await
+    }
+
+    fn finish(&self, mut record: AccessLogRecord) {
+        record.duration_ms = duration_ms(self.accepted_at.elapsed());
+        self.ctx.report(record);
+    }
+}
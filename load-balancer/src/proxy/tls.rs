@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use tokio::time::interval;
+use tokio_rustls::TlsAcceptor;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::config::{CertificateConfig, TlsListenerConfig},
+};
+
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Builds the acceptor for a terminating listener and starts watching its certificate files.
+/// `alpn_protocols` are offered in order of preference, none leaves ALPN out of the handshake
+pub(crate) fn tls_acceptor(
+    config: &TlsListenerConfig,
+    alpn_protocols: &[&[u8]],
+) -> Result<TlsAcceptor> {
+    let resolver = Arc::new(SniCertResolver::load(config)?);
+
+    let mut server_config = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+    tokio::spawn(watch_certificates(resolver, config.clone()));
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[derive(Debug, Default)]
+struct CertStore {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+    fn load(config: &TlsListenerConfig) -> Result<Self> {
+        let provider = crypto_provider();
+        let mut store = Self::default();
+        let mut has_unnamed_default = false;
+
+        for cert_config in &config.certificates {
+            let certified_key = Arc::new(load_certified_key(&provider, cert_config)?);
+
+            for name in &cert_config.server_names {
+                store
+                    .by_name
+                    .insert(name.to_ascii_lowercase(), certified_key.clone());
+            }
+
+            // the first unnamed certificate wins, falling back to the first certificate
+            if cert_config.server_names.is_empty() && !has_unnamed_default {
+                store.default = Some(certified_key);
+                has_unnamed_default = true;
+            } else if store.default.is_none() {
+                store.default = Some(certified_key);
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn resolve(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(server_name) = server_name.map(|name| name.to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+
+        if let Some(certified_key) = self.by_name.get(&server_name) {
+            return Some(certified_key.clone());
+        }
+
+        server_name
+            .split_once('.')
+            .and_then(|(_, parent)| self.by_name.get(&format!("*.{parent}")))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Picks a certificate by SNI. The store is swapped in place when the files change
+#[derive(Debug)]
+struct SniCertResolver {
+    store: RwLock<CertStore>,
+}
+
+impl SniCertResolver {
+    fn load(config: &TlsListenerConfig) -> Result<Self> {
+        Ok(Self {
+            store: RwLock::new(CertStore::load(config)?),
+        })
+    }
+
+    fn reload(&self, config: &TlsListenerConfig) -> Result<()> {
+        let store = CertStore::load(config)?;
+        if let Ok(mut current) = self.store.write() {
+            *current = store;
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.store
+            .read()
+            .ok()
+            .and_then(|store| store.resolve(client_hello.server_name()))
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    config: &CertificateConfig,
+) -> Result<CertifiedKey> {
+    let certs = load_certs(&config.cert)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|e| {
+        LoadBalancerError::TlsFile {
+            kind: "private key",
+            path: config.key.clone(),
+            reason: e.to_string(),
+        }
+    })?;
+
+    let signing_key = provider.key_provider.load_private_key(key)?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let tls_file_error = |reason: String| LoadBalancerError::TlsFile {
+        kind: "certificate",
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| tls_file_error(e.to_string()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| tls_file_error(e.to_string()))?;
+
+    if certs.is_empty() {
+        return Err(tls_file_error("no certificates found".to_string()));
+    }
+
+    Ok(certs)
+}
+
+/// Polls modification times and reloads every certificate of the listener when one changes.
+/// A reload that fails keeps serving the previous certificates
+async fn watch_certificates(resolver: Arc<SniCertResolver>, config: TlsListenerConfig) {
+    let mut last_modified = certificate_mtimes(&config);
+    let mut reload_interval = interval(config.reload_interval());
+    reload_interval.tick().await;
+
+    loop {
+        reload_interval.tick().await;
+
+        let modified = certificate_mtimes(&config);
+        if modified == last_modified {
+            continue;
+        }
+
+        match resolver.reload(&config) {
+            Ok(()) => {
+                event!(Level::INFO, "Reloaded tls certificates");
+                last_modified = modified;
+            }
+            Err(e) => event!(
+                Level::ERROR,
+                "Keeping previous tls certificates. Error: {e}"
+            ),
+        }
+    }
+}
+
+fn certificate_mtimes(config: &TlsListenerConfig) -> Vec<Option<SystemTime>> {
+    config
+        .certificates
+        .iter()
+        .flat_map(|cert_config| [&cert_config.cert, &cert_config.key])
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tempfile::TempDir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// A self-signed certificate for `names`, written as PEM files into `dir`
+    fn write_certificate(
+        dir: &TempDir,
+        file: &str,
+        names: &[&str],
+    ) -> (CertificateConfig, CertificateDer<'static>) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names.clone()).unwrap();
+        let cert = dir.path().join(format!("{file}.pem"));
+        let key = dir.path().join(format!("{file}.key"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        let config = CertificateConfig {
+            cert,
+            key,
+            server_names: names,
+        };
+        (config, generated.cert.der().clone())
+    }
+
+    fn listener_config(certificates: Vec<CertificateConfig>) -> TlsListenerConfig {
+        TlsListenerConfig {
+            certificates,
+            reload_interval_secs: 1,
+        }
+    }
+
+    /// Serves one greeting per accepted connection behind the acceptor
+    async fn serve(acceptor: TlsAcceptor) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let _ = stream.write_all(b"hello").await;
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    /// Connects with `server_name` as SNI, trusting `roots`, and returns the certificate
+    /// the listener presented
+    async fn handshake(
+        address: std::net::SocketAddr,
+        server_name: &str,
+        roots: &[CertificateDer<'static>],
+    ) -> CertificateDer<'static> {
+        let mut root_store = RootCertStore::empty();
+        for root in roots {
+            root_store.add(root.clone()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+
+        let mut greeting = Vec::new();
+        stream.read_to_end(&mut greeting).await.unwrap();
+        assert_eq!(greeting, b"hello");
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn terminates_tls() {
+        let dir = TempDir::new().unwrap();
+        let (cert, der) = write_certificate(&dir, "localhost", &["localhost"]);
+        let acceptor = tls_acceptor(&listener_config(vec![cert]), &[]).unwrap();
+        let address = serve(acceptor).await;
+
+        assert_eq!(
+            handshake(address, "localhost", std::slice::from_ref(&der)).await,
+            der
+        );
+    }
+
+    #[tokio::test]
+    async fn picks_certificate_by_sni() {
+        let dir = TempDir::new().unwrap();
+        let (api, api_der) = write_certificate(&dir, "api", &["api.example.com"]);
+        let (web, web_der) = write_certificate(&dir, "web", &["*.web.example.com"]);
+        // served for names nothing else matches
+        let (mut fallback, fallback_der) = write_certificate(&dir, "fallback", &["other.test"]);
+        fallback.server_names.clear();
+        let config = listener_config(vec![api, web, fallback]);
+        let acceptor = tls_acceptor(&config, &[]).unwrap();
+        let address = serve(acceptor).await;
+        let roots = [api_der.clone(), web_der.clone(), fallback_der.clone()];
+
+        assert_eq!(handshake(address, "api.example.com", &roots).await, api_der);
+        assert_eq!(
+            handshake(address, "www.web.example.com", &roots).await,
+            web_der
+        );
+        assert_eq!(handshake(address, "other.test", &roots).await, fallback_der);
+    }
+
+    #[test]
+    fn resolves_names_wildcards_and_the_default() {
+        let dir = TempDir::new().unwrap();
+        let (api, _) = write_certificate(&dir, "api", &["api.example.com"]);
+        let (web, _) = write_certificate(&dir, "web", &["*.web.example.com"]);
+        let (fallback, _) = write_certificate(&dir, "fallback", &[]);
+        let store = CertStore::load(&listener_config(vec![api, web, fallback])).unwrap();
+
+        let served = |name: Option<&str>| store.resolve(name).unwrap().cert[0].clone();
+        let (api, web, fallback) = (
+            served(Some("API.example.com")),
+            served(Some("www.web.example.com")),
+            served(None),
+        );
+        assert_ne!(api, web);
+        assert_ne!(api, fallback);
+        assert_ne!(web, fallback);
+        // a wildcard covers a single label only
+        assert_eq!(served(Some("a.b.web.example.com")), fallback);
+        assert_eq!(served(Some("unknown.example.org")), fallback);
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_certificates() {
+        let dir = TempDir::new().unwrap();
+        let (cert, old) = write_certificate(&dir, "localhost", &["localhost"]);
+        let acceptor = tls_acceptor(&listener_config(vec![cert]), &[]).unwrap();
+        let address = serve(acceptor).await;
+        assert_eq!(
+            handshake(address, "localhost", std::slice::from_ref(&old)).await,
+            old
+        );
+
+        let (_, new) = write_certificate(&dir, "localhost", &["localhost"]);
+        let roots = [old.clone(), new.clone()];
+        let mut served = old.clone();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            served = handshake(address, "localhost", &roots).await;
+            if served == new {
+                break;
+            }
+        }
+        assert_eq!(served, new);
+    }
+
+    #[test]
+    fn rejects_a_missing_certificate() {
+        let config = listener_config(vec![CertificateConfig {
+            cert: PathBuf::from("/nonexistent/cert.pem"),
+            key: PathBuf::from("/nonexistent/cert.key"),
+            server_names: Vec::new(),
+        }]);
+        assert!(matches!(
+            SniCertResolver::load(&config),
+            Err(LoadBalancerError::TlsFile { .. })
+        ));
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, select, sync::Notify, time::timeout};
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, WorkerPhase},
+    utils::{
+        access_log::{duration_ms, AccessLogRecord, TerminationReason},
+        config::ListenerConfig,
+    },
+};
+
+use super::{
+    acl::Acl, limits::ConnectionPermit, listener::ACCEPT_ERROR_BACKOFF,
+    load_balancer::ProxyContext, pool::WorkerPool, rate_limit::RateLimiter,
+};
+
+/// Largest payload a UDP datagram can carry
+const MAX_DATAGRAM: usize = 65535;
+/// Datagrams held for a client while its flow is set up, later ones are dropped
+const MAX_PENDING_DATAGRAMS: usize = 32;
+
+type Flows = Arc<Mutex<HashMap<SocketAddr, FlowEntry>>>;
+
+enum FlowEntry {
+    /// A worker is being picked and connected to, holding the datagrams that arrive
+    Opening(Vec<Vec<u8>>),
+    Open(Arc<Flow>),
+}
+
+/// Receives datagrams and relays each client's to the worker its flow was assigned, sending
+/// the worker's replies back from the listener's address. New flows are set up on their own
+/// task, so a slow worker or a full pool never holds up other clients' datagrams
+pub(crate) async fn serve_udp(
+    socket: UdpSocket,
+    config: ListenerConfig,
+    limiter: Option<Arc<RateLimiter>>,
+    acl: Arc<Acl>,
+    ctx: ProxyContext,
+) {
+    let Some(pool) = ctx.pool(&config.pool).cloned() else {
+        event!(
+            Level::ERROR,
+            "Listener {} references unknown pool {}",
+            config.address,
+            config.pool
+        );
+        return;
+    };
+    let listener = Arc::new(Listener {
+        socket: Arc::new(socket),
+        address: config.address,
+        idle_timeout: config.udp.unwrap_or_default().flow_idle_timeout(),
+        flows: Flows::default(),
+        pool,
+        ctx,
+    });
+    listener.serve(limiter, acl).await;
+}
+
+struct Listener {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+    idle_timeout: Duration,
+    flows: Flows,
+    pool: WorkerPool,
+    ctx: ProxyContext,
+}
+
+impl Listener {
+    async fn serve(self: Arc<Self>, limiter: Option<Arc<RateLimiter>>, acl: Arc<Acl>) {
+        let mut datagram = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, client_addr) = match self.socket.recv_from(&mut datagram).await {
+                Ok(received) => received,
+                Err(e) => {
+                    event!(Level::WARN, "{}", LoadBalancerError::Accept(e));
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            if !acl.permits(client_addr.ip()) {
+                event!(Level::TRACE, "ACL denied datagram from {client_addr}");
+                continue;
+            }
+            // over the limit datagrams are dropped, as the network would
+            if let Some(limiter) = &limiter {
+                if limiter.check_client(client_addr.ip()).is_err() {
+                    event!(Level::TRACE, "Rate limited datagram from {client_addr}");
+                    continue;
+                }
+            }
+
+            if let Some(flow) = self.flow(client_addr, &datagram[..len]) {
+                flow.send(&datagram[..len]).await;
+            }
+        }
+    }
+
+    /// The client's flow to send the datagram on. When it has none, or its worker has since
+    /// become unhealthy or had its circuit opened, the datagram is held and a new flow set up
+    /// on its own task
+    fn flow(self: &Arc<Self>, client_addr: SocketAddr, datagram: &[u8]) -> Option<Arc<Flow>> {
+        let mut flows = self.flows.lock().ok()?;
+        match flows.get_mut(&client_addr) {
+            Some(FlowEntry::Open(flow)) if self.selectable(flow.worker) => {
+                return Some(flow.clone())
+            }
+            Some(FlowEntry::Open(flow)) => {
+                event!(
+                    Level::INFO,
+                    "Moving UDP flow from {client_addr} off worker {}, unhealthy or its circuit open",
+                    flow.worker
+                );
+                flow.close(format!("worker {} is no longer selectable", flow.worker));
+            }
+            Some(FlowEntry::Opening(pending)) => {
+                if pending.len() < MAX_PENDING_DATAGRAMS {
+                    pending.push(datagram.to_vec());
+                }
+                return None;
+            }
+            None => {}
+        }
+
+        flows.insert(client_addr, FlowEntry::Opening(vec![datagram.to_vec()]));
+        tokio::spawn(self.clone().open(client_addr));
+        None
+    }
+
+    /// Whether the pool could still pick the worker. Checked without waiting on the workers'
+    /// lock, so while it is taken the flow stays put until a later datagram
+    fn selectable(&self, worker: SocketAddr) -> bool {
+        self.pool
+            .workers
+            .try_read()
+            .map_or(true, |workers| workers.is_selectable(worker))
+    }
+
+    /// Sets up the client's flow and sends the datagrams held for it. When no flow can be
+    /// opened they are dropped, and the client's next datagram tries again
+    async fn open(self: Arc<Self>, client_addr: SocketAddr) {
+        let Some((flow, record)) = self.new_flow(client_addr).await else {
+            if let Ok(mut flows) = self.flows.lock() {
+                flows.remove(&client_addr);
+            }
+            return;
+        };
+
+        // held datagrams go first, so the flow only opens to new ones once they are sent
+        loop {
+            let pending = {
+                let Ok(mut flows) = self.flows.lock() else {
+                    return;
+                };
+                match flows.get_mut(&client_addr) {
+                    Some(FlowEntry::Opening(pending)) if !pending.is_empty() => {
+                        std::mem::take(pending)
+                    }
+                    _ => {
+                        flows.insert(client_addr, FlowEntry::Open(flow.clone()));
+                        break;
+                    }
+                }
+            };
+            for datagram in pending {
+                flow.send(&datagram).await;
+            }
+        }
+        event!(
+            Level::DEBUG,
+            "New UDP flow from {client_addr} to {}",
+            flow.worker
+        );
+        tokio::spawn(self.relay(client_addr, flow, record));
+    }
+
+    /// Picks a worker and connects a socket to it, with the record to log the flow under
+    async fn new_flow(&self, client_addr: SocketAddr) -> Option<(Arc<Flow>, AccessLogRecord)> {
+        let started = Instant::now();
+        let mut record =
+            AccessLogRecord::new(self.address, client_addr, TerminationReason::Completed);
+        record.pool = Some(self.pool.name.clone());
+
+        // datagrams are never queued, that would hold up every other client's
+        let Some(permit) = self.ctx.admit(false).await else {
+            record.termination = TerminationReason::OverCapacity;
+            self.ctx.report(record);
+            return None;
+        };
+        let workers = &self.pool.workers;
+        let worker = match self.pool.select(&mut record, false).await {
+            Ok(worker) => *worker,
+            Err(termination) => {
+                record.termination = termination;
+                self.ctx.report(record);
+                if termination == TerminationReason::NoHealthyWorkers {
+                    event!(
+                        Level::ERROR,
+                        "workers all unhealthy {:?}",
+                        workers.read().await.workers_health
+                    );
+                    self.ctx.health_check(&self.pool);
+                }
+                return None;
+            }
+        };
+        record.worker = Some(worker);
+
+        let upstream = match connect(worker).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                let e = LoadBalancerError::worker(worker, WorkerPhase::Connect)(e);
+                event!(Level::WARN, "{e}");
+                workers.write().await.decrease_worker_count(worker);
+                record.termination = TerminationReason::UpstreamConnectFailed;
+                record.error = Some(e.to_string());
+                self.ctx.report(record);
+                return None;
+            }
+        };
+        record.connect_latency_ms = Some(duration_ms(started.elapsed()));
+
+        let flow = Arc::new(Flow {
+            worker,
+            upstream,
+            started,
+            last_active_ms: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            closed: Notify::new(),
+            close_reason: Mutex::new(None),
+            _permit: permit,
+        });
+        Some((flow, record))
+    }
+
+    /// Sends the worker's replies to the client until the flow goes idle or is closed, then
+    /// releases the worker and logs the flow
+    fn relay(
+        &self,
+        client_addr: SocketAddr,
+        flow: Arc<Flow>,
+        mut record: AccessLogRecord,
+    ) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let socket = self.socket.clone();
+        let idle_timeout = self.idle_timeout;
+        let flows = self.flows.clone();
+        let pool = self.pool.clone();
+        let ctx = self.ctx.clone();
+
+        async move {
+            let mut datagram = vec![0; MAX_DATAGRAM];
+            loop {
+                let received = select! {
+                    _ = flow.closed.notified() => break,
+                    received = timeout(idle_timeout, flow.upstream.recv(&mut datagram)) => received,
+                };
+                match received {
+                    Ok(Ok(len)) => {
+                        flow.touch();
+                        match socket.send_to(&datagram[..len], client_addr).await {
+                            Ok(sent) => {
+                                flow.bytes_out.fetch_add(sent as u64, Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                event!(Level::WARN, "Failed to reply to {client_addr}. {e}");
+                            }
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        // a connected socket reports the worker's ICMP port unreachable here
+                        let e = LoadBalancerError::worker(flow.worker, WorkerPhase::Transfer)(e);
+                        event!(Level::WARN, "{e}");
+                        flow.close(e.to_string());
+                        ctx.health_check(&pool);
+                        break;
+                    }
+                    Err(_) if flow.idle_for() >= idle_timeout => break,
+                    Err(_) => {}
+                }
+            }
+
+            if let Ok(mut flows) = flows.lock() {
+                if let Some(FlowEntry::Open(current)) = flows.get(&client_addr) {
+                    if Arc::ptr_eq(current, &flow) {
+                        flows.remove(&client_addr);
+                    }
+                }
+            }
+            pool.workers
+                .write()
+                .await
+                .decrease_worker_count(flow.worker);
+
+            record.duration_ms = duration_ms(flow.started.elapsed());
+            record.bytes_in = flow.bytes_in.load(Ordering::Relaxed);
+            record.bytes_out = flow.bytes_out.load(Ordering::Relaxed);
+            if let Some(reason) = flow.close_reason.lock().ok().and_then(|mut r| r.take()) {
+                record.termination = TerminationReason::TransferError;
+                record.error = Some(reason);
+            }
+            ctx.report(record);
+        }
+    }
+}
+
+/// Datagrams from one client address, all sent to the same worker over a socket of their
+/// own so replies can be told apart
+struct Flow {
+    worker: SocketAddr,
+    upstream: UdpSocket,
+    started: Instant,
+    /// Milliseconds after `started` a datagram last passed in either direction
+    last_active_ms: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    closed: Notify,
+    close_reason: Mutex<Option<String>>,
+    /// Counts the flow against the balancer wide connection limit
+    _permit: ConnectionPermit,
+}
+
+impl Flow {
+    async fn send(&self, datagram: &[u8]) {
+        self.touch();
+        match self.upstream.send(datagram).await {
+            Ok(sent) => {
+                self.bytes_in.fetch_add(sent as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                let e = LoadBalancerError::worker(self.worker, WorkerPhase::Transfer)(e);
+                event!(Level::WARN, "{e}");
+                self.close(e.to_string());
+            }
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_active_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_active)
+    }
+
+    fn close(&self, reason: String) {
+        if let Ok(mut close_reason) = self.close_reason.lock() {
+            close_reason.get_or_insert(reason);
+        }
+        self.closed.notify_one();
+    }
+}
+
+/// A socket of its own connected to the worker, so only its datagrams are received
+pub(crate) async fn connect(worker: SocketAddr) -> std::io::Result<UdpSocket> {
+    let unspecified = match worker.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(worker).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        proxy::load_balancer::LoadBalancerAlgorithm,
+        utils::config::{AclConfig, CircuitBreakerConfig, PoolConfig},
+    };
+
+    use super::*;
+
+    /// Answers every datagram with its own address followed by the datagram
+    async fn echo_worker() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut datagram = vec![0; MAX_DATAGRAM];
+            while let Ok((len, from)) = socket.recv_from(&mut datagram).await {
+                let reply = [format!("{addr} ").as_bytes(), &datagram[..len]].concat();
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+        addr
+    }
+
+    async fn listener(workers: Vec<SocketAddr>, idle_timeout: Duration) -> Arc<Listener> {
+        let config = PoolConfig {
+            algorithm: Some(LoadBalancerAlgorithm::RoundRobin),
+            circuit_breaker: Some(CircuitBreakerConfig {
+                consecutive_failures: 1,
+                open_secs: 60,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let pool = WorkerPool::new("dns".into(), workers, &config).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = Arc::new(Listener {
+            address: socket.local_addr().unwrap(),
+            socket: Arc::new(socket),
+            idle_timeout,
+            flows: Flows::default(),
+            ctx: ProxyContext::for_pools(vec![pool.clone()]),
+            pool,
+        });
+        let acl = Arc::new(Acl::new(listener.address, &AclConfig::default()));
+        tokio::spawn(listener.clone().serve(None, acl));
+        listener
+    }
+
+    /// Sends through the listener and returns which worker answered
+    async fn exchange(client: &UdpSocket, listener: &Listener, datagram: &str) -> SocketAddr {
+        client
+            .send_to(datagram.as_bytes(), listener.address)
+            .await
+            .unwrap();
+        let mut reply = vec![0; MAX_DATAGRAM];
+        let (len, from) = timeout(Duration::from_secs(2), client.recv_from(&mut reply))
+            .await
+            .expect("no reply")
+            .unwrap();
+        assert_eq!(from, listener.address);
+
+        let reply = String::from_utf8_lossy(&reply[..len]).into_owned();
+        let (worker, echoed) = reply.split_once(' ').unwrap();
+        assert_eq!(echoed, datagram);
+        worker.parse().unwrap()
+    }
+
+    fn open_flow(listener: &Listener, client: SocketAddr) -> Option<SocketAddr> {
+        match listener.flows.lock().unwrap().get(&client) {
+            Some(FlowEntry::Open(flow)) => Some(flow.worker),
+            _ => None,
+        }
+    }
+
+    async fn client() -> (UdpSocket, SocketAddr) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = client.local_addr().unwrap();
+        (client, addr)
+    }
+
+    #[tokio::test]
+    async fn pins_a_client_to_one_worker() {
+        let workers = vec![echo_worker().await, echo_worker().await];
+        let listener = listener(workers, Duration::from_secs(30)).await;
+        let (client, client_addr) = client().await;
+
+        let worker = exchange(&client, &listener, "first").await;
+        assert_eq!(open_flow(&listener, client_addr), Some(worker));
+        for datagram in ["second", "third", "fourth"] {
+            assert_eq!(exchange(&client, &listener, datagram).await, worker);
+        }
+        assert_eq!(listener.flows.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_each_client_its_own_flow() {
+        let workers = vec![echo_worker().await, echo_worker().await];
+        let listener = listener(workers, Duration::from_secs(30)).await;
+        let (first, first_addr) = client().await;
+        let (second, second_addr) = client().await;
+
+        let first_worker = exchange(&first, &listener, "a").await;
+        let second_worker = exchange(&second, &listener, "b").await;
+        // round robin puts the second flow on the other worker
+        assert_ne!(first_worker, second_worker);
+        assert_eq!(open_flow(&listener, first_addr), Some(first_worker));
+        assert_eq!(open_flow(&listener, second_addr), Some(second_worker));
+    }
+
+    #[tokio::test]
+    async fn forgets_idle_flows() {
+        let worker = echo_worker().await;
+        let listener = listener(vec![worker], Duration::from_millis(100)).await;
+        let (client, client_addr) = client().await;
+
+        exchange(&client, &listener, "hello").await;
+        assert!(open_flow(&listener, client_addr).is_some());
+
+        let released = async {
+            while !listener.flows.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        timeout(Duration::from_secs(3), released).await.unwrap();
+        let loads = listener
+            .pool
+            .workers
+            .read()
+            .await
+            .current_worker_loads
+            .clone();
+        assert!(loads.values().all(|load| *load == 0));
+
+        assert_eq!(exchange(&client, &listener, "again").await, worker);
+    }
+
+    #[tokio::test]
+    async fn moves_flows_off_an_unhealthy_worker() {
+        let workers = vec![echo_worker().await, echo_worker().await];
+        let listener = listener(workers, Duration::from_secs(30)).await;
+        let (client, _) = client().await;
+
+        let first = exchange(&client, &listener, "before").await;
+        {
+            let mut workers = listener.pool.workers.write().await;
+            let health = workers
+                .worker_addrs
+                .iter()
+                .map(|worker| (worker.clone(), **worker != first))
+                .collect();
+            workers.update_healthy_workers(health);
+        }
+
+        let moved = exchange(&client, &listener, "after").await;
+        assert_ne!(moved, first);
+    }
+
+    #[tokio::test]
+    async fn moves_flows_off_a_worker_with_an_open_circuit() {
+        let workers = vec![echo_worker().await, echo_worker().await];
+        let listener = listener(workers, Duration::from_secs(30)).await;
+        let (client, _) = client().await;
+
+        let first = exchange(&client, &listener, "before").await;
+        listener.pool.record_outcome(first, false);
+        assert!(!listener.pool.breakers.allows(first));
+
+        let moved = exchange(&client, &listener, "after").await;
+        assert_ne!(moved, first);
+    }
+}
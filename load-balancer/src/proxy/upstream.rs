@@ -0,0 +1,175 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    error::{LoadBalancerError, Result, WorkerPhase},
+    utils::config::UpstreamTlsConfig,
+};
+
+use super::{
+    stream::BoxedStream,
+    tls::{crypto_provider, load_certs},
+};
+
+pub(crate) async fn connect_worker(worker: SocketAddr) -> Result<TcpStream> {
+    TcpStream::connect(worker)
+        .await
+        .map_err(LoadBalancerError::worker(worker, WorkerPhase::Connect))
+}
+
+/// TLS settings for connections from the balancer to a pool's workers
+#[derive(Clone)]
+pub(crate) struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl std::fmt::Debug for UpstreamTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamTls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UpstreamTls {
+    /// `alpn_protocols` are offered to workers, none leaves ALPN out of the handshake
+    pub fn new(config: &UpstreamTlsConfig, alpn_protocols: &[&[u8]]) -> Result<Self> {
+        let provider = crypto_provider();
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if config.verify {
+            builder.with_root_certificates(root_store(config)?)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+        };
+
+        let mut client_config = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = load_certs(cert)?;
+                let key =
+                    PrivateKeyDer::from_pem_file(key).map_err(|e| LoadBalancerError::TlsFile {
+                        kind: "client key",
+                        path: key.clone(),
+                        reason: e.to_string(),
+                    })?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            (Some(path), None) | (None, Some(path)) => {
+                return Err(LoadBalancerError::TlsFile {
+                    kind: "client certificate",
+                    path: path.clone(),
+                    reason: "client_cert and client_key must be set together".to_string(),
+                })
+            }
+        };
+
+        client_config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+        let server_name = config
+            .server_name
+            .as_ref()
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .map_err(|_| LoadBalancerError::TlsServerName(name.clone()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, worker: SocketAddr, stream: TcpStream) -> Result<BoxedStream> {
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or(ServerName::IpAddress(worker.ip().into()));
+
+        let tls_stream = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .map_err(LoadBalancerError::worker(worker, WorkerPhase::TlsHandshake))?;
+
+        Ok(Box::new(tls_stream))
+    }
+}
+
+fn root_store(config: &UpstreamTlsConfig) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    match &config.ca_bundle {
+        Some(ca_bundle) => {
+            for cert in load_certs(ca_bundle)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    Ok(roots)
+}
+
+/// Used when `verify = false`. Handshake signatures are still checked, only the
+/// certificate chain and name are not
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -1,9 +1,10 @@
 use rand::prelude::*;
-use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
+use tokio::sync::Notify;
 use tracing::{event, Level};
 
-use super::load_balancer::LoadBalancerAlgorithm;
+use super::{circuit_breaker::CircuitBreakers, load_balancer::LoadBalancerAlgorithm};
 
 #[derive(Debug)]
 pub struct Workers {
@@ -12,16 +13,28 @@ pub struct Workers {
     pub current_worker: usize,
     pub current_worker_loads: HashMap<Arc<SocketAddr>, usize>,
     pub algorithm: LoadBalancerAlgorithm,
+    /// Set when the pool configures an algorithm, which turns off switching by load
+    pub pinned_algorithm: Option<LoadBalancerAlgorithm>,
+    /// Workers at this load are skipped until a connection to them finishes
+    pub max_load: Option<usize>,
+    /// Woken when a worker may have capacity again, for connections queued waiting on one
+    pub released: Arc<Notify>,
+    /// Workers whose circuit is open are skipped like unhealthy ones
+    pub breakers: Arc<CircuitBreakers>,
 }
 
 impl Workers {
-    pub fn new(raw_workers: Vec<String>) -> Self {
+    pub fn new(
+        worker_addresses: Vec<SocketAddr>,
+        pinned_algorithm: Option<LoadBalancerAlgorithm>,
+        max_load: Option<usize>,
+        breakers: Arc<CircuitBreakers>,
+    ) -> Self {
         let mut worker_addrs: Vec<Arc<SocketAddr>> = vec![];
         let mut workers_health_map = HashMap::new();
         let mut worker_loads_map = HashMap::new();
 
-        for addr in raw_workers {
-            let addr = SocketAddr::from_str(&addr).expect("Failed to parse url");
+        for addr in worker_addresses {
             let arc_addr = Arc::new(addr);
             worker_addrs.push(arc_addr.clone());
             workers_health_map.insert(arc_addr.clone(), true); // Optimistic health check
@@ -33,7 +46,13 @@ impl Workers {
             workers_health: workers_health_map,
             current_worker: 0,
             current_worker_loads: worker_loads_map,
-            algorithm: LoadBalancerAlgorithm::Random,
+            algorithm: pinned_algorithm
+                .clone()
+                .unwrap_or(LoadBalancerAlgorithm::Random),
+            pinned_algorithm,
+            max_load,
+            released: Arc::new(Notify::new()),
+            breakers,
         }
     }
 
@@ -42,7 +61,7 @@ impl Workers {
         let lb = self.algorithm.clone();
         event!(Level::INFO, "Current Algorithm: {:?}", lb);
 
-        let healthy_workers = self.get_healthy_workers();
+        let healthy_workers = self.get_available_workers();
         // NOTE guard for unwraps
         if healthy_workers.is_empty() {
             return None
@@ -55,43 +74,40 @@ impl Workers {
                 } else {
                     self.current_worker = (self.current_worker + 1) % healthy_workers.len();
                 }
-                let worker = healthy_workers.get(self.current_worker).unwrap().clone();
-                worker
+                healthy_workers.get(self.current_worker)?.clone()
             }
 
             LoadBalancerAlgorithm::Random => {
-                let healthy_workers = self.get_healthy_workers();
+                let healthy_workers = self.get_available_workers();
                 let num_workers = healthy_workers.len();
                 let mut rng = rand::thread_rng();
                 let rand_num = (num_workers as f32 * rng.gen::<f32>()).floor();
 
                 let worker = healthy_workers
                     .get(rand_num as usize)
-                    .unwrap_or(&self.worker_addrs[0])
+                    .or(healthy_workers.first())?
                     .clone();
                 self.current_worker = rand_num as usize;
                 worker
             }
 
             LoadBalancerAlgorithm::LeastConnections => {
-                let current_healthy = self.get_healthy_workers();
+                let current_healthy = self.get_available_workers();
                 let filtered_loads: Vec<(&Arc<SocketAddr>, &usize)> = self
                     .current_worker_loads
                     .iter()
                     .filter(|(addr, _)| current_healthy.contains(addr))
                     .collect();
-                let (addr, _) = filtered_loads.iter().min_by_key(|entry| entry.1).unwrap();
-                let worker = self
-                    .worker_addrs
+                let (addr, _) = filtered_loads.iter().min_by_key(|entry| entry.1)?;
+                self.worker_addrs
                     .iter()
-                    .find(|worker| **worker == **addr)
-                    .unwrap()
-                    .clone();
-                worker
+                    .find(|worker| **worker == **addr)?
+                    .clone()
             }
         };
 
         self.increase_worker_count(&worker);
+        self.breakers.selected(*worker);
         Some(worker)
     }
 
@@ -113,17 +129,49 @@ impl Workers {
             if *current_count > 0 {
                 *current_count -= 1;
             }
+            self.released.notify_one();
             // println!("decrement count: {current_count:?}");
         } else {
             event!(Level::ERROR, "worker map record should not be missing");
         }
     }
 
-    pub fn update_healthy_workers(&mut self, updated_map: HashMap<Arc<SocketAddr>, bool>) {
+    /// Replaces the health map, returning the workers whose health changed
+    pub fn update_healthy_workers(
+        &mut self,
+        updated_map: HashMap<Arc<SocketAddr>, bool>,
+    ) -> Vec<(Arc<SocketAddr>, bool)> {
+        let transitions = updated_map
+            .iter()
+            .filter(|(addr, healthy)| self.workers_health.get(*addr) != Some(*healthy))
+            .map(|(addr, healthy)| (addr.clone(), *healthy))
+            .collect::<Vec<_>>();
         self.workers_health = updated_map;
+        if transitions.iter().any(|(_, healthy)| *healthy) {
+            self.released.notify_waiters();
+        }
+        transitions
+    }
+
+    /// Whether the worker could be picked now, healthy and with a circuit that lets traffic
+    /// through, whatever its load
+    pub fn is_selectable(&self, worker: SocketAddr) -> bool {
+        self.workers_health.get(&worker).copied().unwrap_or(false) && self.breakers.allows(worker)
+    }
+
+    /// Every healthy worker with a closed circuit is at `max_load`, as opposed to there
+    /// being none to pick from at all
+    pub fn at_capacity(&self) -> bool {
+        self.max_load.is_some()
+            && !self.get_selectable_workers().is_empty()
+            && self.get_available_workers().is_empty()
     }
 
     fn optimal_algorithm(&mut self) {
+        if self.pinned_algorithm.is_some() {
+            return;
+        }
+
         let loads = self
             .current_worker_loads
             .clone()
@@ -132,10 +180,11 @@ impl Workers {
 
         let mut values: Vec<&usize> = loads.iter().collect();
 
-        values.sort_by(|a, b| a.cmp(b));
+        values.sort();
 
-        let max = values[values.len() - 1];
-        let min = values[0];
+        let (Some(&&min), Some(&&max)) = (values.first(), values.last()) else {
+            return;
+        };
         let algorithm_candidate: LoadBalancerAlgorithm;
         if max - min > 10 {
             algorithm_candidate = LoadBalancerAlgorithm::LeastConnections;
@@ -163,8 +212,27 @@ impl Workers {
     fn get_healthy_workers(&self) -> Vec<Arc<SocketAddr>> {
         self.workers_health
             .iter()
-            .filter(|(_, health)| **health == true)
+            .filter(|(_, health)| **health)
             .map(|(addr, _)| addr.clone())
             .collect()
     }
+
+    /// Healthy workers whose circuit lets traffic through
+    fn get_selectable_workers(&self) -> Vec<Arc<SocketAddr>> {
+        self.get_healthy_workers()
+            .into_iter()
+            .filter(|addr| self.breakers.allows(**addr))
+            .collect()
+    }
+
+    /// Selectable workers below `max_load`
+    fn get_available_workers(&self) -> Vec<Arc<SocketAddr>> {
+        let Some(max_load) = self.max_load else {
+            return self.get_selectable_workers();
+        };
+        self.get_selectable_workers()
+            .into_iter()
+            .filter(|addr| self.current_worker_loads.get(addr).copied().unwrap_or(0) < max_load)
+            .collect()
+    }
 }
@@ -0,0 +1,378 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use tokio::{sync::mpsc, time::interval};
+use tracing::{event, Level};
+
+use crate::utils::access_log::AccessLogRecord;
+
+use super::postgres_store::PostgresWorkerStore;
+
+const HISTORY_BUFFER: usize = 8192;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct WorkerHealthEvent {
+    pub worker: SocketAddr,
+    pub healthy: bool,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Traffic for one worker aggregated over one minute
+#[derive(Debug, Clone)]
+pub struct WorkerTrafficStats {
+    pub worker: SocketAddr,
+    pub minute: DateTime<Utc>,
+    pub requests: i64,
+    pub errors: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub latency_p50_ms: f64,
+    pub latency_p95_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+#[derive(Debug)]
+enum HistoryEvent {
+    Health(WorkerHealthEvent),
+    Traffic(TrafficEvent),
+}
+
+/// One connection or request, counted into its worker's minute
+#[derive(Debug)]
+struct TrafficEvent {
+    worker: SocketAddr,
+    at: DateTime<Utc>,
+    /// Unset when the worker never answered, so failures do not skew the percentiles
+    latency_ms: Option<f64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    error: bool,
+}
+
+type MinuteBuckets = HashMap<(SocketAddr, DateTime<Utc>), MinuteBucket>;
+
+/// Handle to the history writer. Events are buffered in memory and written to
+/// Postgres in batches every `FLUSH_INTERVAL`, so recording never waits on the database
+#[derive(Debug, Clone)]
+pub struct HistoryWriter {
+    sender: mpsc::Sender<HistoryEvent>,
+}
+
+impl HistoryWriter {
+    pub fn new(store: PostgresWorkerStore) -> Self {
+        let (sender, receiver) = mpsc::channel(HISTORY_BUFFER);
+        tokio::spawn(run_writer(store, receiver));
+
+        Self { sender }
+    }
+
+    pub fn record_health_transition(&self, worker: SocketAddr, healthy: bool) {
+        self.send(HistoryEvent::Health(WorkerHealthEvent {
+            worker,
+            healthy,
+            recorded_at: Utc::now(),
+        }));
+    }
+
+    /// Connections that never reached a worker are not attributed to any worker. Errors are
+    /// counted as [`AccessLogRecord::is_worker_error`] sees them. The latency is the
+    /// worker's, how long it took to send HTTP response headers or to accept a connection,
+    /// never how long the client kept the connection or tunnel open
+    pub fn record_connection(&self, record: &AccessLogRecord) {
+        let Some(worker) = record.worker else {
+            return;
+        };
+
+        self.send(HistoryEvent::Traffic(TrafficEvent {
+            worker,
+            at: record.timestamp,
+            latency_ms: record.response_latency_ms.or(record.connect_latency_ms),
+            bytes_in: record.bytes_in,
+            bytes_out: record.bytes_out,
+            error: record.is_worker_error(),
+        }));
+    }
+
+    fn send(&self, history_event: HistoryEvent) {
+        if let Err(e) = self.sender.try_send(history_event) {
+            event!(Level::WARN, "History event dropped. Error: {e}");
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MinuteBucket {
+    requests: i64,
+    errors: i64,
+    bytes_in: i64,
+    bytes_out: i64,
+    latencies_ms: Vec<f64>,
+}
+
+impl MinuteBucket {
+    fn add(&mut self, traffic: &TrafficEvent) {
+        self.requests += 1;
+        self.errors += traffic.error as i64;
+        self.bytes_in += traffic.bytes_in as i64;
+        self.bytes_out += traffic.bytes_out as i64;
+        if let Some(latency_ms) = traffic.latency_ms {
+            self.latencies_ms.push(latency_ms);
+        }
+    }
+
+    fn into_stats(mut self, worker: SocketAddr, minute: DateTime<Utc>) -> WorkerTrafficStats {
+        self.latencies_ms.sort_by(|a, b| a.total_cmp(b));
+        WorkerTrafficStats {
+            worker,
+            minute,
+            requests: self.requests,
+            errors: self.errors,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            latency_p50_ms: percentile(&self.latencies_ms, 50.0),
+            latency_p95_ms: percentile(&self.latencies_ms, 95.0),
+            latency_p99_ms: percentile(&self.latencies_ms, 99.0),
+        }
+    }
+}
+
+async fn run_writer(store: PostgresWorkerStore, mut receiver: mpsc::Receiver<HistoryEvent>) {
+    let mut health_events: Vec<WorkerHealthEvent> = vec![];
+    let mut buckets = MinuteBuckets::new();
+    let mut flush_interval = interval(FLUSH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            history_event = receiver.recv() => {
+                match history_event {
+                    Some(HistoryEvent::Health(health_event)) => health_events.push(health_event),
+                    Some(HistoryEvent::Traffic(traffic)) => count(&mut buckets, &traffic),
+                    None => {
+                        // every handle is gone, write whatever is left including the current minute
+                        flush(&store, &mut health_events, &mut buckets, None).await;
+                        return;
+                    }
+                }
+            }
+            _ = flush_interval.tick() => {
+                let current_minute = truncate_to_minute(Utc::now());
+                flush(&store, &mut health_events, &mut buckets, Some(current_minute)).await;
+            }
+        }
+    }
+}
+
+/// Writes pending health events and every minute bucket older than `before`.
+/// Failed batches are dropped after logging so a database outage cannot grow memory unbounded
+async fn flush(
+    store: &PostgresWorkerStore,
+    health_events: &mut Vec<WorkerHealthEvent>,
+    buckets: &mut MinuteBuckets,
+    before: Option<DateTime<Utc>>,
+) {
+    if !health_events.is_empty() {
+        if let Err(e) = store.insert_health_events(health_events).await {
+            event!(
+                Level::ERROR,
+                "Failed to persist {} health events. Error: {e}",
+                health_events.len()
+            );
+        }
+        health_events.clear();
+    }
+
+    let stats = take_completed(buckets, before);
+    if stats.is_empty() {
+        return;
+    }
+
+    if let Err(e) = store.upsert_traffic_stats(&stats).await {
+        event!(
+            Level::ERROR,
+            "Failed to persist {} traffic stats. Error: {e}",
+            stats.len()
+        );
+    }
+}
+
+fn count(buckets: &mut MinuteBuckets, traffic: &TrafficEvent) {
+    let minute = truncate_to_minute(traffic.at);
+    buckets
+        .entry((traffic.worker, minute))
+        .or_default()
+        .add(traffic);
+}
+
+/// Removes every minute bucket older than `before`, or all of them, and aggregates each
+fn take_completed(
+    buckets: &mut MinuteBuckets,
+    before: Option<DateTime<Utc>>,
+) -> Vec<WorkerTrafficStats> {
+    let completed: Vec<(SocketAddr, DateTime<Utc>)> = buckets
+        .keys()
+        .filter(|(_, minute)| before.is_none_or(|before| *minute < before))
+        .cloned()
+        .collect();
+
+    completed
+        .into_iter()
+        .filter_map(|key| buckets.remove(&key).map(|bucket| (key, bucket)))
+        .map(|((worker, minute), bucket)| bucket.into_stats(worker, minute))
+        .collect()
+}
+
+fn truncate_to_minute(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(TimeDelta::minutes(1)).unwrap_or(at)
+}
+
+/// Nearest-rank percentile over already sorted values
+fn percentile(sorted: &[f64], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::access_log::TerminationReason;
+
+    const A: &str = "127.0.0.1:9001";
+    const B: &str = "127.0.0.1:9002";
+
+    fn at(time: &str) -> DateTime<Utc> {
+        format!("2025-01-01T{time}Z").parse().unwrap()
+    }
+
+    fn traffic(worker: &str, time: &str, latency_ms: Option<f64>, error: bool) -> TrafficEvent {
+        TrafficEvent {
+            worker: worker.parse().unwrap(),
+            at: at(time),
+            latency_ms,
+            bytes_in: 10,
+            bytes_out: 100,
+            error,
+        }
+    }
+
+    fn aggregate(events: Vec<TrafficEvent>) -> MinuteBuckets {
+        let mut buckets = MinuteBuckets::new();
+        for traffic in &events {
+            count(&mut buckets, traffic);
+        }
+        buckets
+    }
+
+    fn stats_for<'a>(
+        stats: &'a [WorkerTrafficStats],
+        worker: &str,
+        minute: &str,
+    ) -> &'a WorkerTrafficStats {
+        let worker: SocketAddr = worker.parse().unwrap();
+        stats
+            .iter()
+            .find(|stats| stats.worker == worker && stats.minute == at(minute))
+            .unwrap()
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 50.0), 50.0);
+        assert_eq!(percentile(&sorted, 95.0), 95.0);
+        assert_eq!(percentile(&sorted, 99.0), 99.0);
+        assert_eq!(percentile(&sorted, 100.0), 100.0);
+
+        assert_eq!(percentile(&[7.0], 50.0), 7.0);
+        assert_eq!(percentile(&[7.0], 99.0), 7.0);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0], 50.0), 2.0);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0], 0.0), 1.0);
+        assert_eq!(percentile(&[], 99.0), 0.0);
+    }
+
+    #[test]
+    fn aggregates_per_worker_and_minute() {
+        let mut buckets = aggregate(vec![
+            traffic(A, "10:00:05", Some(30.0), false),
+            traffic(A, "10:00:59", Some(10.0), true),
+            traffic(A, "10:00:30", Some(20.0), false),
+            traffic(A, "10:01:00", Some(5.0), false),
+            traffic(B, "10:00:10", Some(40.0), false),
+        ]);
+        let stats = take_completed(&mut buckets, None);
+        assert_eq!(stats.len(), 3);
+        assert!(buckets.is_empty());
+
+        let a = stats_for(&stats, A, "10:00:00");
+        assert_eq!((a.requests, a.errors), (3, 1));
+        assert_eq!((a.bytes_in, a.bytes_out), (30, 300));
+        assert_eq!(a.latency_p50_ms, 20.0);
+        assert_eq!(a.latency_p95_ms, 30.0);
+        assert_eq!(a.latency_p99_ms, 30.0);
+
+        let next = stats_for(&stats, A, "10:01:00");
+        assert_eq!((next.requests, next.latency_p50_ms), (1, 5.0));
+        let b = stats_for(&stats, B, "10:00:00");
+        assert_eq!((b.requests, b.latency_p99_ms), (1, 40.0));
+    }
+
+    #[test]
+    fn requests_without_a_latency_are_counted_but_not_ranked() {
+        let mut buckets = aggregate(vec![
+            traffic(A, "10:00:00", Some(12.0), false),
+            traffic(A, "10:00:01", None, true),
+            traffic(A, "10:00:02", None, true),
+        ]);
+        let stats = take_completed(&mut buckets, None);
+        let a = stats_for(&stats, A, "10:00:00");
+        assert_eq!((a.requests, a.errors), (3, 2));
+        assert_eq!(a.latency_p50_ms, 12.0);
+        assert_eq!(a.latency_p99_ms, 12.0);
+    }
+
+    #[test]
+    fn keeps_the_current_minute_until_it_is_over() {
+        let mut buckets = aggregate(vec![
+            traffic(A, "10:00:30", Some(1.0), false),
+            traffic(A, "10:01:30", Some(1.0), false),
+        ]);
+
+        let stats = take_completed(&mut buckets, Some(at("10:01:00")));
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].minute, at("10:00:00"));
+        assert_eq!(buckets.len(), 1);
+
+        assert!(take_completed(&mut buckets, Some(at("10:01:00"))).is_empty());
+        assert_eq!(take_completed(&mut buckets, Some(at("10:02:00"))).len(), 1);
+    }
+
+    #[test]
+    fn long_connections_report_worker_latency() {
+        let mut record = AccessLogRecord::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            TerminationReason::Completed,
+        );
+        record.worker = Some(A.parse().unwrap());
+        record.connect_latency_ms = Some(2.0);
+        record.duration_ms = 60_000.0;
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        let history = HistoryWriter { sender };
+        history.record_connection(&record);
+        let Some(HistoryEvent::Traffic(traffic)) = receiver.try_recv().ok() else {
+            panic!("no traffic recorded");
+        };
+        assert_eq!(traffic.latency_ms, Some(2.0));
+
+        record.response_latency_ms = Some(35.0);
+        history.record_connection(&record);
+        let Some(HistoryEvent::Traffic(traffic)) = receiver.try_recv().ok() else {
+            panic!("no traffic recorded");
+        };
+        assert_eq!(traffic.latency_ms, Some(35.0));
+    }
+}
@@ -1,5 +1,16 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use sqlx::PgPool;
 
+use crate::{
+    error::{LoadBalancerError, Result},
+    utils::worker_address::{validate_worker_addresses, ValidatedWorkers},
+};
+
+use super::history::{WorkerHealthEvent, WorkerTrafficStats};
+
 #[derive(Debug, Clone)]
 pub struct PostgresWorkerStore {
     pool: PgPool,
@@ -10,15 +21,150 @@ impl PostgresWorkerStore {
         Self { pool }
     }
 
-    pub async fn get_workers(&self) -> Result<Vec<String>, &str> {
-        let workers: Vec<String> = sqlx::query!("Select worker_address from workers")
-            .fetch_all(&self.pool)
-            .await
-            .map_err(|_| "failed to get records")?
-            .iter()
-            .map(|row| row.worker_address.clone())
-            .collect();
+    /// Workers grouped by pool. Rows that are not a routable `ip:port` are returned in
+    /// `rejected` instead of failing the load
+    pub async fn get_workers(&self) -> Result<HashMap<String, ValidatedWorkers>> {
+        let rows = sqlx::query!(
+            "SELECT pool_name, worker_address FROM workers ORDER BY pool_name, worker_address"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("fetching workers"))?;
+
+        let mut raw_pools: HashMap<String, Vec<String>> = HashMap::new();
+        for row in rows {
+            raw_pools
+                .entry(row.pool_name)
+                .or_default()
+                .push(row.worker_address);
+        }
+
+        Ok(raw_pools
+            .into_iter()
+            .map(|(pool, raw_workers)| (pool, validate_worker_addresses(raw_workers)))
+            .collect())
+    }
+
+    /// The networks allowed and denied on a listener, as `(network, deny)` pairs. Rows that
+    /// fail to parse are skipped
+    pub async fn get_listener_acl(&self, listener: SocketAddr) -> Result<Vec<(IpNet, bool)>> {
+        let rows = sqlx::query!(
+            r#"SELECT network::TEXT AS "network!", action FROM listener_acls
+            WHERE listener_address = $1"#,
+            listener.to_string(),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("fetching listener acls"))?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                let network = row.network.parse().ok()?;
+                Some((network, row.action == "deny"))
+            })
+            .collect())
+    }
+
+    /// The share of a listener's traffic stored for its canary pool, if any
+    pub async fn get_traffic_split(&self, listener: SocketAddr) -> Result<Option<f64>> {
+        let row = sqlx::query!(
+            "SELECT percentage FROM traffic_splits WHERE listener_address = $1",
+            listener.to_string(),
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("fetching traffic split"))?;
+
+        Ok(row.map(|row| row.percentage))
+    }
+
+    pub async fn set_traffic_split(&self, listener: SocketAddr, percentage: f64) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO traffic_splits(listener_address, percentage) VALUES ($1, $2)
+            ON CONFLICT (listener_address)
+            DO UPDATE SET percentage = EXCLUDED.percentage, updated_at = NOW()",
+            listener.to_string(),
+            percentage,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("storing traffic split"))?;
+
+        Ok(())
+    }
+
+    pub async fn insert_health_events(
+        &self,
+        events: &[WorkerHealthEvent],
+    ) -> Result<()> {
+        let worker_addresses: Vec<String> =
+            events.iter().map(|e| e.worker.to_string()).collect();
+        let healthy: Vec<bool> = events.iter().map(|e| e.healthy).collect();
+        let recorded_at: Vec<DateTime<Utc>> = events.iter().map(|e| e.recorded_at).collect();
+
+        sqlx::query!(
+            "INSERT INTO worker_health_events(worker_address, healthy, recorded_at)
+            SELECT * FROM UNNEST($1::VARCHAR[], $2::BOOLEAN[], $3::TIMESTAMPTZ[])",
+            &worker_addresses,
+            &healthy,
+            &recorded_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("inserting health events"))?;
+
+        Ok(())
+    }
+
+    /// Stats for a minute that was already written (e.g. after a restart) are merged,
+    /// keeping the worst percentile seen
+    pub async fn upsert_traffic_stats(
+        &self,
+        stats: &[WorkerTrafficStats],
+    ) -> Result<()> {
+        let worker_addresses: Vec<String> = stats.iter().map(|s| s.worker.to_string()).collect();
+        let minutes: Vec<DateTime<Utc>> = stats.iter().map(|s| s.minute).collect();
+        let requests: Vec<i64> = stats.iter().map(|s| s.requests).collect();
+        let errors: Vec<i64> = stats.iter().map(|s| s.errors).collect();
+        let bytes_in: Vec<i64> = stats.iter().map(|s| s.bytes_in).collect();
+        let bytes_out: Vec<i64> = stats.iter().map(|s| s.bytes_out).collect();
+        let p50: Vec<f64> = stats.iter().map(|s| s.latency_p50_ms).collect();
+        let p95: Vec<f64> = stats.iter().map(|s| s.latency_p95_ms).collect();
+        let p99: Vec<f64> = stats.iter().map(|s| s.latency_p99_ms).collect();
+
+        sqlx::query!(
+            "INSERT INTO worker_traffic_stats(
+                worker_address, minute, requests, errors, bytes_in, bytes_out,
+                latency_p50_ms, latency_p95_ms, latency_p99_ms
+            )
+            SELECT * FROM UNNEST(
+                $1::VARCHAR[], $2::TIMESTAMPTZ[], $3::BIGINT[], $4::BIGINT[], $5::BIGINT[],
+                $6::BIGINT[], $7::DOUBLE PRECISION[], $8::DOUBLE PRECISION[],
+                $9::DOUBLE PRECISION[]
+            )
+            ON CONFLICT (worker_address, minute) DO UPDATE SET
+                requests = worker_traffic_stats.requests + EXCLUDED.requests,
+                errors = worker_traffic_stats.errors + EXCLUDED.errors,
+                bytes_in = worker_traffic_stats.bytes_in + EXCLUDED.bytes_in,
+                bytes_out = worker_traffic_stats.bytes_out + EXCLUDED.bytes_out,
+                latency_p50_ms = GREATEST(worker_traffic_stats.latency_p50_ms, EXCLUDED.latency_p50_ms),
+                latency_p95_ms = GREATEST(worker_traffic_stats.latency_p95_ms, EXCLUDED.latency_p95_ms),
+                latency_p99_ms = GREATEST(worker_traffic_stats.latency_p99_ms, EXCLUDED.latency_p99_ms)",
+            &worker_addresses,
+            &minutes,
+            &requests,
+            &errors,
+            &bytes_in,
+            &bytes_out,
+            &p50,
+            &p95,
+            &p99,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(LoadBalancerError::database("upserting traffic stats"))?;
 
-        Ok(workers)
+        Ok(())
     }
 }
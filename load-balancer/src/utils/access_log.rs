@@ -0,0 +1,513 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{event, Level};
+
+use crate::proxy::load_balancer::LoadBalancerAlgorithm;
+
+const ACCESS_LOG_BUFFER: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    /// Both sides closed the connection cleanly
+    Completed,
+    /// The worker's side of the splice or response failed part way through
+    TransferError,
+    /// The client hung up, reset the connection or abandoned a body part way through, which
+    /// says nothing about the worker
+    ClientAborted,
+    /// No worker accepted the outbound connection, including the retry
+    UpstreamConnectFailed,
+    /// Every worker was marked unhealthy when the connection arrived
+    NoHealthyWorkers,
+    /// The client never completed the TLS handshake on a terminating listener
+    TlsHandshakeFailed,
+    /// A passthrough listener timed out or could not read a ClientHello to route on
+    InvalidClientHello,
+    /// The listener expects a PROXY protocol header and the connection did not start with one
+    InvalidProxyHeader,
+    /// The client had used up its rate limit, the connection was closed or the request
+    /// answered with 429
+    RateLimited,
+    /// Every worker, or the balancer as a whole, was at its connection limit and the wait
+    /// queue was full or timed out
+    OverCapacity,
+    /// The pool was at its adaptive concurrency limit, the connection was closed or the
+    /// request answered with 503
+    Shed,
+    /// The listener's ACL does not let the client in, the connection was closed or the
+    /// datagram dropped
+    Denied,
+}
+
+/// How the HTTP cache took part in a request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    /// Answered from the cache without contacting a worker
+    Hit,
+    /// Sent to a worker, the response may have been stored
+    Miss,
+    /// A stale response the worker confirmed with `304 Not Modified`
+    Revalidated,
+}
+
+/// One line of the access log, written as JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogRecord {
+    pub timestamp: DateTime<Utc>,
+    pub listener: SocketAddr,
+    pub client_addr: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Set on HTTP listeners, which log one record per request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Set for gRPC calls that reported a status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grpc_status: Option<u16>,
+    /// Set on requests to HTTP listeners with a cache that the cache could answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheStatus>,
+    pub pool: Option<String>,
+    pub worker: Option<SocketAddr>,
+    pub algorithm: Option<LoadBalancerAlgorithm>,
+    pub connect_latency_ms: Option<f64>,
+    /// Set on HTTP requests the worker answered, from the start of the connect to the
+    /// response headers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_latency_ms: Option<f64>,
+    pub duration_ms: f64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub termination: TerminationReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AccessLogRecord {
+    pub fn new(
+        listener: SocketAddr,
+        client_addr: SocketAddr,
+        termination: TerminationReason,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            listener,
+            client_addr,
+            server_name: None,
+            method: None,
+            host: None,
+            path: None,
+            status: None,
+            grpc_status: None,
+            cache: None,
+            pool: None,
+            worker: None,
+            algorithm: None,
+            connect_latency_ms: None,
+            response_latency_ms: None,
+            duration_ms: 0.0,
+            bytes_in: 0,
+            bytes_out: 0,
+            termination,
+            error: None,
+        }
+    }
+
+    /// Whether the outcome points at the worker: no connection to it could be made, its side
+    /// of a transfer failed, or it answered with an HTTP 5xx or a gRPC status meaning the
+    /// server failed (UNKNOWN, DEADLINE_EXCEEDED, INTERNAL, UNAVAILABLE, DATA_LOSS). Anything
+    /// the client or the balancer's own limits caused is not held against it
+    pub fn is_worker_error(&self) -> bool {
+        match self.termination {
+            TerminationReason::UpstreamConnectFailed | TerminationReason::TransferError => true,
+            TerminationReason::Completed => {
+                self.status.is_some_and(|status| status >= 500)
+                    || self
+                        .grpc_status
+                        .is_some_and(|status| matches!(status, 2 | 4 | 13 | 14 | 15))
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Debug, Clone)]
+pub enum AccessLogTarget {
+    Stdout,
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        max_files: usize,
+    },
+}
+
+/// Handle to the access log writer. Records are queued and written on a blocking
+/// task so the proxy path never waits on disk
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    sender: mpsc::Sender<AccessLogRecord>,
+}
+
+impl AccessLog {
+    pub fn new(target: AccessLogTarget) -> io::Result<Self> {
+        let mut writer = AccessLogWriter::open(target)?;
+        let (sender, mut receiver) = mpsc::channel::<AccessLogRecord>(ACCESS_LOG_BUFFER);
+
+        tokio::task::spawn_blocking(move || {
+            while let Some(record) = receiver.blocking_recv() {
+                if let Err(e) = writer.write(&record) {
+                    event!(Level::ERROR, "Failed to write access log. Error: {e}");
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    /// Drops every record, for tests that need a context but not its log
+    #[cfg(test)]
+    pub fn discard() -> Self {
+        let (sender, mut receiver) = mpsc::channel::<AccessLogRecord>(ACCESS_LOG_BUFFER);
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        Self { sender }
+    }
+
+    pub fn log(&self, record: AccessLogRecord) {
+        if let Err(e) = self.sender.try_send(record) {
+            event!(Level::WARN, "Access log record dropped. Error: {e}");
+        }
+    }
+}
+
+enum AccessLogWriter {
+    Stdout(io::Stdout),
+    File {
+        file: File,
+        path: PathBuf,
+        size: u64,
+        max_bytes: u64,
+        max_files: usize,
+    },
+}
+
+impl AccessLogWriter {
+    fn open(target: AccessLogTarget) -> io::Result<Self> {
+        match target {
+            AccessLogTarget::Stdout => Ok(Self::Stdout(io::stdout())),
+            AccessLogTarget::File {
+                path,
+                max_bytes,
+                max_files,
+            } => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let size = file.metadata()?.len();
+                Ok(Self::File {
+                    file,
+                    path,
+                    size,
+                    max_bytes,
+                    max_files,
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, record: &AccessLogRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        match self {
+            Self::Stdout(stdout) => stdout.lock().write_all(&line),
+            Self::File {
+                file,
+                path,
+                size,
+                max_bytes,
+                max_files,
+            } => {
+                if *size > 0 && *size + line.len() as u64 > *max_bytes {
+                    rotate(path, *max_files)?;
+                    *file = OpenOptions::new().create(true).append(true).open(&*path)?;
+                    *size = 0;
+                }
+                file.write_all(&line)?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Shifts `access.log.1` to `access.log.2` and so on, dropping anything past
+/// `max_files`, then moves the live file to `access.log.1`
+fn rotate(path: &PathBuf, max_files: usize) -> io::Result<()> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+
+    let _ = fs::remove_file(rotated(max_files));
+    for n in (1..max_files).rev() {
+        let from = rotated(n);
+        if from.exists() {
+            fs::rename(from, rotated(n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn record(termination: TerminationReason) -> AccessLogRecord {
+        AccessLogRecord::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            termination,
+        )
+    }
+
+    #[test]
+    fn worker_errors_are_the_workers_alone() {
+        assert!(!record(TerminationReason::Completed).is_worker_error());
+        assert!(record(TerminationReason::UpstreamConnectFailed).is_worker_error());
+        assert!(record(TerminationReason::TransferError).is_worker_error());
+        for termination in [
+            TerminationReason::ClientAborted,
+            TerminationReason::NoHealthyWorkers,
+            TerminationReason::RateLimited,
+            TerminationReason::OverCapacity,
+            TerminationReason::Shed,
+            TerminationReason::Denied,
+        ] {
+            let mut record = record(termination);
+            record.status = Some(502);
+            assert!(!record.is_worker_error(), "{termination:?}");
+        }
+    }
+
+    #[test]
+    fn worker_errors_include_server_statuses() {
+        let mut record = record(TerminationReason::Completed);
+        record.status = Some(404);
+        assert!(!record.is_worker_error());
+        record.status = Some(500);
+        assert!(record.is_worker_error());
+
+        record.status = Some(200);
+        for (grpc_status, server_error) in [(0, false), (5, false), (13, true), (14, true)] {
+            record.grpc_status = Some(grpc_status);
+            assert_eq!(record.is_worker_error(), server_error, "grpc {grpc_status}");
+        }
+    }
+
+    fn file_writer(path: &Path, max_bytes: u64, max_files: usize) -> AccessLogWriter {
+        AccessLogWriter::open(AccessLogTarget::File {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+        })
+        .unwrap()
+    }
+
+    fn lines(path: &Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn line_len(record: &AccessLogRecord) -> u64 {
+        serde_json::to_vec(record).unwrap().len() as u64 + 1
+    }
+
+    #[test]
+    fn serialises_records_as_json() {
+        let mut record = record(TerminationReason::ClientAborted);
+        record.method = Some("GET".into());
+        record.path = Some("/work".into());
+        record.status = Some(200);
+        record.cache = Some(CacheStatus::Revalidated);
+        record.pool = Some("web".into());
+        record.worker = Some("127.0.0.1:9001".parse().unwrap());
+        record.algorithm = Some(LoadBalancerAlgorithm::LeastConnections);
+        record.connect_latency_ms = Some(1.5);
+        record.duration_ms = 12.25;
+        record.bytes_in = 10;
+        record.bytes_out = 20;
+
+        let mut value = serde_json::to_value(&record).unwrap();
+        let timestamp = value.as_object_mut().unwrap().remove("timestamp").unwrap();
+        let timestamp = timestamp.as_str().unwrap();
+        assert!(
+            DateTime::parse_from_rfc3339(timestamp).is_ok(),
+            "{timestamp}"
+        );
+        assert_eq!(
+            value,
+            json!({
+                "listener": "127.0.0.1:8080",
+                "client_addr": "127.0.0.1:50000",
+                "method": "GET",
+                "path": "/work",
+                "status": 200,
+                "cache": "revalidated",
+                "pool": "web",
+                "worker": "127.0.0.1:9001",
+                "algorithm": "least_connections",
+                "connect_latency_ms": 1.5,
+                "duration_ms": 12.25,
+                "bytes_in": 10,
+                "bytes_out": 20,
+                "termination": "client_aborted",
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_the_connection_fields_when_unset() {
+        let value = serde_json::to_value(record(TerminationReason::UpstreamConnectFailed)).unwrap();
+        let object = value.as_object().unwrap();
+        for field in ["pool", "worker", "algorithm", "connect_latency_ms"] {
+            assert_eq!(object[field], Value::Null, "{field}");
+        }
+        for field in [
+            "server_name",
+            "method",
+            "status",
+            "cache",
+            "response_latency_ms",
+            "error",
+        ] {
+            assert!(!object.contains_key(field), "{field}");
+        }
+        assert_eq!(object["termination"], "upstream_connect_failed");
+    }
+
+    #[test]
+    fn rotates_once_the_next_line_would_pass_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = dir.path().join("access.log.1");
+        let record = record(TerminationReason::Completed);
+        let len = line_len(&record);
+
+        // room for two lines, the third starts a new file
+        let mut writer = file_writer(&path, 2 * len + len / 2, 3);
+        writer.write(&record).unwrap();
+        writer.write(&record).unwrap();
+        assert!(!rotated.exists());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * len);
+
+        writer.write(&record).unwrap();
+        assert_eq!(lines(&rotated).len(), 2);
+        assert_eq!(lines(&path).len(), 1);
+        assert_eq!(lines(&path)[0]["termination"], "completed");
+    }
+
+    #[test]
+    fn keeps_at_most_max_files_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = |n: usize| dir.path().join(format!("access.log.{n}"));
+        let mut writer = file_writer(&path, 1, 2);
+
+        for bytes_in in 0..5 {
+            let mut record = record(TerminationReason::Completed);
+            record.bytes_in = bytes_in;
+            writer.write(&record).unwrap();
+        }
+
+        // a line larger than max_bytes still goes into a file of its own
+        assert_eq!(lines(&path)[0]["bytes_in"], 4);
+        assert_eq!(lines(&rotated(1))[0]["bytes_in"], 3);
+        assert_eq!(lines(&rotated(2))[0]["bytes_in"], 2);
+        assert!(!rotated(3).exists());
+    }
+
+    #[test]
+    fn counts_what_the_file_held_before_opening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let record = record(TerminationReason::Completed);
+        fs::write(&path, "x".repeat(line_len(&record) as usize)).unwrap();
+
+        let mut writer = file_writer(&path, line_len(&record) + 1, 1);
+        writer.write(&record).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("access.log.1"))
+                .unwrap()
+                .len(),
+            line_len(&record) as usize
+        );
+        assert_eq!(lines(&path).len(), 1);
+    }
+
+    #[test]
+    fn drops_the_old_file_without_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let mut writer = file_writer(&path, 1, 0);
+
+        let record = record(TerminationReason::Completed);
+        writer.write(&record).unwrap();
+        writer.write(&record).unwrap();
+        assert_eq!(lines(&path).len(), 1);
+        assert!(!dir.path().join("access.log.1").exists());
+    }
+
+    #[tokio::test]
+    async fn writes_logged_records_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::new(AccessLogTarget::File {
+            path: path.clone(),
+            max_bytes: 1024 * 1024,
+            max_files: 1,
+        })
+        .unwrap();
+
+        log.log(record(TerminationReason::Completed));
+        log.log(record(TerminationReason::Denied));
+        drop(log);
+        for _ in 0..50 {
+            if lines(&path).len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let terminations: Vec<_> = lines(&path)
+            .iter()
+            .map(|line| line["termination"].clone())
+            .collect();
+        assert_eq!(terminations, ["completed", "denied"]);
+    }
+}
@@ -0,0 +1,711 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tracing::{event, Level};
+
+use crate::{
+    error::{LoadBalancerError, Result},
+    proxy::load_balancer::LoadBalancerAlgorithm,
+};
+
+/// Pool used by listeners that do not name one, and by workers stored without a pool
+pub const DEFAULT_POOL: &str = "default";
+
+/// Settings read from the TOML file at `CONFIG_PATH`. Every section is optional, a missing
+/// file runs a single listener on `127.0.0.1:3000` in front of the default pool
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    /// Per pool settings keyed by pool name, pools without an entry use the defaults
+    pub pools: HashMap<String, PoolConfig>,
+    /// Serves the balancer's metrics, off unless configured
+    pub admin: Option<AdminConfig>,
+    pub limits: LimitsConfig,
+}
+
+/// Bounds on the balancer as a whole, across every listener and pool
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// TCP connections, HTTP requests and UDP flows proxied at once, unlimited when unset
+    pub max_connections: Option<usize>,
+    /// Where connections wait for one to finish once `max_connections` is reached
+    pub queue: QueueConfig,
+}
+
+/// A bounded wait for capacity. Connections arriving when the queue is full, or still
+/// waiting after the timeout, are turned away
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub size: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            size: 100,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Plain HTTP, bind it to a private address
+    pub address: SocketAddr,
+    /// Bearer token required to change traffic splits. Without one, only clients on a
+    /// loopback address may change them
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listeners: vec![ListenerConfig {
+                address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+                pool: DEFAULT_POOL.to_string(),
+                dual_stack: false,
+                tls: None,
+                passthrough: None,
+                http: None,
+                accept_proxy_protocol: false,
+                udp: None,
+                rate_limit: None,
+                acl: None,
+                split: None,
+            }],
+            pools: HashMap::new(),
+            admin: None,
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    /// Worker pool connections on this listener are balanced over
+    #[serde(default = "default_pool")]
+    pub pool: String,
+    /// For IPv6 addresses, also accept IPv4 clients as mapped addresses.
+    /// Off by default so `0.0.0.0:port` and `[::]:port` can be bound side by side
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// Terminate TLS on this listener and proxy plaintext to the workers
+    #[serde(default)]
+    pub tls: Option<TlsListenerConfig>,
+    /// Route TLS connections by SNI without terminating them. Connections matching no
+    /// route, or sending no SNI, go to `pool`
+    #[serde(default)]
+    pub passthrough: Option<PassthroughConfig>,
+    /// Proxy HTTP/1.1 requests instead of raw connections, routing each request to a pool
+    #[serde(default)]
+    pub http: Option<HttpListenerConfig>,
+    /// Expect a PROXY protocol v1 or v2 header from an upstream balancer on every
+    /// connection, and treat the address it carries as the client
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    /// Balance UDP datagrams instead of TCP connections. Datagrams from one client address
+    /// form a flow that stays on one worker until it goes idle
+    #[serde(default)]
+    pub udp: Option<UdpListenerConfig>,
+    /// Limit how often each client may connect, send a request or, over UDP, a datagram
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Allow or deny clients by address before any worker is picked
+    #[serde(default)]
+    pub acl: Option<AclConfig>,
+    /// Send a share of the connections, or of the HTTP requests no route claims, to a
+    /// canary pool instead of `pool`
+    #[serde(default)]
+    pub split: Option<SplitConfig>,
+}
+
+/// `percentage` is where the split starts. It can be changed on the admin listener, and
+/// with `database` the latest value is stored in the `traffic_splits` table, where it
+/// outlives restarts and is shared by every balancer with the same listener address
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    pub canary: String,
+    /// Share sent to `canary`, from 0 to 100
+    pub percentage: f64,
+    #[serde(default)]
+    pub database: bool,
+    /// How often the stored split is read again, so changes made through another balancer
+    /// reach this one
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+impl SplitConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs.max(1))
+    }
+}
+
+/// CIDR lists checked against the address a client connects from. A client in `deny` is
+/// refused. When `allow` is not empty, only clients in it are let in
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    /// Also apply the rules stored for this listener in the `listener_acls` table
+    pub database: bool,
+    /// How often the lists are read again from the config file and the database
+    pub reload_interval_secs: u64,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            database: false,
+            reload_interval_secs: 30,
+        }
+    }
+}
+
+impl AclConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs.max(1))
+    }
+}
+
+/// A token bucket per client. Each connection, request or datagram takes a token, buckets
+/// hold up to `burst` tokens and refill at `per_second`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub per_second: f64,
+    pub burst: u32,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+/// What identifies a client. Only HTTP listeners can key on more than the address, and a
+/// request without the header falls back to its address
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    ClientIp,
+    /// The client address and the request path, a bucket per client and path
+    Path,
+    /// The value of this request header, such as an API key
+    Header(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpListenerConfig {
+    /// A flow with no datagrams in either direction for this long is forgotten
+    pub flow_idle_timeout_secs: u64,
+}
+
+impl Default for UdpListenerConfig {
+    fn default() -> Self {
+        Self {
+            flow_idle_timeout_secs: 30,
+        }
+    }
+}
+
+impl UdpListenerConfig {
+    pub fn flow_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.flow_idle_timeout_secs.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpListenerConfig {
+    /// Checked in order, the first match picks the pool. Requests matching no route go to
+    /// the listener's `pool`
+    pub routes: Vec<RouteConfig>,
+    /// Add `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `Forwarded`
+    /// to requests sent to workers
+    pub forwarded_headers: bool,
+    /// Clients whose forwarding headers are kept and appended to. Anyone else's are
+    /// replaced, so they cannot spoof the address workers see
+    pub trusted_proxies: Vec<IpNet>,
+    /// Answer repeated GET and HEAD requests from memory, as their responses allow
+    pub cache: Option<HttpCacheConfig>,
+    /// Copy a share of requests to a shadow pool, whose responses are only compared
+    pub mirror: Option<MirrorConfig>,
+}
+
+impl Default for HttpListenerConfig {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            forwarded_headers: true,
+            trusted_proxies: Vec::new(),
+            cache: None,
+            mirror: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpCacheConfig {
+    /// Bodies and headers held at once, the least recently used responses are evicted
+    /// beyond it
+    pub max_bytes: usize,
+    /// Larger responses, and responses without a `Content-Length`, are not stored
+    pub max_object_bytes: usize,
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            max_object_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Mirrored requests are sent once the client's body has been read, and whatever the
+/// shadow pool answers is discarded after its status and latency are compared
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub pool: String,
+    /// Share of requests copied, from 0 to 100
+    pub percentage: f64,
+    /// Requests with larger bodies are not mirrored
+    #[serde(default = "default_mirror_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Mirrored requests in progress at once, beyond it requests are not mirrored
+    #[serde(default = "default_mirror_max_in_flight")]
+    pub max_in_flight: usize,
+    #[serde(default = "default_mirror_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl MirrorConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Every condition that is set must match. `path_prefix` matches whole segments, so `/api`
+/// matches `/api` and `/api/users` but not `/apis`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Host header without the port, `*.example.com` matches a single label
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Header names and the exact value each must have
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub pool: String,
+    /// Removed from the start of the path before forwarding, on whole segments like
+    /// `path_prefix`. `/v1` turns `/v1/work` into `/work`
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    /// Applied to the path after `strip_prefix`
+    #[serde(default)]
+    pub rewrite_path: Option<PathRewriteConfig>,
+    #[serde(default)]
+    pub request_headers: HeaderRewriteConfig,
+    #[serde(default)]
+    pub response_headers: HeaderRewriteConfig,
+}
+
+/// Replaces the first match of `regex` in the path, `replacement` can use `$1` or `$name`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathRewriteConfig {
+    pub regex: String,
+    pub replacement: String,
+}
+
+/// Applied in field order: headers are renamed, removed, set (replacing any existing
+/// values) and then added (keeping existing values)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRewriteConfig {
+    /// Old name to new name
+    pub rename: HashMap<String, String>,
+    pub remove: Vec<String>,
+    pub set: HashMap<String, String>,
+    pub add: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PassthroughConfig {
+    pub routes: Vec<SniRouteConfig>,
+    /// How long to wait for the ClientHello before dropping the connection
+    #[serde(default = "default_client_hello_timeout_ms")]
+    pub client_hello_timeout_ms: u64,
+}
+
+impl PassthroughConfig {
+    pub fn client_hello_timeout(&self) -> Duration {
+        Duration::from_millis(self.client_hello_timeout_ms)
+    }
+}
+
+/// Sends connections whose SNI matches one of `server_names` to `pool`. Names are matched
+/// like certificate names, `*.example.com` matches a single label
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRouteConfig {
+    pub server_names: Vec<String>,
+    pub pool: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListenerConfig {
+    pub certificates: Vec<CertificateConfig>,
+    /// How often certificate and key files are checked for changes
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+impl TlsListenerConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs.max(1))
+    }
+}
+
+/// A PEM certificate chain and its private key. `server_names` are matched against SNI,
+/// `*.example.com` matches a single label. A certificate without names is the default
+/// served when nothing matches, otherwise the first certificate is
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Connect to this pool's workers over TLS, for both proxied traffic and health checks
+    pub tls: Option<UpstreamTlsConfig>,
+    /// Always use this algorithm. When unset the pool switches between algorithms as the
+    /// load across its workers becomes uneven
+    pub algorithm: Option<LoadBalancerAlgorithm>,
+    pub health_check: HealthCheckConfig,
+    /// Send a PROXY protocol header to workers on every connection, so they see the
+    /// client's address instead of the balancer's
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Idle HTTP connections kept open to workers for reuse by HTTP listeners
+    pub keep_alive: KeepAliveConfig,
+    /// Speak HTTP/2 to workers from HTTP listeners, as gRPC services need. Over TLS `h2` is
+    /// negotiated with ALPN, otherwise it is sent with prior knowledge (h2c)
+    pub http2: bool,
+    /// Connections, HTTP requests or UDP flows each worker is given at once, unlimited
+    /// when unset
+    pub max_connections_per_worker: Option<usize>,
+    /// Where connections wait for a worker once every healthy worker is at its limit
+    pub queue: QueueConfig,
+    /// Stop sending traffic to a worker as soon as it fails, without waiting for the next
+    /// health check
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Cap the connections and HTTP requests in progress on the pool at a limit that
+    /// follows the workers' latency, failing the rest straight away
+    pub adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+}
+
+/// The limit starts at `initial_limit` and moves between `min_limit` and `max_limit` as
+/// latency is observed: how long workers take to answer HTTP requests, and to accept TCP
+/// connections. UDP flows are not limited
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConcurrencyConfig {
+    pub algorithm: ConcurrencyAlgorithm,
+    pub initial_limit: u32,
+    pub min_limit: u32,
+    pub max_limit: u32,
+    /// `aimd`: a response slower than this, or a failure, shrinks the limit
+    pub latency_threshold_ms: u64,
+    /// `aimd`: the limit is multiplied by this when it shrinks
+    pub backoff_ratio: f64,
+    /// `gradient`: how many times slower than the long term latency responses may get
+    /// before the limit shrinks
+    pub tolerance: f64,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: ConcurrencyAlgorithm::default(),
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1000,
+            latency_threshold_ms: 1000,
+            backoff_ratio: 0.9,
+            tolerance: 1.5,
+        }
+    }
+}
+
+impl AdaptiveConcurrencyConfig {
+    pub fn latency_threshold(&self) -> Duration {
+        Duration::from_millis(self.latency_threshold_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcurrencyAlgorithm {
+    /// Compares recent latency with the long term average, shrinking the limit in
+    /// proportion as it rises and growing it while latency holds steady
+    #[default]
+    Gradient,
+    /// Grows the limit by one for every fast response and shrinks it by `backoff_ratio`
+    /// for every slow or failed one
+    Aimd,
+}
+
+/// A worker's circuit opens after `consecutive_failures` failures in a row, or once
+/// `error_rate` of at least `min_requests` in the current `window_secs` failed. It stays
+/// open for `open_secs`, then lets `half_open_requests` through: if they all succeed it
+/// closes, any failure opens it again
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub consecutive_failures: u32,
+    pub error_rate: f64,
+    pub min_requests: u32,
+    pub window_secs: u64,
+    pub open_secs: u64,
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window_secs: 10,
+            open_secs: 30,
+            half_open_requests: 1,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs.max(1))
+    }
+
+    pub fn open_for(&self) -> Duration {
+        Duration::from_secs(self.open_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub kind: HealthCheckKind,
+    /// Requested with GET by `http` checks, any status below 400 is healthy
+    pub path: String,
+    /// Service name sent by `grpc` checks, empty asks about the server as a whole
+    pub service: String,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            kind: HealthCheckKind::Http,
+            path: "/health_check".to_string(),
+            service: String::new(),
+            interval_secs: 60,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckKind {
+    Http,
+    /// Healthy when the worker accepts a connection, for services such as DNS that answer
+    /// on TCP as well as UDP
+    Tcp,
+    /// The standard `grpc.health.v1.Health/Check` call, healthy when it answers `SERVING`
+    Grpc,
+    /// Sends an empty datagram and is healthy unless the worker's host reports the port
+    /// unreachable within the timeout, for UDP-only services such as syslog that never answer
+    Udp,
+}
+
+impl HealthCheckConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Limits on idle worker connections. A `max_idle_per_worker` of 0 turns reuse off, and
+/// pools sending PROXY protocol never reuse connections since the header names one client
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAliveConfig {
+    /// Across all of the pool's workers
+    pub max_idle: usize,
+    pub max_idle_per_worker: usize,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 64,
+            max_idle_per_worker: 8,
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of trusted roots, the Mozilla roots are used when unset
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    /// Client certificate chain and key presented to workers for mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// SNI and verification name sent to every worker, defaults to the worker's ip
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Turning this off accepts any worker certificate
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+fn default_reload_interval_secs() -> u64 {
+    30
+}
+
+fn default_client_hello_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_mirror_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_mirror_max_in_flight() -> usize {
+    100
+}
+
+fn default_mirror_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            event!(
+                Level::INFO,
+                "No config at {}, using defaults",
+                path.display()
+            );
+            return Ok(Self::default());
+        }
+
+        let raw = fs::read_to_string(path).map_err(|source| LoadBalancerError::ConfigRead {
+            path: PathBuf::from(path),
+            source,
+        })?;
+
+        toml::from_str(&raw).map_err(|source| LoadBalancerError::ConfigParse {
+            path: PathBuf::from(path),
+            source,
+        })
+    }
+
+    /// Every pool referenced by a listener, its routes, mirror or split, or configured under
+    /// `[pools]`
+    pub fn pool_names(&self) -> Vec<String> {
+        let mut pools: Vec<String> = self
+            .listeners
+            .iter()
+            .flat_map(|l| {
+                let sni_routes = l.passthrough.iter().flat_map(|p| &p.routes);
+                let http_routes = l.http.iter().flat_map(|h| &h.routes);
+                let mirrors = l.http.iter().flat_map(|h| &h.mirror);
+                std::iter::once(&l.pool)
+                    .chain(sni_routes.map(|r| &r.pool))
+                    .chain(http_routes.map(|r| &r.pool))
+                    .chain(mirrors.map(|m| &m.pool))
+                    .chain(l.split.iter().map(|s| &s.canary))
+            })
+            .cloned()
+            .chain(self.pools.keys().cloned())
+            .collect();
+        pools.sort();
+        pools.dedup();
+        pools
+    }
+}
@@ -4,15 +4,74 @@ use dotenvy::dotenv;
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref DATABSE_URL: String = set_database_url();
+    pub static ref DATABSE_URL: Option<String> = set_database_url();
+    pub static ref CONFIG_PATH: String = set_config_path();
+    pub static ref FALLBACK_WORKERS: Vec<String> = set_fallback_workers();
+    pub static ref ACCESS_LOG_PATH: Option<String> = set_access_log_path();
+    pub static ref ACCESS_LOG_MAX_BYTES: u64 = set_access_log_max_bytes();
+    pub static ref ACCESS_LOG_MAX_FILES: usize = set_access_log_max_files();
 }
 
 
-fn set_database_url() -> String {
+fn set_database_url() -> Option<String> {
     dotenv().ok();
-    env::var(env_variables::DATABASE_URL_ENV_VAR).expect("Database connection url must be set")
+    env::var(env_variables::DATABASE_URL_ENV_VAR).ok()
+}
+
+fn set_config_path() -> String {
+    dotenv().ok();
+    env::var(env_variables::CONFIG_PATH_ENV_VAR).unwrap_or(defaults::CONFIG_PATH.to_string())
+}
+
+/// Comma separated worker addresses used when the worker store cannot be reached
+fn set_fallback_workers() -> Vec<String> {
+    dotenv().ok();
+    env::var(env_variables::FALLBACK_WORKERS_ENV_VAR)
+        .map(|workers| {
+            workers
+                .split(',')
+                .map(|worker| worker.trim().to_string())
+                .filter(|worker| !worker.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `None` (unset or `stdout`) writes the access log to stdout
+fn set_access_log_path() -> Option<String> {
+    dotenv().ok();
+    env::var(env_variables::ACCESS_LOG_PATH_ENV_VAR)
+        .ok()
+        .filter(|path| !path.is_empty() && path != "stdout")
+}
+
+fn set_access_log_max_bytes() -> u64 {
+    dotenv().ok();
+    env::var(env_variables::ACCESS_LOG_MAX_BYTES_ENV_VAR)
+        .ok()
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .unwrap_or(defaults::ACCESS_LOG_MAX_BYTES)
+}
+
+fn set_access_log_max_files() -> usize {
+    dotenv().ok();
+    env::var(env_variables::ACCESS_LOG_MAX_FILES_ENV_VAR)
+        .ok()
+        .and_then(|files| files.parse::<usize>().ok())
+        .unwrap_or(defaults::ACCESS_LOG_MAX_FILES)
 }
 
 pub mod env_variables {
     pub const DATABASE_URL_ENV_VAR: &str = "DATABASE_URL";
+    pub const CONFIG_PATH_ENV_VAR: &str = "CONFIG_PATH";
+    pub const FALLBACK_WORKERS_ENV_VAR: &str = "FALLBACK_WORKERS";
+    pub const ACCESS_LOG_PATH_ENV_VAR: &str = "ACCESS_LOG_PATH";
+    pub const ACCESS_LOG_MAX_BYTES_ENV_VAR: &str = "ACCESS_LOG_MAX_BYTES";
+    pub const ACCESS_LOG_MAX_FILES_ENV_VAR: &str = "ACCESS_LOG_MAX_FILES";
+}
+
+pub mod defaults {
+    pub const CONFIG_PATH: &str = "load_balancer.toml";
+    pub const ACCESS_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+    pub const ACCESS_LOG_MAX_FILES: usize = 5;
 }
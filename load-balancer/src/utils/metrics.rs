@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Anything with state worth exporting, asked for its current values on every scrape
+pub trait MetricSource: fmt::Debug + Send + Sync {
+    fn collect(&self, metrics: &mut MetricsWriter);
+}
+
+/// Sources registered by the listeners and pools, rendered in the Prometheus text format
+/// on the admin listener
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    sources: Arc<Mutex<Vec<Arc<dyn MetricSource>>>>,
+}
+
+impl Metrics {
+    pub fn register(&self, source: Arc<dyn MetricSource>) {
+        if let Ok(mut sources) = self.sources.lock() {
+            sources.push(source);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut metrics = MetricsWriter::default();
+        if let Ok(sources) = self.sources.lock() {
+            for source in sources.iter() {
+                source.collect(&mut metrics);
+            }
+        }
+        metrics.render()
+    }
+}
+
+/// Collects samples by metric name, since every source reports into the same families
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+    families: BTreeMap<&'static str, Family>,
+}
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<String>,
+}
+
+impl MetricsWriter {
+    pub fn counter(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: u64,
+    ) {
+        self.sample(name, help, "counter", labels, value as f64);
+    }
+
+    pub fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.sample(name, help, "gauge", labels, value);
+    }
+
+    fn sample(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        kind: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let mut sample = name.to_string();
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ = write!(sample, "{{{}}}", labels.join(","));
+        }
+        let _ = write!(sample, " {value}");
+
+        self.families
+            .entry(name)
+            .or_insert_with(|| Family {
+                help,
+                kind,
+                samples: Vec::new(),
+            })
+            .samples
+            .push(sample);
+    }
+
+    fn render(self) -> String {
+        let mut out = String::new();
+        for (name, family) in self.families {
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} {}", family.kind);
+            for sample in family.samples {
+                let _ = writeln!(out, "{sample}");
+            }
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,4 +1,8 @@
 pub mod tracing;
 pub mod stream_reader;
 pub mod constants;
+pub mod access_log;
+pub mod worker_address;
+pub mod config;
+pub mod metrics;
 
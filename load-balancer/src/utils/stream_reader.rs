@@ -3,9 +3,5 @@ pub fn read_status_code(str_slice: &str) -> u16 {
     if str_parts.len() < 3 {
         return 404_u16
     }
-    let parse_result = str_parts[1].parse::<u16>();
-    match parse_result {
-        Ok(status_code) => status_code,
-        Err(_) => 500u16,
-    }
+    str_parts[1].parse::<u16>().unwrap_or(500u16)
 }
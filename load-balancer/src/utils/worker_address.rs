@@ -0,0 +1,128 @@
+use std::{collections::HashSet, net::SocketAddr, str::FromStr};
+
+use tracing::{event, Level};
+
+use crate::error::{InvalidAddressReason, LoadBalancerError};
+
+/// Worker addresses split into the ones safe to balance over and the ones rejected
+#[derive(Debug, Default)]
+pub struct ValidatedWorkers {
+    pub workers: Vec<SocketAddr>,
+    pub rejected: Vec<LoadBalancerError>,
+}
+
+impl ValidatedWorkers {
+    pub fn log_rejected(&self) {
+        for e in &self.rejected {
+            event!(Level::WARN, "Skipping worker. Error: {e}");
+        }
+    }
+}
+
+pub fn validate_worker_addresses(raw_workers: Vec<String>) -> ValidatedWorkers {
+    let mut validated = ValidatedWorkers::default();
+    let mut seen = HashSet::new();
+
+    for raw_addr in raw_workers {
+        match validate_worker_address(raw_addr.trim()) {
+            Ok(addr) if !seen.insert(addr) => {
+                validated.rejected.push(LoadBalancerError::InvalidWorkerAddress {
+                    address: raw_addr,
+                    reason: InvalidAddressReason::Duplicate,
+                })
+            }
+            Ok(addr) => validated.workers.push(addr),
+            Err(reason) => validated
+                .rejected
+                .push(LoadBalancerError::InvalidWorkerAddress {
+                    address: raw_addr,
+                    reason,
+                }),
+        }
+    }
+
+    validated
+}
+
+fn validate_worker_address(raw_addr: &str) -> Result<SocketAddr, InvalidAddressReason> {
+    let addr = SocketAddr::from_str(raw_addr)?;
+
+    if addr.port() == 0 {
+        return Err(InvalidAddressReason::ZeroPort);
+    }
+    if addr.ip().is_unspecified() {
+        return Err(InvalidAddressReason::UnspecifiedIp(addr.ip()));
+    }
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(raw: &[&str]) -> ValidatedWorkers {
+        validate_worker_addresses(raw.iter().map(|raw| raw.to_string()).collect())
+    }
+
+    fn reasons(validated: &ValidatedWorkers) -> Vec<&InvalidAddressReason> {
+        validated
+            .rejected
+            .iter()
+            .map(|e| match e {
+                LoadBalancerError::InvalidWorkerAddress { reason, .. } => reason,
+                e => panic!("unexpected error {e}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accepts_ipv4_and_ipv6_workers() {
+        let validated = validate(&["127.0.0.1:8000", " 10.0.0.2:9000 ", "[::1]:8080"]);
+        let expected: Vec<SocketAddr> = ["127.0.0.1:8000", "10.0.0.2:9000", "[::1]:8080"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(validated.workers, expected);
+        assert!(validated.rejected.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_syntax() {
+        let validated = validate(&["localhost:8000", "127.0.0.1", "999.0.0.1:80", "::1:80"]);
+        assert!(validated.workers.is_empty());
+        let reasons = reasons(&validated);
+        assert_eq!(reasons.len(), 4);
+        assert!(reasons
+            .iter()
+            .all(|reason| matches!(reason, InvalidAddressReason::Syntax(_))));
+    }
+
+    #[test]
+    fn rejects_port_zero_and_unspecified_ips() {
+        let validated = validate(&["127.0.0.1:0", "0.0.0.0:8000", "[::]:8000"]);
+        assert!(validated.workers.is_empty());
+        assert!(matches!(
+            reasons(&validated)[..],
+            [
+                InvalidAddressReason::ZeroPort,
+                InvalidAddressReason::UnspecifiedIp(_),
+                InvalidAddressReason::UnspecifiedIp(_),
+            ]
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicates_after_the_first() {
+        let validated = validate(&["127.0.0.1:8000", "127.0.0.1:8001", " 127.0.0.1:8000"]);
+        assert_eq!(validated.workers.len(), 2);
+        assert!(matches!(
+            reasons(&validated)[..],
+            [InvalidAddressReason::Duplicate]
+        ));
+        // the rejected entry is reported as it was written
+        assert!(validated.rejected[0]
+            .to_string()
+            .contains(" 127.0.0.1:8000"));
+    }
+}
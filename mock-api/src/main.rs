@@ -1,11 +1,14 @@
-use std::{thread::sleep, time::Duration};
+use std::{net::SocketAddr, thread::sleep, time::Duration};
 
-use axum::{http::StatusCode, response::IntoResponse, routing::{get, post}, Json, Router};
+use axum::{http::StatusCode, response::IntoResponse, routing::{get, post}, Extension, Json, Router};
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
 
 use rand::Rng;
 use serde::Serialize;
 
 mod constants;
+mod proxy_protocol;
 
 use constants::PORT;
 
@@ -39,8 +42,12 @@ impl IntoResponse for ApiError {
     }
 }
 
-async fn work() -> String {
-    println!("Incoming request");
+/// The client as seen by the balancer when it sends PROXY protocol, otherwise the peer
+#[derive(Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
+async fn work(Extension(ClientAddr(client)): Extension<ClientAddr>) -> String {
+    println!("Incoming request from {client}");
     let mut rng = rand::thread_rng();
     let random_number = rng.gen_range(10..=50);
     sleep(Duration::from_millis(random_number * 100));
@@ -67,5 +74,23 @@ async fn main() {
         .route("/health_check", get(health_check));
 
     let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", PORT.to_string())).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    loop {
+        let Ok((mut stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let client = proxy_protocol::read_client_addr(&mut stream).await.unwrap_or(peer);
+            let service = TowerToHyperService::new(app.layer(Extension(ClientAddr(client))));
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                println!("Connection from {client} failed: {e}");
+            }
+        });
+    }
 }
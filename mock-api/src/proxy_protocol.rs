@@ -0,0 +1,61 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a PROXY protocol v1 or v2 header if the connection starts with one, returning
+/// the client address it carries. Connections without a header are left untouched
+pub async fn read_client_addr(stream: &mut TcpStream) -> Option<SocketAddr> {
+    let mut buf = [0u8; 536];
+    let n = stream.peek(&mut buf).await.ok()?;
+    let buf = &buf[..n];
+
+    let (len, client) = if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)?
+    } else if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)?
+    } else {
+        return None;
+    };
+
+    let mut header = vec![0u8; len];
+    stream.read_exact(&mut header).await.ok()?;
+    client
+}
+
+fn parse_v1(buf: &[u8]) -> Option<(usize, Option<SocketAddr>)> {
+    let end = buf.windows(2).position(|w| w == b"\r\n")?;
+    let line = std::str::from_utf8(&buf[..end]).ok()?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let client = match fields.as_slice() {
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            Some(SocketAddr::new(source.parse().ok()?, source_port.parse().ok()?))
+        }
+        _ => None,
+    };
+    Some((end + 2, client))
+}
+
+fn parse_v2(buf: &[u8]) -> Option<(usize, Option<SocketAddr>)> {
+    let header = buf.get(..16)?;
+    let len = 16 + u16::from_be_bytes([header[14], header[15]]) as usize;
+    let addresses = buf.get(16..len)?;
+
+    let client = match (header[12] & 0x0F, header[13]) {
+        (0x01, 0x11) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        (0x01, 0x21) if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    };
+    Some((len, client))
+}